@@ -0,0 +1,127 @@
+use std::io::{Error, ErrorKind, Result};
+
+fn malformed(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("malformed bencode: {}", reason),
+    )
+}
+
+/* Parse the length prefix of a byte string at start, returning the span of its contents */
+fn string_span(buf: &[u8], start: usize) -> Result<(usize, usize)> {
+    let colon = match buf[start..].iter().position(|&b| b == b':') {
+        Some(offset) => start + offset,
+        None => return Err(malformed("missing string separator")),
+    };
+    let digits = &buf[start..colon];
+    if digits.is_empty() || !digits.iter().all(|b| b.is_ascii_digit()) {
+        return Err(malformed("invalid string length"));
+    }
+    let length: usize = match std::str::from_utf8(digits).unwrap().parse() {
+        Ok(length) => length,
+        Err(_) => return Err(malformed("string length too large")),
+    };
+    match (colon + 1).checked_add(length) {
+        Some(end) if end <= buf.len() => Ok((colon + 1, end)),
+        _ => Err(malformed("string runs past end of input")),
+    }
+}
+
+/* Return the index just past the bencoded value that starts at start */
+pub(crate) fn value_end(buf: &[u8], start: usize) -> Result<usize> {
+    let mut pos = start;
+    let mut depth = 0;
+    loop {
+        match buf.get(pos) {
+            Some(b'i') => match buf[pos..].iter().position(|&b| b == b'e') {
+                Some(offset) => pos += offset + 1,
+                None => return Err(malformed("unterminated integer")),
+            },
+            Some(b'l') | Some(b'd') => {
+                depth += 1;
+                pos += 1;
+                continue;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            Some(b'0'..=b'9') => pos = string_span(buf, pos)?.1,
+            Some(_) => return Err(malformed("unexpected byte")),
+            None => return Err(malformed("unexpected end of input")),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/* Find the exact byte span of the value stored under key in the dictionary at the start of buf */
+pub(crate) fn dict_value_span(buf: &[u8], key: &[u8]) -> Result<(usize, usize)> {
+    if buf.first() != Some(&b'd') {
+        return Err(malformed("expected a dictionary"));
+    }
+    let mut pos = 1;
+    loop {
+        match buf.get(pos) {
+            Some(b'0'..=b'9') => {}
+            Some(b'e') => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("missing key {:?}", String::from_utf8_lossy(key)),
+                ))
+            }
+            _ => return Err(malformed("expected a string key")),
+        }
+        let (key_begin, key_end) = string_span(buf, pos)?;
+        let end = value_end(buf, key_end)?;
+        if &buf[key_begin..key_end] == key {
+            return Ok((key_end, end));
+        }
+        pos = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_end_scalars() {
+        assert_eq!(value_end(b"i42e", 0).unwrap(), 4);
+        assert_eq!(value_end(b"4:spamxyz", 0).unwrap(), 6);
+        assert_eq!(value_end(b"0:", 0).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_value_end_nested() {
+        let input = b"d4:listli1ei2ee3:subd1:a1:bee4:rest";
+        assert_eq!(value_end(input, 0).unwrap(), input.len() - 6);
+        assert_eq!(value_end(b"xli1ee", 1).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_value_end_malformed() {
+        assert!(value_end(b"i42", 0).is_err());
+        assert!(value_end(b"10:short", 0).is_err());
+        assert!(value_end(b"18446744073709551615:x", 0).is_err());
+        assert!(value_end(b"li1e", 0).is_err());
+        assert!(value_end(b"x", 0).is_err());
+    }
+
+    #[test]
+    fn test_dict_value_span() {
+        let input = b"d8:announce3:url4:infod6:lengthi5ee7:privatei1ee";
+        let (begin, end) = dict_value_span(input, b"info").unwrap();
+        assert_eq!(&input[begin..end], b"d6:lengthi5ee");
+        let (begin, end) = dict_value_span(input, b"private").unwrap();
+        assert_eq!(&input[begin..end], b"i1e");
+    }
+
+    #[test]
+    fn test_dict_value_span_missing_key() {
+        let err = dict_value_span(b"d8:announce3:urle", b"info").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(dict_value_span(b"l4:infoe", b"info").is_err());
+    }
+}
@@ -1,9 +1,9 @@
 pub type Bitfield = Vec<u8>;
 
-pub(crate) fn has_piece(bf: &mut Bitfield, index: usize) -> bool {
+pub(crate) fn has_piece(bf: &[u8], index: usize) -> bool {
     let byte_index = index / 8;
     let offset = index % 8;
-    let bflength = bf.len() as usize;
+    let bflength = bf.len();
     if byte_index >= bflength {
         false
     } else {
@@ -14,7 +14,7 @@ pub(crate) fn has_piece(bf: &mut Bitfield, index: usize) -> bool {
 pub fn set_piece(bf: &Bitfield, index: usize) -> Bitfield {
     let byte_index = index / 8;
     let offset = index % 8;
-    let bflength = bf.len() as usize;
+    let bflength = bf.len();
     let mut newbf = bf.to_vec();
     if byte_index >= bflength {
         newbf
@@ -24,19 +24,26 @@ pub fn set_piece(bf: &Bitfield, index: usize) -> Bitfield {
     }
 }
 
+/* Same as set_piece without copying the bitfield */
+pub(crate) fn mark_piece(bf: &mut Bitfield, index: usize) {
+    if let Some(byte) = bf.get_mut(index / 8) {
+        *byte |= (1 << (7 - index % 8)) as u8;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_has_piece() {
-        let mut bf: Bitfield = vec![0b01010100, 0b01010100];
+        let bf: Bitfield = vec![0b01010100, 0b01010100];
         let outputs: [bool; 20] = [
             false, true, false, true, false, true, false, false, false, true, false, true, false,
             true, false, false, false, false, false, false,
         ];
-        for i in 0..outputs.len() {
-            assert_eq!(outputs[i], has_piece(&mut bf, i))
+        for (i, output) in outputs.iter().enumerate() {
+            assert_eq!(*output, has_piece(&bf, i))
         }
     }
 
@@ -75,4 +82,14 @@ mod tests {
         assert_eq!(tests[2].output, set_piece(&tests[2].input, tests[2].index));
         assert_eq!(tests[3].output, set_piece(&tests[3].input, tests[3].index));
     }
+
+    #[test]
+    fn test_mark_piece() {
+        let mut bf: Bitfield = vec![0b01010100, 0b01010100];
+        for index in [4, 9, 15, 19] {
+            let expected = set_piece(&bf, index);
+            mark_piece(&mut bf, index);
+            assert_eq!(bf, expected);
+        }
+    }
 }
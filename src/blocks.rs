@@ -0,0 +1,359 @@
+use crate::client::PeerWriter;
+use crate::message::format_cancel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* Requests are for blocks of this size, except for the last block of a piece */
+pub(crate) static BLOCK_SIZE: u32 = 16384;
+/* Requests kept outstanding at a peer before its rate is known, and never fewer unless its reqq
+says so */
+pub(crate) static MIN_QUEUE_DEPTH: u32 = 5;
+/* Assumed reqq of peers that don't send one, the value we advertise ourselves */
+static DEFAULT_PEER_REQQ: u32 = 250;
+/* Enough requests are queued to keep a peer busy for this long at its measured rate */
+static QUEUE_TIME: Duration = Duration::from_secs(3);
+/* How often the rate of a peer is measured */
+static RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct PartialPiece {
+    buf: Vec<u8>,
+    received: Vec<bool>,
+    /* Workers with an outstanding request for each block */
+    requested: Vec<Vec<usize>>,
+}
+
+impl PartialPiece {
+    fn block(&self, begin: u32) -> Option<usize> {
+        let block = (begin / BLOCK_SIZE) as usize;
+        if begin % BLOCK_SIZE == 0 && block < self.received.len() {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    fn block_length(&self, block: usize) -> u32 {
+        let begin = block as u32 * BLOCK_SIZE;
+        BLOCK_SIZE.min(self.buf.len() as u32 - begin)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|&received| received)
+    }
+}
+
+#[derive(Default)]
+struct BlocksState {
+    pieces: HashMap<u32, PartialPiece>,
+    /* Used to cancel a worker's requests when a block arrives from somebody else */
+    writers: HashMap<usize, Arc<PeerWriter>>,
+    next_id: usize,
+}
+
+/* The blocks of the pieces being downloaded, shared between the download workers. A piece whose
+worker went away keeps the blocks it got, and the next worker to pick it only requests the rest.
+In end-game several workers download the same piece into one buffer, requesting whatever blocks
+haven't arrived yet, and the first copy of a block to arrive cancels the requests for it at the
+other peers. */
+#[derive(Clone, Default)]
+pub struct Blocks {
+    state: Arc<Mutex<BlocksState>>,
+}
+
+/* A worker's place in Blocks, its requests are forgotten when it goes away */
+pub struct BlockWorker {
+    blocks: Blocks,
+    pub(crate) id: usize,
+}
+
+impl Drop for BlockWorker {
+    fn drop(&mut self) {
+        let mut state = self.blocks.state.lock().unwrap();
+        state.writers.remove(&self.id);
+        for piece in state.pieces.values_mut() {
+            for requested in piece.requested.iter_mut() {
+                requested.retain(|&worker| worker != self.id);
+            }
+        }
+    }
+}
+
+impl Blocks {
+    pub fn register(&self, writer: PeerWriter) -> BlockWorker {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.writers.insert(id, Arc::new(writer));
+        BlockWorker {
+            blocks: self.clone(),
+            id,
+        }
+    }
+
+    /* Start downloading a piece, or continue it if it was started before or another worker is on it */
+    pub fn join(&self, index: u32, length: u32) {
+        let mut state = self.state.lock().unwrap();
+        let num_blocks = length.div_ceil(BLOCK_SIZE) as usize;
+        state.pieces.entry(index).or_insert_with(|| PartialPiece {
+            buf: vec![0; length as usize],
+            received: vec![false; num_blocks],
+            requested: vec![vec![]; num_blocks],
+        });
+    }
+
+    /* The next block the worker should request, as begin and length. It is recorded as requested
+    by the worker. */
+    pub fn next_request(&self, id: usize, index: u32) -> Option<(u32, u32)> {
+        let mut state = self.state.lock().unwrap();
+        let piece = state.pieces.get_mut(&index)?;
+        let block = (0..piece.received.len())
+            .find(|&block| !piece.received[block] && !piece.requested[block].contains(&id))?;
+        piece.requested[block].push(id);
+        Some((block as u32 * BLOCK_SIZE, piece.block_length(block)))
+    }
+
+    /* Requests of the worker that haven't been answered or cancelled, over all its pieces */
+    pub fn outstanding(&self, id: usize) -> u32 {
+        let state = self.state.lock().unwrap();
+        state
+            .pieces
+            .values()
+            .flat_map(|piece| piece.requested.iter())
+            .filter(|requested| requested.contains(&id))
+            .count() as u32
+    }
+
+    /* A choking peer drops our requests, so they are no longer outstanding */
+    pub fn forget(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        for piece in state.pieces.values_mut() {
+            for requested in piece.requested.iter_mut() {
+                requested.retain(|&worker| worker != id);
+            }
+        }
+    }
+
+    /* Store a block a worker received and cancel it at the other peers that were asked for it.
+    Returns false for blocks nobody needs any more, such as late copies of cancelled requests. */
+    pub fn received(&self, id: usize, index: u32, begin: u32, data: &[u8]) -> bool {
+        let cancel = {
+            let mut state = self.state.lock().unwrap();
+            let piece = match state.pieces.get_mut(&index) {
+                Some(piece) => piece,
+                None => return false,
+            };
+            let block = match piece.block(begin) {
+                Some(block) if data.len() as u32 == piece.block_length(block) => block,
+                _ => return false,
+            };
+            let requested = std::mem::take(&mut piece.requested[block]);
+            if piece.received[block] {
+                return false;
+            }
+            piece.received[block] = true;
+            piece.buf[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+            let others: Vec<Arc<PeerWriter>> = requested
+                .iter()
+                .filter(|&&worker| worker != id)
+                .filter_map(|worker| state.writers.get(worker).cloned())
+                .collect();
+            others
+        };
+        /* A peer that can't take the cancel will be dropped by its own worker soon enough */
+        for writer in cancel {
+            let _ = writer.send(format_cancel(index, begin, data.len() as u32));
+        }
+        true
+    }
+
+    /* Whether every block has arrived, or the piece was finished and handed out already */
+    pub fn is_done(&self, index: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .pieces
+            .get(&index)
+            .map_or(true, PartialPiece::is_complete)
+    }
+
+    /* Stop working on a piece. The first worker to leave a complete piece gets its data, an
+    incomplete one is kept for whoever picks it next. */
+    pub fn leave(&self, id: usize, index: u32) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let piece = state.pieces.get_mut(&index)?;
+        for requested in piece.requested.iter_mut() {
+            requested.retain(|&worker| worker != id);
+        }
+        if piece.is_complete() {
+            state.pieces.remove(&index).map(|piece| piece.buf)
+        } else {
+            None
+        }
+    }
+}
+
+/* How many requests to keep outstanding at one peer. Fast peers get deep queues so the link stays
+busy while requests are in flight, up to the reqq the peer advertised. */
+pub struct QueueDepth {
+    /* Bytes per second, None until the first measurement */
+    rate: Option<f64>,
+    since: Instant,
+    received: u64,
+}
+
+impl QueueDepth {
+    pub fn new() -> QueueDepth {
+        QueueDepth {
+            rate: None,
+            since: Instant::now(),
+            received: 0,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        self.received += bytes;
+    }
+
+    pub fn depth(&mut self, reqq: Option<i64>) -> u32 {
+        let elapsed = self.since.elapsed();
+        if elapsed >= RATE_INTERVAL {
+            let sample = self.received as f64 / elapsed.as_secs_f64();
+            /* Averaged with the previous rate so a single slow second doesn't empty the queue */
+            self.rate = Some(self.rate.map_or(sample, |rate| (rate + sample) / 2.0));
+            self.since = Instant::now();
+            self.received = 0;
+        }
+        let limit = match reqq {
+            Some(reqq) => reqq.clamp(1, u32::MAX as i64) as u32,
+            None => DEFAULT_PEER_REQQ,
+        };
+        let wanted = self.rate.map_or(0.0, |rate| {
+            rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64
+        });
+        (wanted.ceil() as u32).max(MIN_QUEUE_DEPTH).min(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{read_message, MESSAGE_CANCEL};
+    use std::net::{TcpListener, TcpStream};
+
+    /* A PeerWriter whose messages can be read from the returned stream */
+    fn writer_pair() -> (PeerWriter, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (other, _) = listener.accept().unwrap();
+        (PeerWriter::new(conn), other)
+    }
+
+    #[test]
+    fn test_blocks_of_one_worker() {
+        let blocks = Blocks::default();
+        let (writer, _) = writer_pair();
+        let worker = blocks.register(writer);
+        blocks.join(3, 40000);
+        assert_eq!(blocks.next_request(worker.id, 3), Some((0, 16384)));
+        assert_eq!(blocks.next_request(worker.id, 3), Some((16384, 16384)));
+        assert_eq!(blocks.next_request(worker.id, 3), Some((32768, 7232)));
+        assert_eq!(blocks.next_request(worker.id, 3), None);
+        assert_eq!(blocks.outstanding(worker.id), 3);
+
+        /* Blocks that weren't asked for in this form are ignored */
+        assert!(!blocks.received(worker.id, 3, 100, &[0; 16384]));
+        assert!(!blocks.received(worker.id, 3, 32768, &[0; 16384]));
+        assert!(!blocks.received(worker.id, 4, 0, &[0; 16384]));
+
+        assert!(blocks.received(worker.id, 3, 0, &[1; 16384]));
+        assert!(blocks.received(worker.id, 3, 32768, &[3; 7232]));
+        assert!(!blocks.is_done(3));
+        blocks.forget(worker.id);
+        assert_eq!(blocks.outstanding(worker.id), 0);
+        assert_eq!(blocks.next_request(worker.id, 3), Some((16384, 16384)));
+        assert!(blocks.received(worker.id, 3, 16384, &[2; 16384]));
+        assert!(blocks.is_done(3));
+
+        let buf = blocks.leave(worker.id, 3).unwrap();
+        assert_eq!(buf.len(), 40000);
+        assert_eq!((buf[0], buf[16384], buf[39999]), (1, 2, 3));
+        assert!(blocks.is_done(3));
+    }
+
+    #[test]
+    fn test_endgame_cancels_other_requests() {
+        let blocks = Blocks::default();
+        let (slow_writer, mut slow_peer) = writer_pair();
+        let (fast_writer, _fast_peer) = writer_pair();
+        let slow = blocks.register(slow_writer);
+        let fast = blocks.register(fast_writer);
+        blocks.join(0, 20000);
+        blocks.join(0, 20000);
+        assert_eq!(blocks.next_request(slow.id, 0), Some((0, 16384)));
+        assert_eq!(blocks.next_request(slow.id, 0), Some((16384, 3616)));
+        /* The second worker asks for the same blocks */
+        assert_eq!(blocks.next_request(fast.id, 0), Some((0, 16384)));
+        assert!(blocks.received(fast.id, 0, 0, &[7; 16384]));
+
+        let cancel = read_message(&mut slow_peer).unwrap();
+        assert_eq!(cancel.id, MESSAGE_CANCEL);
+        assert_eq!(cancel.payload, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0]);
+        assert_eq!(blocks.outstanding(slow.id), 1);
+
+        /* The slow peer's copy arrives too late */
+        assert!(!blocks.received(slow.id, 0, 0, &[7; 16384]));
+        assert!(blocks.received(slow.id, 0, 16384, &[8; 3616]));
+        assert!(blocks.is_done(0));
+        assert!(blocks.leave(fast.id, 0).is_some());
+        assert!(blocks.leave(slow.id, 0).is_none());
+    }
+
+    #[test]
+    fn test_partial_piece_survives_its_worker() {
+        let blocks = Blocks::default();
+        let (first_writer, _) = writer_pair();
+        let first = blocks.register(first_writer);
+        blocks.join(2, 40000);
+        blocks.join(5, 100);
+        for _ in 0..3 {
+            blocks.next_request(first.id, 2).unwrap();
+        }
+        assert_eq!(blocks.next_request(first.id, 5), Some((0, 100)));
+        assert_eq!(blocks.outstanding(first.id), 4);
+        assert!(blocks.received(first.id, 2, 16384, &[4; 16384]));
+        assert!(blocks.leave(first.id, 2).is_none());
+        drop(first);
+        assert!(blocks.state.lock().unwrap().writers.is_empty());
+
+        /* The next worker only asks for what is missing */
+        let (second_writer, _) = writer_pair();
+        let second = blocks.register(second_writer);
+        blocks.join(2, 40000);
+        assert_eq!(blocks.next_request(second.id, 2), Some((0, 16384)));
+        assert_eq!(blocks.next_request(second.id, 2), Some((32768, 7232)));
+        assert_eq!(blocks.next_request(second.id, 2), None);
+        assert!(blocks.received(second.id, 2, 0, &[3; 16384]));
+        assert!(blocks.received(second.id, 2, 32768, &[5; 7232]));
+        let buf = blocks.leave(second.id, 2).unwrap();
+        assert_eq!((buf[0], buf[16384], buf[32768]), (3, 4, 5));
+    }
+
+    #[test]
+    fn test_queue_depth_follows_rate() {
+        let mut queue = QueueDepth::new();
+        assert_eq!(queue.depth(None), MIN_QUEUE_DEPTH);
+        /* 1 MiB/s keeps 3 MiB, 192 blocks, in flight */
+        queue.rate = Some(1048576.0);
+        assert_eq!(queue.depth(None), 192);
+        assert_eq!(queue.depth(Some(100)), 100);
+        assert_eq!(queue.depth(Some(2)), 2);
+        queue.rate = Some(100000000.0);
+        assert_eq!(queue.depth(None), DEFAULT_PEER_REQQ);
+
+        queue.rate = None;
+        queue.since = Instant::now() - RATE_INTERVAL * 2;
+        queue.record(BLOCK_SIZE as u64 * 20);
+        assert_eq!(queue.depth(None), 30);
+        assert_eq!(queue.received, 0);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::client::PeerWriter;
+use crate::message::*;
+use crate::p2p::TransferStats;
+use crate::runtime;
+use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/* Number of peers we upload to at once, one of which is the optimistic unchoke */
+pub(crate) static DEFAULT_UNCHOKE_SLOTS: usize = 4;
+static CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/* The optimistic unchoke moves to another peer every third round, i.e. every 30 seconds */
+static OPTIMISTIC_ROUNDS: u32 = 3;
+
+struct ChokerPeer {
+    writer: PeerWriter,
+    interested: bool,
+    choked: bool,
+    /* Bytes exchanged with the peer since the last round */
+    downloaded: u64,
+    uploaded: u64,
+}
+
+#[derive(Default)]
+struct ChokerState {
+    peers: BTreeMap<usize, ChokerPeer>,
+    next_id: usize,
+    optimistic: Option<usize>,
+    rounds: u32,
+}
+
+/* Decides which connected peers we upload to. Every round the fastest interested peers get
+the regular slots, rated by what they sent us or by what we sent them once we are seeding. */
+#[derive(Clone)]
+pub struct Choker {
+    state: Arc<Mutex<ChokerState>>,
+    slots: usize,
+    stats: Arc<TransferStats>,
+}
+
+impl Choker {
+    pub fn new(slots: usize, stats: Arc<TransferStats>) -> Choker {
+        Choker {
+            state: Arc::new(Mutex::new(ChokerState::default())),
+            slots,
+            stats,
+        }
+    }
+
+    /* Run a round every CHOKE_INTERVAL until every clone of the choker has been dropped */
+    pub fn start(&self) {
+        let state = Arc::downgrade(&self.state);
+        let slots = self.slots;
+        let stats = self.stats.clone();
+        runtime::spawn(async move {
+            loop {
+                delay_for(CHOKE_INTERVAL).await;
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    None => return,
+                };
+                Choker {
+                    state,
+                    slots,
+                    stats: stats.clone(),
+                }
+                .rechoke();
+            }
+        });
+    }
+
+    /* Track a new connection, which starts out choked. Returns the id used for it from now on. */
+    pub fn register(&self, writer: PeerWriter) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.insert(
+            id,
+            ChokerPeer {
+                writer,
+                interested: false,
+                choked: true,
+                downloaded: 0,
+                uploaded: 0,
+            },
+        );
+        id
+    }
+
+    pub fn unregister(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.remove(&id);
+        if state.optimistic == Some(id) {
+            state.optimistic = None;
+        }
+    }
+
+    /* Record the peer's interest. While slots are free interested peers are unchoked right away
+    instead of waiting for the next round. */
+    pub fn set_interested(&self, id: usize, interested: bool) {
+        let mut state = self.state.lock().unwrap();
+        let unchoked = state.peers.values().filter(|p| !p.choked).count();
+        if let Some(peer) = state.peers.get_mut(&id) {
+            peer.interested = interested;
+            if interested && peer.choked && unchoked < self.slots {
+                set_choked(peer, false);
+            }
+        }
+    }
+
+    pub fn is_choked(&self, id: usize) -> bool {
+        match self.state.lock().unwrap().peers.get(&id) {
+            Some(peer) => peer.choked,
+            None => true,
+        }
+    }
+
+    pub fn record_download(&self, id: usize, bytes: u64) {
+        if let Some(peer) = self.state.lock().unwrap().peers.get_mut(&id) {
+            peer.downloaded += bytes;
+        }
+    }
+
+    pub fn record_upload(&self, id: usize, bytes: u64) {
+        if let Some(peer) = self.state.lock().unwrap().peers.get_mut(&id) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    fn rechoke(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.rounds += 1;
+        let seeding = self.stats.left() == 0;
+        let rates: Vec<(usize, u64)> = state
+            .peers
+            .iter()
+            .filter(|(_, p)| p.interested)
+            .map(|(&id, p)| (id, if seeding { p.uploaded } else { p.downloaded }))
+            .collect();
+        let rotate = state.rounds % OPTIMISTIC_ROUNDS == 0;
+        let (regular, optimistic) = select_unchoked(&rates, self.slots, state.optimistic, rotate);
+        state.optimistic = optimistic;
+        for (id, peer) in state.peers.iter_mut() {
+            let unchoke = regular.contains(id) || optimistic == Some(*id);
+            set_choked(peer, !unchoke);
+            peer.downloaded = 0;
+            peer.uploaded = 0;
+        }
+    }
+}
+
+/* Send choke or unchoke if the peer's state changes. A failed write means the connection is
+going away, its own task notices and unregisters it. */
+fn set_choked(peer: &mut ChokerPeer, choked: bool) {
+    if peer.choked == choked {
+        return;
+    }
+    let id = if choked {
+        MESSAGE_CHOKE
+    } else {
+        MESSAGE_UNCHOKE
+    };
+    let msg = Message {
+        id,
+        ..Message::default()
+    };
+    if peer.writer.send(msg).is_ok() {
+        peer.choked = choked;
+    }
+}
+
+/* Pick the slots - 1 fastest of the interested peers in rates, ties broken at random, plus an
+optimistic unchoke among the rest. The current optimistic unchoke is kept unless rotate is set. */
+fn select_unchoked(
+    rates: &[(usize, u64)],
+    slots: usize,
+    optimistic: Option<usize>,
+    rotate: bool,
+) -> (Vec<usize>, Option<usize>) {
+    let mut ranked = rates.to_vec();
+    ranked.shuffle(&mut rand::thread_rng());
+    ranked.sort_by_key(|&(_, rate)| std::cmp::Reverse(rate));
+    let regular: Vec<usize> = ranked
+        .iter()
+        .take(slots.saturating_sub(1))
+        .map(|&(id, _)| id)
+        .collect();
+    if slots == 0 {
+        return (regular, None);
+    }
+    let rest: Vec<usize> = ranked
+        .iter()
+        .map(|&(id, _)| id)
+        .filter(|id| !regular.contains(id))
+        .collect();
+    let optimistic = match optimistic {
+        Some(id) if !rotate && rest.contains(&id) => Some(id),
+        _ => rest.choose(&mut rand::thread_rng()).copied(),
+    };
+    (regular, optimistic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_unchoked_prefers_fastest() {
+        let rates = vec![(1, 100), (2, 5000), (3, 0), (4, 300), (5, 2000)];
+        let (regular, optimistic) = select_unchoked(&rates, 4, None, false);
+        assert_eq!(regular, vec![2, 5, 4]);
+        assert!(optimistic == Some(1) || optimistic == Some(3));
+    }
+
+    #[test]
+    fn test_select_unchoked_keeps_optimistic_until_rotation() {
+        let rates = vec![(1, 100), (2, 5000), (3, 0), (4, 50)];
+        let (_, optimistic) = select_unchoked(&rates, 2, Some(3), false);
+        assert_eq!(optimistic, Some(3));
+
+        /* Rotation picks at random, so over many rounds another peer gets a turn */
+        let rotated = (0..100).any(|_| select_unchoked(&rates, 2, Some(3), true).1 != Some(3));
+        assert!(rotated);
+    }
+
+    #[test]
+    fn test_select_unchoked_optimistic_promoted_to_regular() {
+        let rates = vec![(1, 100), (2, 5000)];
+        let (regular, optimistic) = select_unchoked(&rates, 2, Some(2), false);
+        assert_eq!(regular, vec![2]);
+        assert_eq!(optimistic, Some(1));
+    }
+
+    #[test]
+    fn test_select_unchoked_few_peers() {
+        assert_eq!(select_unchoked(&[], 4, None, true), (vec![], None));
+        let (regular, optimistic) = select_unchoked(&[(7, 10)], 4, None, false);
+        assert_eq!(regular, vec![7]);
+        assert_eq!(optimistic, None);
+        assert_eq!(select_unchoked(&[(7, 10)], 0, None, false), (vec![], None));
+    }
+}
@@ -0,0 +1,598 @@
+use crate::create::CreateOptions;
+use crate::log::Verbosity;
+use crate::picker::DEFAULT_READ_AHEAD;
+use crate::priority::parse_file_list;
+use crate::torrentfile::{Destination, DownloadOptions};
+use std::fs;
+use std::str::FromStr;
+
+pub static USAGE: &str = "Usage:
+  rust-torrent download <torrent or magnet> [destination] [options]
+  rust-torrent seed <torrent> [destination] [options]
+  rust-torrent magnet <magnet link> [destination] [options]
+  rust-torrent create <file or directory> <torrent> [create options]
+  rust-torrent info <torrent> [--json]
+  rust-torrent verify <torrent> <data>
+
+Download options:
+  -o, --output-dir DIR      save into DIR instead of a given destination (default .)
+  -p, --port PORT           port for incoming peers (default 6881)
+      --max-peers N         outbound peer connections at once (default 50)
+      --max-inbound N       incoming peer connections at once (default 50)
+      --download-limit RATE bytes per second for this torrent, K and M suffixes allowed, 0 for
+                            unlimited
+      --upload-limit RATE   bytes per second for this torrent
+      --global-download-limit RATE, --global-upload-limit RATE
+                            bytes per second for every torrent together
+      --peer-download-limit RATE, --peer-upload-limit RATE
+                            bytes per second for each peer connection
+      --count-overhead      count protocol messages towards the limits, not just piece data
+      --alt-download-limit RATE, --alt-upload-limit RATE
+                            global limits while the alternate schedule is in effect
+      --alt-schedule HH:MM-HH:MM
+                            local time of day the alternate limits apply, e.g. 22:00-07:00
+      --unchoke-slots N     peers uploaded to at once (default 4)
+      --seed                keep seeding once the download is complete
+      --sequential          fetch pieces in order, for playing or reading while downloading
+      --read-ahead N        pieces fetched in order ahead of the first missing one (default 8)
+      --select LIST         only download these files, e.g. 0,2,5-7 (indices as listed by info)
+      --file-priority LIST=PRIORITY
+                            skip, low, normal or high for the listed files, repeatable
+      --commands            read commands from standard input while running, one per line:
+                            seek OFFSET, deadline PIECE MILLISECONDS, no-deadline PIECE,
+                            priority LIST=PRIORITY, or any of the limits above except the
+                            alternate ones followed by a RATE
+
+Create options:
+      --announce URL[,URL...]  add a tier of trackers, repeatable
+      --piece-length BYTES     power of two of at least 16384, picked from the size by default
+      --comment TEXT
+      --created-by TEXT
+      --private
+      --web-seed URL           repeatable
+
+General options:
+      --config FILE         read options from FILE, one `name = value` per line
+  -q, --quiet               only print errors and command output
+  -v, --verbose             also print per peer and tracker detail
+  -h, --help                print this help";
+
+#[derive(Debug)]
+pub enum Command {
+    Download {
+        input: String,
+        destination: Destination,
+        options: DownloadOptions,
+    },
+    Create {
+        path: String,
+        torrent: String,
+        options: CreateOptions,
+    },
+    Info {
+        torrent: String,
+        json: bool,
+    },
+    Verify {
+        torrent: String,
+        path: String,
+    },
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub verbosity: Verbosity,
+}
+
+/* Every option, whether it takes a value and the subcommands it applies to */
+static OPTIONS: &[(&str, bool, &[&str])] = &[
+    ("output-dir", true, &["download", "seed", "magnet"]),
+    ("port", true, &["download", "seed", "magnet"]),
+    ("max-peers", true, &["download", "seed", "magnet"]),
+    ("max-inbound", true, &["download", "seed", "magnet"]),
+    ("download-limit", true, &["download", "seed", "magnet"]),
+    ("upload-limit", true, &["download", "seed", "magnet"]),
+    (
+        "global-download-limit",
+        true,
+        &["download", "seed", "magnet"],
+    ),
+    ("global-upload-limit", true, &["download", "seed", "magnet"]),
+    ("peer-download-limit", true, &["download", "seed", "magnet"]),
+    ("peer-upload-limit", true, &["download", "seed", "magnet"]),
+    ("count-overhead", false, &["download", "seed", "magnet"]),
+    ("alt-download-limit", true, &["download", "seed", "magnet"]),
+    ("alt-upload-limit", true, &["download", "seed", "magnet"]),
+    ("alt-schedule", true, &["download", "seed", "magnet"]),
+    ("unchoke-slots", true, &["download", "seed", "magnet"]),
+    ("seed", false, &["download", "seed", "magnet"]),
+    ("sequential", false, &["download", "seed", "magnet"]),
+    ("read-ahead", true, &["download", "seed", "magnet"]),
+    ("select", true, &["download", "seed", "magnet"]),
+    ("file-priority", true, &["download", "seed", "magnet"]),
+    ("commands", false, &["download", "seed", "magnet"]),
+    ("announce", true, &["create"]),
+    ("piece-length", true, &["create"]),
+    ("comment", true, &["create"]),
+    ("created-by", true, &["create"]),
+    ("private", false, &["create"]),
+    ("web-seed", true, &["create"]),
+    ("json", false, &["info"]),
+    ("quiet", false, &[]),
+    ("verbose", false, &[]),
+];
+
+static COMMANDS: &[&str] = &["download", "seed", "magnet", "create", "info", "verify"];
+
+fn long_name(flag: &str) -> Option<&str> {
+    match flag {
+        "-o" => Some("output-dir"),
+        "-p" => Some("port"),
+        "-q" => Some("quiet"),
+        "-v" => Some("verbose"),
+        _ => flag.strip_prefix("--"),
+    }
+}
+
+fn option(name: &str) -> Option<&'static (&'static str, bool, &'static [&'static str])> {
+    OPTIONS.iter().find(|option| option.0 == name)
+}
+
+/* Options with an empty command list are accepted everywhere */
+fn applies_to(name: &str, command: &str) -> bool {
+    option(name).is_some_and(|(_, _, commands)| commands.is_empty() || commands.contains(&command))
+}
+
+/* Everything an option can set, before it is known which command it ends up in */
+struct Settings {
+    verbosity: Verbosity,
+    download: DownloadOptions,
+    output_dir: Option<String>,
+    sequential: bool,
+    read_ahead: usize,
+    create: CreateOptions,
+    json: bool,
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("--{} expects a number, got {}", name, value))
+}
+
+fn file_list(name: &str, value: &str) -> Result<Vec<usize>, String> {
+    parse_file_list(value).ok_or_else(|| {
+        format!(
+            "--{} expects file indices such as 0,2,5-7, got {}",
+            name, value
+        )
+    })
+}
+
+/* Bytes per second with an optional K or M suffix, 0 meaning unlimited */
+pub(crate) fn rate(name: &str, value: &str) -> Result<Option<u64>, String> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1024),
+        Some('M') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    let rate = number::<u64>(name, digits)?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("--{} is too large, got {}", name, value))?;
+    Ok(if rate == 0 { None } else { Some(rate) })
+}
+
+impl Settings {
+    /* Switches in a config file carry true or false, on the command line they carry nothing */
+    fn apply(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        let value = value.unwrap_or("true");
+        let switch = || match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(format!("{} expects true or false, got {}", name, value)),
+        };
+        match name {
+            "output-dir" => self.output_dir = Some(value.to_string()),
+            "port" => self.download.port = number(name, value)?,
+            "max-peers" => self.download.max_peers = number(name, value)?,
+            "max-inbound" => self.download.max_inbound = number(name, value)?,
+            "download-limit" => self.download.limits.torrent.download = rate(name, value)?,
+            "upload-limit" => self.download.limits.torrent.upload = rate(name, value)?,
+            "global-download-limit" => self.download.limits.global.download = rate(name, value)?,
+            "global-upload-limit" => self.download.limits.global.upload = rate(name, value)?,
+            "peer-download-limit" => self.download.limits.peer.download = rate(name, value)?,
+            "peer-upload-limit" => self.download.limits.peer.upload = rate(name, value)?,
+            "count-overhead" => self.download.limits.count_overhead = switch()?,
+            "alt-download-limit" => self.download.limits.alternate.download = rate(name, value)?,
+            "alt-upload-limit" => self.download.limits.alternate.upload = rate(name, value)?,
+            "alt-schedule" => {
+                let schedule = value.parse().map_err(|e| format!("--{}: {}", name, e))?;
+                self.download.limits.schedule = Some(schedule);
+            }
+            "unchoke-slots" => self.download.unchoke_slots = number(name, value)?,
+            "seed" => self.download.seed = switch()?,
+            "sequential" => self.sequential = switch()?,
+            "read-ahead" => self.read_ahead = number(name, value)?,
+            "select" => self.download.files.only = Some(file_list(name, value)?),
+            "commands" => self.download.commands = switch()?,
+            "file-priority" => {
+                let (list, priority) = value
+                    .rsplit_once('=')
+                    .ok_or_else(|| format!("--{} expects LIST=PRIORITY, got {}", name, value))?;
+                let priority = priority.trim().parse()?;
+                self.download
+                    .files
+                    .priorities
+                    .push((file_list(name, list)?, priority));
+            }
+            /* Each --announce is a tier, trackers within a tier are separated by commas */
+            "announce" => self
+                .create
+                .announce_list
+                .push(value.split(',').map(|url| url.trim().to_string()).collect()),
+            "piece-length" => self.create.piece_length = Some(number(name, value)?),
+            "comment" => self.create.comment = Some(value.to_string()),
+            "created-by" => self.create.created_by = Some(value.to_string()),
+            "private" => self.create.private = switch()?,
+            "web-seed" => self.create.web_seeds.push(value.to_string()),
+            "json" => self.json = switch()?,
+            "quiet" if switch()? => self.verbosity = Verbosity::Quiet,
+            "verbose" if switch()? => self.verbosity = Verbosity::Verbose,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/* name = value lines, blank lines and lines starting with # are skipped */
+fn parse_config(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut entries = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) => {
+                entries.push((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Err(format!("config line {}: expected name = value", number + 1)),
+        }
+    }
+    Ok(entries)
+}
+
+/* Parse the arguments after the program name. The old `<torrent> <destination> [options]` form
+is still understood as download. */
+pub fn parse(args: &[String]) -> Result<Cli, String> {
+    let first = match args.first() {
+        Some(first) => first.as_str(),
+        None => return Err("no command given".to_string()),
+    };
+    let (command, rest) = match first {
+        "help" | "-h" | "--help" => {
+            return Ok(Cli {
+                command: Command::Help,
+                verbosity: Verbosity::Normal,
+            })
+        }
+        _ if COMMANDS.contains(&first) => (first, &args[1..]),
+        _ if !first.starts_with('-') => ("download", args),
+        _ => return Err(format!("expected a command before {}", first)),
+    };
+
+    let mut positional = vec![];
+    let mut flags: Vec<(String, Option<String>)> = vec![];
+    let mut config = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            positional.push(arg.to_owned());
+            continue;
+        }
+        if arg == "-h" || arg == "--help" {
+            return Ok(Cli {
+                command: Command::Help,
+                verbosity: Verbosity::Normal,
+            });
+        }
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let name = match long_name(flag) {
+            Some(name) => name,
+            None => return Err(format!("unknown option {}", flag)),
+        };
+        if name == "config" {
+            config = match inline.or_else(|| rest.next().cloned()) {
+                Some(path) => Some(path),
+                None => return Err("--config expects a value".to_string()),
+            };
+            continue;
+        }
+        if !applies_to(name, command) {
+            return Err(format!("unknown option {} for {}", flag, command));
+        }
+        let takes_value = option(name).is_some_and(|option| option.1);
+        let value = match (takes_value, inline) {
+            (true, Some(value)) => Some(value),
+            (true, None) => match rest.next() {
+                Some(value) => Some(value.to_owned()),
+                None => return Err(format!("--{} expects a value", name)),
+            },
+            (false, Some(_)) => return Err(format!("--{} doesn't take a value", name)),
+            (false, None) => None,
+        };
+        flags.push((name.to_string(), value));
+    }
+
+    let mut settings = Settings {
+        verbosity: Verbosity::Normal,
+        download: DownloadOptions::default(),
+        output_dir: None,
+        sequential: false,
+        read_ahead: DEFAULT_READ_AHEAD,
+        create: CreateOptions::default(),
+        json: false,
+    };
+    if command == "seed" {
+        settings.download.seed = true;
+    }
+    /* A config file is shared between commands, so options that don't apply are skipped rather
+    than rejected. The command line comes after and overrides it. */
+    if let Some(path) = config {
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        for (name, value) in parse_config(&text).map_err(|e| format!("{}: {}", path, e))? {
+            if option(&name).is_none() {
+                return Err(format!("{}: unknown option {}", path, name));
+            }
+            if applies_to(&name, command) {
+                settings.apply(&name, Some(&value))?;
+            }
+        }
+    }
+    for (name, value) in &flags {
+        settings.apply(name, value.as_deref())?;
+    }
+    if settings.sequential {
+        settings.download.sequential = Some(settings.read_ahead.max(1));
+    }
+
+    let expect = |min: usize, max: usize, what: &str| {
+        if positional.len() < min || positional.len() > max {
+            Err(format!("usage: rust-torrent {} {}", command, what))
+        } else {
+            Ok(())
+        }
+    };
+    let command = match command {
+        "download" | "seed" | "magnet" => {
+            expect(1, 2, "<input> [destination] [options]")?;
+            let input = positional[0].to_owned();
+            if command == "magnet" && !input.starts_with("magnet:") {
+                return Err(format!("{} is not a magnet link", input));
+            }
+            let destination = match positional.get(1) {
+                Some(path) => Destination::Path(path.to_owned()),
+                None => Destination::Dir(settings.output_dir.unwrap_or_else(|| ".".to_string())),
+            };
+            Command::Download {
+                input,
+                destination,
+                options: settings.download,
+            }
+        }
+        "create" => {
+            expect(2, 2, "<file or directory> <torrent> [options]")?;
+            Command::Create {
+                path: positional[0].to_owned(),
+                torrent: positional[1].to_owned(),
+                options: settings.create,
+            }
+        }
+        "info" => {
+            expect(1, 1, "<torrent> [--json]")?;
+            Command::Info {
+                torrent: positional[0].to_owned(),
+                json: settings.json,
+            }
+        }
+        _ => {
+            expect(2, 2, "<torrent> <data>")?;
+            Command::Verify {
+                torrent: positional[0].to_owned(),
+                path: positional[1].to_owned(),
+            }
+        }
+    };
+    Ok(Cli {
+        command,
+        verbosity: settings.verbosity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::{FileSelection, Priority};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_download() {
+        let cli = parse(&args(
+            "download a.torrent -o out --port 7000 --max-peers=10 --download-limit 2M --upload-limit 0 -q --commands",
+        ))
+        .unwrap();
+        assert_eq!(cli.verbosity, Verbosity::Quiet);
+        match cli.command {
+            Command::Download {
+                input,
+                destination,
+                options,
+            } => {
+                assert_eq!(input, "a.torrent");
+                assert_eq!(destination, Destination::Dir("out".to_string()));
+                assert_eq!(options.port, 7000);
+                assert_eq!(options.max_peers, 10);
+                assert_eq!(options.limits.torrent.download, Some(2 * 1024 * 1024));
+                assert_eq!(options.limits.torrent.upload, None);
+                assert!(!options.seed);
+                assert_eq!(options.sequential, None);
+                assert_eq!(options.files, FileSelection::default());
+                assert!(options.commands);
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_legacy_and_seed() {
+        match parse(&args("a.torrent out.iso --seed")).unwrap().command {
+            Command::Download {
+                destination,
+                options,
+                ..
+            } => {
+                assert_eq!(destination, Destination::Path("out.iso".to_string()));
+                assert!(options.seed);
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+        match parse(&args(
+            "seed a.torrent --select 0,3-4 --file-priority 1,2=high --file-priority 3=skip",
+        ))
+        .unwrap()
+        .command
+        {
+            Command::Download {
+                destination,
+                options,
+                ..
+            } => {
+                assert_eq!(destination, Destination::Dir(".".to_string()));
+                assert!(options.seed);
+                assert_eq!(
+                    options.files,
+                    FileSelection {
+                        only: Some(vec![0, 3, 4]),
+                        priorities: vec![(vec![1, 2], Priority::High), (vec![3], Priority::Skip)],
+                    }
+                );
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_parse_usage_errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&args("download")).is_err());
+        assert!(parse(&args("download a b c")).is_err());
+        assert!(parse(&args("download a --port")).is_err());
+        assert!(parse(&args("download a --port many")).is_err());
+        assert!(parse(&args("download a --frobnicate")).is_err());
+        assert!(parse(&args("info a.torrent --seed")).is_err());
+        assert!(parse(&args("verify a.torrent")).is_err());
+        assert!(parse(&args("magnet a.torrent")).is_err());
+        assert!(parse(&args("create dir --private=yes out.torrent")).is_err());
+        assert!(parse(&args("download a --select 1,x")).is_err());
+        assert!(parse(&args("download a --file-priority 1=urgent")).is_err());
+        assert!(parse(&args("download a --file-priority high")).is_err());
+        assert!(parse(&args("download a --download-limit 99999999999999999M")).is_err());
+        assert!(matches!(
+            parse(&args("seed --help")).unwrap().command,
+            Command::Help
+        ));
+    }
+
+    #[test]
+    fn test_parse_create_and_info() {
+        match parse(&args(
+            "create dir out.torrent --announce http://a,http://b --announce udp://c --private -v",
+        ))
+        .unwrap()
+        {
+            Cli {
+                command:
+                    Command::Create {
+                        path,
+                        torrent,
+                        options,
+                    },
+                verbosity,
+            } => {
+                assert_eq!((path.as_str(), torrent.as_str()), ("dir", "out.torrent"));
+                assert_eq!(options.announce_list.len(), 2);
+                assert_eq!(options.announce_list[0].len(), 2);
+                assert!(options.private);
+                assert_eq!(verbosity, Verbosity::Verbose);
+            }
+            cli => panic!("unexpected {:?}", cli),
+        }
+        assert!(matches!(
+            parse(&args("info a.torrent --json")).unwrap().command,
+            Command::Info { json: true, .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let line =
+            "seed a.torrent --global-download-limit 1M --peer-upload-limit 100K --count-overhead \
+                    --alt-download-limit 50K --alt-schedule 22:00-07:00";
+        match parse(&args(line)).unwrap().command {
+            Command::Download { options, .. } => {
+                let limits = options.limits;
+                assert_eq!(limits.global.download, Some(1024 * 1024));
+                assert_eq!(limits.peer.upload, Some(100 * 1024));
+                assert_eq!(limits.alternate.download, Some(50 * 1024));
+                assert_eq!(limits.torrent.download, None);
+                assert!(limits.count_overhead);
+                assert!(limits.schedule.is_some());
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+        assert!(parse(&args("download a --alt-schedule 22:00")).is_err());
+        assert!(parse(&args("download a --alt-schedule 25:00-07:00")).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("rust-torrent-{}.conf", rand::random::<u32>()));
+        fs::write(
+            &path,
+            "# shared settings\nport = 7001\nupload-limit = 500K\nprivate = true\nseed = true\n\nverbose = true\n",
+        )
+        .unwrap();
+        let line = format!(
+            "download a.torrent --config {} --port 7002 --sequential --read-ahead 4",
+            path.display()
+        );
+        let cli = parse(&args(&line)).unwrap();
+        assert_eq!(cli.verbosity, Verbosity::Verbose);
+        match cli.command {
+            Command::Download { options, .. } => {
+                /* The command line wins over the file, private is skipped as a create option */
+                assert_eq!(options.port, 7002);
+                assert_eq!(options.limits.torrent.upload, Some(500 * 1024));
+                assert!(options.seed);
+                assert_eq!(options.sequential, Some(4));
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+
+        fs::write(&path, "port: 7001\n").unwrap();
+        assert!(parse(&args(&line)).is_err());
+        fs::write(&path, "colour = blue\n").unwrap();
+        assert!(parse(&args(&line)).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(parse(&args(&line)).is_err());
+    }
+}
@@ -1,167 +1,374 @@
 use crate::bitfield::Bitfield;
-use crate::handshake::{read_handshake, serialize_handshake, Handshake};
+use crate::extension::*;
+use crate::handshake::*;
 use crate::message::*;
 use crate::peers::Peer;
-#[allow(unused_imports)]
-use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpStream};
-#[allow(unused_imports)]
-use std::sync::mpsc;
-#[allow(unused_imports)]
-use std::thread;
-use std::time::Duration;
+use crate::ratelimit::ConnectionLimits;
+use futures::SinkExt;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::stream::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/* Connecting and exchanging handshakes with a peer */
+static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/* The bitfield, and the extended handshake when we wait for it */
+static BITFIELD_TIMEOUT: Duration = Duration::from_secs(5);
+/* A connection nothing arrives on for this long is given up on */
+static IDLE_TIMEOUT: Duration = Duration::from_secs(1000);
+
+/* What the writer task of a connection is asked to do */
+enum Outgoing {
+    Message(Message),
+    /* Applies to the messages queued after it */
+    Limits(ConnectionLimits),
+}
 
 #[allow(dead_code)]
 pub struct Client {
-    pub(crate) conn: TcpStream,
+    frames: FramedRead<ReadHalf<TcpStream>, MessageCodec>,
+    /* Every message goes to the connection's writer task, so writes from a PeerWriter never
+    interleave with ours */
+    outbox: UnboundedSender<Outgoing>,
     pub(crate) choked: bool,
     pub(crate) bitfield: Bitfield,
+    /* Our extension handlers, extended messages are dispatched to them as they are read */
+    pub(crate) extensions: ExtensionRegistry,
+    /* The peer's extended handshake, None until one has been received */
+    pub(crate) peer_extensions: Option<ExtendedHandshake>,
     peer: Peer,
     info_hash: Vec<u8>,
     peer_id: Vec<u8>,
+    /* Applied to every message read after the bitfield, unlimited until set */
+    limits: ConnectionLimits,
+}
+
+/* Second handle on a Client's connection, used by the choker to send choke and unchoke */
+#[derive(Clone)]
+pub struct PeerWriter {
+    outbox: UnboundedSender<Outgoing>,
+}
+
+impl PeerWriter {
+    #[cfg(test)]
+    pub(crate) fn new(conn: std::net::TcpStream) -> PeerWriter {
+        crate::runtime::block_on(async {
+            let (_, writer) = io::split(TcpStream::from_std(conn).unwrap());
+            PeerWriter {
+                outbox: spawn_writer(writer),
+            }
+        })
+    }
+
+    /* Queue a message behind the ones already sent on the connection */
+    pub(crate) fn send(&self, msg: Message) -> Result<(), Error> {
+        queue(&self.outbox, Outgoing::Message(msg))
+    }
+}
+
+/* Fails once the writer task has stopped, which it does when a write fails */
+fn queue(outbox: &UnboundedSender<Outgoing>, out: Outgoing) -> Result<(), Error> {
+    outbox
+        .send(out)
+        .map_err(|_| Error::new(ErrorKind::BrokenPipe, "connection closed"))
+}
+
+/* Start the task that writes a connection's messages in the order they are queued. It runs until
+every handle on the connection is dropped, so the messages queued last are still sent. */
+fn spawn_writer(writer: WriteHalf<TcpStream>) -> UnboundedSender<Outgoing> {
+    let (outbox, mut queued) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut sink = FramedWrite::new(writer, MessageCodec);
+        let mut limits = ConnectionLimits::default();
+        while let Some(out) = queued.recv().await {
+            match out {
+                Outgoing::Limits(new_limits) => limits = new_limits,
+                Outgoing::Message(msg) => {
+                    limits.sending(&msg).await;
+                    if sink.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    outbox
 }
 
 impl Client {
-    pub(crate) fn read(&mut self) -> Result<Message, Error> {
-        match read_message(&mut self.conn) {
-            Ok(msg) => Ok(msg),
-            Err(e) => Err(e),
+    fn new(
+        frames: FramedRead<ReadHalf<TcpStream>, MessageCodec>,
+        writer: WriteHalf<TcpStream>,
+        bitfield: Bitfield,
+        extensions: ExtensionRegistry,
+        peer: Peer,
+        info_hash: &[u8],
+        peer_id: &[u8],
+    ) -> Client {
+        Client {
+            frames,
+            outbox: spawn_writer(writer),
+            choked: true,
+            bitfield,
+            extensions,
+            peer_extensions: None,
+            peer,
+            info_hash: info_hash.to_vec(),
+            peer_id: peer_id.to_vec(),
+            limits: ConnectionLimits::default(),
         }
     }
 
-    pub(crate) fn send_request(
-        &mut self,
-        index: &u32,
-        begin: &u32,
-        length: &u32,
-    ) -> Result<(), Error> {
-        let req = format_request(*index, *begin, *length);
-        match self.conn.write(&serialize_message(&req)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    pub(crate) fn send(&self, msg: Message) -> Result<(), Error> {
+        queue(&self.outbox, Outgoing::Message(msg))
+    }
+
+    /* A handle other tasks can use to send messages on this connection */
+    pub(crate) fn writer(&self) -> PeerWriter {
+        PeerWriter {
+            outbox: self.outbox.clone(),
         }
     }
 
-    pub(crate) fn send_interested(&mut self) -> Result<(), Error> {
-        let mut msg = Message::default();
-        msg.id = MESSAGE_INTERESTED;
-        match self.conn.write(&serialize_message(&msg)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    /* Limit the messages read from now on and the ones sent after those already queued */
+    pub(crate) fn set_limits(&mut self, limits: ConnectionLimits) {
+        let _ = queue(&self.outbox, Outgoing::Limits(limits.clone()));
+        self.limits = limits;
+    }
+
+    pub(crate) async fn read(&mut self) -> Result<Message, Error> {
+        let next = timeout(IDLE_TIMEOUT, self.frames.next()).await?;
+        self.receive(next).await
+    }
+
+    /* Wait up to wait for a message. Unlike a failed read, nothing is lost when it runs out
+    halfway through a message, the codec keeps what arrived for the next call. */
+    pub(crate) async fn poll(&mut self, wait: Duration) -> Result<Option<Message>, Error> {
+        match timeout(wait, self.frames.next()).await {
+            Ok(next) => self.receive(next).await.map(Some),
+            Err(_) => Ok(None),
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn send_not_interested(&mut self) -> Result<(), Error> {
-        let mut msg = Message::default();
-        msg.id = MESSAGE_NOT_INTERESTED;
-        match self.conn.write(&serialize_message(&msg)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    async fn receive(&mut self, next: Option<Result<Message, Error>>) -> Result<Message, Error> {
+        let msg = match next {
+            Some(msg) => msg?,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+        };
+        /* Not reading on while the rate is used up makes the peer slow down */
+        self.limits.received(&msg).await;
+        if msg.id == MESSAGE_EXTENDED {
+            self.handle_extended(&msg)?;
         }
+        Ok(msg)
     }
 
-    pub(crate) fn send_unchoke(&mut self) -> Result<(), Error> {
-        let mut msg = Message::default();
-        msg.id = MESSAGE_UNCHOKE;
-        match self.conn.write(&serialize_message(&msg)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    /* Hand an extended message to the registry and send whatever the handlers reply */
+    fn handle_extended(&mut self, msg: &Message) -> Result<(), Error> {
+        let replies = if msg.payload.first() == Some(&EXTENDED_HANDSHAKE_ID) {
+            let hs = parse_extended_handshake(msg)?;
+            let replies = self.extensions.on_handshake(&hs)?;
+            self.peer_extensions = Some(hs);
+            replies
+        } else {
+            self.extensions.dispatch(msg)?
+        };
+        for reply in replies {
+            self.send(reply)?;
         }
+        Ok(())
     }
 
-    pub(crate) fn send_have(&mut self, index: u32) -> Result<(), Error> {
-        let msg = format_have(index);
-        match self.conn.write(&serialize_message(&msg)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    pub(crate) fn send_request(&self, index: &u32, begin: &u32, length: &u32) -> Result<(), Error> {
+        self.send(format_request(*index, *begin, *length))
+    }
+
+    pub(crate) fn send_interested(&self) -> Result<(), Error> {
+        let msg = Message {
+            id: MESSAGE_INTERESTED,
+            ..Message::default()
+        };
+        self.send(msg)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn send_not_interested(&self) -> Result<(), Error> {
+        let msg = Message {
+            id: MESSAGE_NOT_INTERESTED,
+            ..Message::default()
+        };
+        self.send(msg)
+    }
+
+    pub(crate) fn send_bitfield(&self, bitfield: &Bitfield) -> Result<(), Error> {
+        let msg = Message {
+            id: MESSAGE_BITFIELD,
+            payload: bitfield.to_vec(),
+        };
+        self.send(msg)
+    }
+
+    pub(crate) fn send_piece(&self, index: u32, begin: u32, block: &[u8]) -> Result<(), Error> {
+        self.send(format_piece(index, begin, block))
+    }
+
+    pub(crate) fn send_have(&self, index: u32) -> Result<(), Error> {
+        self.send(format_have(index))
+    }
+
+    /* Read messages until the peer's extended handshake arrives, if it hasn't already */
+    pub(crate) async fn wait_for_extended_handshake(
+        &mut self,
+    ) -> Result<&ExtendedHandshake, Error> {
+        let deadline = Instant::now() + BITFIELD_TIMEOUT;
+        while self.peer_extensions.is_none() {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if self.poll(wait).await?.is_none() {
+                return Err(ErrorKind::TimedOut.into());
+            }
         }
+        Ok(self.peer_extensions.as_ref().unwrap())
     }
 }
-fn receive_bitfield(conn: &mut TcpStream) -> Result<Bitfield, Error> {
-    conn.set_write_timeout(Some(Duration::new(5, 0))).unwrap();
-    conn.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
-
-    match read_message(conn) {
-        Ok(s) => {
-            let msg = s;
-            let id_error = Error::new(ErrorKind::InvalidData, "id Error");
-            if msg.id != MESSAGE_BITFIELD {
-                Err(id_error)
-            } else {
-                conn.set_write_timeout(Some(Duration::new(1000, 0)))
-                    .unwrap();
-                conn.set_read_timeout(Some(Duration::new(1000, 0))).unwrap();
-                Ok(msg.payload)
-            }
+
+async fn next_message<R: AsyncRead + Unpin>(
+    frames: &mut FramedRead<R, MessageCodec>,
+) -> Result<Message, Error> {
+    match frames.next().await {
+        Some(msg) => msg,
+        None => Err(Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+    }
+}
+
+/* Read the bitfield, extended messages that some peers send first are kept in early */
+async fn receive_bitfield<R: AsyncRead + Unpin>(
+    frames: &mut FramedRead<R, MessageCodec>,
+    early: &mut Vec<Message>,
+) -> Result<Bitfield, Error> {
+    let first_messages = async {
+        let mut msg = next_message(frames).await?;
+        while msg.id == MESSAGE_EXTENDED && early.len() < 4 {
+            early.push(msg);
+            msg = next_message(frames).await?;
         }
-        Err(e) => Err(e),
+        Ok::<Message, Error>(msg)
+    };
+    let msg = timeout(BITFIELD_TIMEOUT, first_messages).await??;
+    let id_error = Error::new(ErrorKind::InvalidData, "id Error");
+    if msg.id != MESSAGE_BITFIELD {
+        Err(id_error)
+    } else {
+        Ok(msg.payload)
     }
 }
 
-fn complete_handshake(
+async fn complete_handshake(
     conn: &mut TcpStream,
-    info_hash: &Vec<u8>,
-    peer_id: &Vec<u8>,
+    info_hash: &[u8],
+    peer_id: &[u8],
 ) -> Result<Handshake, Error> {
-    conn.set_write_timeout(Some(Duration::new(3, 0))).unwrap();
-    conn.set_read_timeout(Some(Duration::new(3, 0))).unwrap();
     let reader_error = Error::new(ErrorKind::InvalidData, "unexpected infohash");
-    let req = Handshake {
+    let mut req = Handshake {
         pstr: String::from("BitTorrent protocol").into_bytes(),
-        info_hash: info_hash.clone(),
-        peer_id: peer_id.clone(),
+        reserved: [0; 8],
+        info_hash: info_hash.to_vec(),
+        peer_id: peer_id.to_vec(),
     };
-    conn.write(&serialize_handshake(&req)).unwrap();
-    let received = match read_handshake(conn) {
-        Ok(received) => received,
-        Err(e) => return Err(e),
+    req.set_reserved_bit(EXTENSION_PROTOCOL_BIT);
+    let exchange = async {
+        conn.write_all(&serialize_handshake(&req)).await?;
+        receive_handshake(conn).await
     };
-    if received.info_hash == info_hash.clone() {
-        conn.set_write_timeout(Some(Duration::new(1000, 0)))
-            .unwrap();
-        conn.set_read_timeout(Some(Duration::new(1000, 0))).unwrap();
+    let received = timeout(HANDSHAKE_TIMEOUT, exchange).await??;
+    if received.info_hash == info_hash {
         Ok(received)
     } else {
         Err(reader_error)
     }
 }
 
-pub(crate) fn new_client(
+pub(crate) async fn new_client(
     peer: &Peer,
-    peer_id: &Vec<u8>,
-    info_hash: &Vec<u8>,
+    peer_id: &[u8],
+    info_hash: &[u8],
 ) -> Result<Client, Error> {
-    let info_hash_copy = info_hash.to_vec();
-    let peer_id_copy = peer_id.to_vec();
-    let three_seconds = Duration::new(3, 0);
-    let peer_clone = peer.clone();
-    match TcpStream::connect_timeout(&SocketAddr::from(peer.get_socket_address()), three_seconds) {
-        Ok(mut s) => match complete_handshake(&mut s, info_hash, peer_id) {
-            Ok(_) => match receive_bitfield(&mut s) {
-                Ok(bf) => {
-                    let client = Client {
-                        conn: s,
-                        choked: true,
-                        bitfield: bf,
-                        peer: peer_clone,
-                        info_hash: info_hash_copy,
-                        peer_id: peer_id_copy,
-                    };
-                    return Ok(client);
-                }
-                Err(e) => return Err(e),
-            },
-            Err(e) => return Err(e),
-        },
-        Err(e) => return Err(e),
+    new_client_with_extensions(peer, peer_id, info_hash, ExtensionRegistry::default()).await
+}
+
+/* Connect and handshake with a peer, negotiating the extensions in the registry if the peer
+supports the extension protocol */
+pub(crate) async fn new_client_with_extensions(
+    peer: &Peer,
+    peer_id: &[u8],
+    info_hash: &[u8],
+    extensions: ExtensionRegistry,
+) -> Result<Client, Error> {
+    let connect = TcpStream::connect(peer.get_socket_address());
+    let mut s = timeout(HANDSHAKE_TIMEOUT, connect).await??;
+    let received = complete_handshake(&mut s, info_hash, peer_id).await?;
+    if received.has_reserved_bit(EXTENSION_PROTOCOL_BIT) {
+        let hs = extensions.handshake(&peer.ip_octets());
+        s.write_all(&serialize_message(&format_extended_handshake(&hs)))
+            .await?;
+    }
+    let (reader, writer) = io::split(s);
+    let mut frames = FramedRead::new(reader, MessageCodec);
+    let mut early = vec![];
+    let bf = receive_bitfield(&mut frames, &mut early).await?;
+    let mut client = Client::new(frames, writer, bf, extensions, *peer, info_hash, peer_id);
+    for msg in early {
+        client.handle_extended(&msg)?;
+    }
+    Ok(client)
+}
+
+/* Answer the handshake of a peer that connected to us. The peer speaks first and is only
+accepted if it asks for info_hash. Its bitfield arrives later, so it starts out empty. */
+pub(crate) async fn accept_client(
+    mut conn: TcpStream,
+    peer_id: &[u8],
+    info_hash: &[u8],
+    extensions: ExtensionRegistry,
+) -> Result<Client, Error> {
+    let received = timeout(HANDSHAKE_TIMEOUT, receive_handshake(&mut conn)).await??;
+    if received.info_hash != info_hash {
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected infohash"));
+    }
+    let mut reply = new_handshake_with_input(info_hash.to_vec(), peer_id.to_vec());
+    reply.set_reserved_bit(EXTENSION_PROTOCOL_BIT);
+    let mut greeting = serialize_handshake(&reply);
+    let peer = Peer {
+        addr: conn.peer_addr()?,
+    };
+    if received.has_reserved_bit(EXTENSION_PROTOCOL_BIT) {
+        let hs = extensions.handshake(&peer.ip_octets());
+        greeting.extend_from_slice(&serialize_message(&format_extended_handshake(&hs)));
     }
+    timeout(HANDSHAKE_TIMEOUT, conn.write_all(&greeting)).await??;
+    let (reader, writer) = io::split(conn);
+    let frames = FramedRead::new(reader, MessageCodec);
+    Ok(Client::new(
+        frames,
+        writer,
+        vec![],
+        extensions,
+        peer,
+        info_hash,
+        peer_id,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::runtime;
+    use std::io::{Read, Write};
     use std::net::{Shutdown, TcpListener};
+    use std::thread;
 
     #[test]
     fn test_successful_receive_bitfield() {
@@ -169,38 +376,40 @@ mod tests {
     }
 
     fn dummy_client_bf() {
-        match TcpStream::connect("127.0.0.1:8082") {
-            Ok(mut stream) => {
-                let msg = b"hello";
-                let pass_test = b"pass";
-                let fail_test = b"fail";
-                stream.write(msg).unwrap();
-                let bf = receive_bitfield(&mut stream).unwrap();
-                let expected_bf: [u8; 5] = [1, 2, 3, 4, 5];
-                if &bf[0..5] == expected_bf {
-                    stream.write(pass_test).unwrap();
-                } else {
-                    stream.write(fail_test).unwrap();
+        runtime::block_on(async {
+            match TcpStream::connect("127.0.0.1:8082").await {
+                Ok(mut stream) => {
+                    let msg = b"hello";
+                    let pass_test = b"pass";
+                    let fail_test = b"fail";
+                    stream.write_all(msg).await.unwrap();
+                    let mut frames = FramedRead::new(&mut stream, MessageCodec);
+                    let bf = receive_bitfield(&mut frames, &mut vec![]).await.unwrap();
+                    let expected_bf: [u8; 5] = [1, 2, 3, 4, 5];
+                    if bf[0..5] == expected_bf {
+                        stream.write_all(pass_test).await.unwrap();
+                    } else {
+                        stream.write_all(fail_test).await.unwrap();
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to connect: {}", e);
                 }
             }
-            Err(e) => {
-                println!("Failed to connect: {}", e);
-            }
-        }
+        })
     }
 
-    fn handle_client_bf(mut stream: TcpStream) {
-        let mut data = [0 as u8; 50]; // using 50 byte buffer
+    fn handle_client_bf(mut stream: std::net::TcpStream) {
+        let mut data = [0u8; 50]; // using 50 byte buffer
         while match stream.read(&mut data) {
             Ok(size) => {
                 // dont echo everything!
                 if &data[0..size] == b"hello" {
                     let msg: [u8; 10] = [0x00, 0x00, 0x00, 0x06, 5, 1, 2, 3, 4, 5];
-                    stream.write(&msg).unwrap();
+                    stream.write_all(&msg).unwrap();
                     true
                 } else {
                     // assert_eq!(&data[0..size], b"pass");
-                    assert_eq!(1, 1);
                     false
                 }
             }
@@ -240,49 +449,49 @@ mod tests {
         create_client_server_hs();
     }
     fn dummy_client_hs() {
-        match TcpStream::connect("127.0.0.1:8080") {
-            Ok(mut stream) => {
-                //let msg = b"hello";
-                let pass_test = b"pass";
-                let fail_test = b"fail";
-                let client_infohash: [u8; 20] = [
-                    134, 212, 200, 0, 36, 164, 105, 190, 76, 80, 188, 90, 16, 44, 247, 23, 128, 49,
-                    0, 116,
-                ];
-                let client_peer_id: [u8; 20] = [
-                    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
-                ];
-                //stream.write(msg).unwrap();
-                let incoming_handshake = complete_handshake(
-                    &mut stream,
-                    &client_infohash.to_vec(),
-                    &client_peer_id.to_vec(),
-                )
-                .unwrap();
-                let expected_infohash: [u8; 20] = [
-                    134, 212, 200, 0, 36, 164, 105, 190, 76, 80, 188, 90, 16, 44, 247, 23, 128, 49,
-                    0, 116,
-                ];
-                let expected_peer_id: [u8; 20] = [
-                    45, 83, 89, 48, 48, 49, 48, 45, 192, 125, 147, 203, 136, 32, 59, 180, 253, 168,
-                    193, 19,
-                ];
-                if incoming_handshake.peer_id[0..20] == expected_peer_id
-                    && incoming_handshake.info_hash[0..20] == expected_infohash
-                {
-                    stream.write(pass_test).unwrap();
-                } else {
-                    stream.write(fail_test).unwrap();
+        runtime::block_on(async {
+            match TcpStream::connect("127.0.0.1:8080").await {
+                Ok(mut stream) => {
+                    //let msg = b"hello";
+                    let pass_test = b"pass";
+                    let fail_test = b"fail";
+                    let client_infohash: [u8; 20] = [
+                        134, 212, 200, 0, 36, 164, 105, 190, 76, 80, 188, 90, 16, 44, 247, 23, 128,
+                        49, 0, 116,
+                    ];
+                    let client_peer_id: [u8; 20] = [
+                        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+                    ];
+                    //stream.write_all(msg).unwrap();
+                    let incoming_handshake =
+                        complete_handshake(&mut stream, &client_infohash, &client_peer_id)
+                            .await
+                            .unwrap();
+                    let expected_infohash: [u8; 20] = [
+                        134, 212, 200, 0, 36, 164, 105, 190, 76, 80, 188, 90, 16, 44, 247, 23, 128,
+                        49, 0, 116,
+                    ];
+                    let expected_peer_id: [u8; 20] = [
+                        45, 83, 89, 48, 48, 49, 48, 45, 192, 125, 147, 203, 136, 32, 59, 180, 253,
+                        168, 193, 19,
+                    ];
+                    if incoming_handshake.peer_id[0..20] == expected_peer_id
+                        && incoming_handshake.info_hash[0..20] == expected_infohash
+                    {
+                        stream.write_all(pass_test).await.unwrap();
+                    } else {
+                        stream.write_all(fail_test).await.unwrap();
+                    }
+                }
+                Err(e) => {
+                    println!("Failed to connect: {}", e);
                 }
             }
-            Err(e) => {
-                println!("Failed to connect: {}", e);
-            }
-        }
+        })
     }
 
-    fn handle_client_hs(mut stream: TcpStream) {
-        let mut data = [0 as u8; 100]; // using 50 byte buffer
+    fn handle_client_hs(mut stream: std::net::TcpStream) {
+        let mut data = [0u8; 100]; // using 50 byte buffer
         let mut counter = 0;
         while match stream.read(&mut data) {
             Ok(size) => {
@@ -294,9 +503,9 @@ mod tests {
                         190, 76, 80, 188, 90, 16, 44, 247, 23, 128, 49, 0, 116, 45, 83, 89, 48, 48,
                         49, 48, 45, 192, 125, 147, 203, 136, 32, 59, 180, 253, 168, 193, 19,
                     ];
-                    stream.write(&server_handshake).unwrap();
-                    counter = counter + 1;
-                    data = [0 as u8; 100];
+                    stream.write_all(&server_handshake).unwrap();
+                    counter += 1;
+                    data = [0u8; 100];
                     true
                 } else {
                     for x in &data[0..size] {
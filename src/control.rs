@@ -0,0 +1,210 @@
+use crate::cli::rate;
+use crate::picker::PiecePicker;
+use crate::priority::{parse_file_list, FilePriorities, Priority};
+use crate::ratelimit::{self, TorrentLimits};
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* A change to a running download, read one per line with --commands */
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    /* Move the sequential window to the piece holding this byte of the torrent */
+    Seek(u64),
+    /* Fetch a piece before everything else, it is needed within the duration */
+    Deadline(u32, Duration),
+    ClearDeadline(u32),
+    /* Same as --file-priority */
+    Priority(Vec<usize>, Priority),
+    /* A new rate for one of the limits in LIMITS, None for unlimited */
+    Limit(&'static str, Option<u64>),
+}
+
+/* The limits that can be changed while running, named like their options */
+static LIMITS: &[&str] = &[
+    "download-limit",
+    "upload-limit",
+    "global-download-limit",
+    "global-upload-limit",
+    "peer-download-limit",
+    "peer-upload-limit",
+];
+
+fn argument<T: FromStr>(command: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got {}", command, value))
+}
+
+/* seek OFFSET, deadline PIECE MILLISECONDS, no-deadline PIECE, priority LIST=PRIORITY or a limit
+followed by RATE */
+pub fn parse_command(line: &str) -> Result<ControlCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["seek", offset] => Ok(ControlCommand::Seek(argument("seek", offset)?)),
+        ["deadline", piece, millis] => Ok(ControlCommand::Deadline(
+            argument("deadline", piece)?,
+            Duration::from_millis(argument("deadline", millis)?),
+        )),
+        ["no-deadline", piece] => Ok(ControlCommand::ClearDeadline(argument(
+            "no-deadline",
+            piece,
+        )?)),
+        ["priority", setting] => {
+            let error = || format!("priority expects LIST=PRIORITY, got {}", setting);
+            let (list, priority) = setting.rsplit_once('=').ok_or_else(error)?;
+            let list = parse_file_list(list).ok_or_else(error)?;
+            Ok(ControlCommand::Priority(list, priority.parse()?))
+        }
+        [name, value] => match LIMITS.iter().find(|&&limit| limit == *name) {
+            Some(limit) => Ok(ControlCommand::Limit(limit, rate(limit, value)?)),
+            None => Err(format!("unknown command {}", line.trim())),
+        },
+        _ => Err(format!("unknown command {}", line.trim())),
+    }
+}
+
+/* The parts of a running download that commands act on */
+pub struct Controls {
+    pub(crate) picker: PiecePicker,
+    pub(crate) piece_length: u32,
+    pub(crate) priorities: FilePriorities,
+    pub(crate) limits: TorrentLimits,
+}
+
+impl Controls {
+    pub fn apply(&self, command: ControlCommand) -> Result<(), String> {
+        match command {
+            ControlCommand::Seek(offset) => {
+                let index = offset / self.piece_length as u64;
+                self.picker.set_position(index.min(u32::MAX as u64) as u32);
+            }
+            ControlCommand::Deadline(index, within) => {
+                self.picker.set_deadline(index, Instant::now() + within)
+            }
+            ControlCommand::ClearDeadline(index) => self.picker.clear_deadline(index),
+            ControlCommand::Priority(files, priority) => self
+                .priorities
+                .set(&files, priority)
+                .map_err(|e| e.to_string())?,
+            /* A scheduled switch to or from the alternate limits overrides the global ones */
+            ControlCommand::Limit(name, rate) => match name {
+                "download-limit" => self.limits.download.set_rate(rate),
+                "upload-limit" => self.limits.upload.set_rate(rate),
+                "global-download-limit" => ratelimit::global().download.set_rate(rate),
+                "global-upload-limit" => ratelimit::global().upload.set_rate(rate),
+                "peer-download-limit" => self.limits.peer_download.set(rate),
+                "peer-upload-limit" => self.limits.peer_upload.set(rate),
+                _ => return Err(format!("unknown limit {}", name)),
+            },
+        }
+        Ok(())
+    }
+}
+
+/* Carry out the commands on standard input until it closes, reporting the ones that fail */
+pub fn read_commands(controls: Controls) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Err(e) = parse_command(&line).and_then(|command| controls.apply(command)) {
+                eprintln!("Error: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("seek 1048576").unwrap(),
+            ControlCommand::Seek(1 << 20)
+        );
+        assert_eq!(
+            parse_command(" deadline 7  2500 ").unwrap(),
+            ControlCommand::Deadline(7, Duration::from_millis(2500))
+        );
+        assert_eq!(
+            parse_command("no-deadline 7").unwrap(),
+            ControlCommand::ClearDeadline(7)
+        );
+        assert!(parse_command("seek").is_err());
+        assert!(parse_command("seek -1").is_err());
+        assert!(parse_command("deadline 7").is_err());
+        assert_eq!(
+            parse_command("priority 0,2-3=high").unwrap(),
+            ControlCommand::Priority(vec![0, 2, 3], Priority::High)
+        );
+        assert!(parse_command("priority 1=urgent").is_err());
+        assert!(parse_command("priority high").is_err());
+        assert_eq!(
+            parse_command("peer-upload-limit 50K").unwrap(),
+            ControlCommand::Limit("peer-upload-limit", Some(50 * 1024))
+        );
+        assert_eq!(
+            parse_command("download-limit 0").unwrap(),
+            ControlCommand::Limit("download-limit", None)
+        );
+        assert!(parse_command("alt-download-limit 1M").is_err());
+        assert!(parse_command("upload-limit fast").is_err());
+        assert!(parse_command("rewind 5").is_err());
+    }
+
+    #[test]
+    fn test_commands_steer_the_picker() {
+        let picker = PiecePicker::new(8, |_| false);
+        let storage = Storage::new(Path::new("/tmp/unused"), "single", &[], 8 * 16384);
+        let priorities = FilePriorities::new(
+            Arc::new(storage),
+            picker.clone(),
+            16384,
+            vec![Priority::Normal],
+        );
+        let controls = Controls {
+            picker: picker.clone(),
+            piece_length: 16384,
+            priorities,
+            limits: TorrentLimits::default(),
+        };
+        /* Seeking switches to sequential picking if it was off */
+        controls
+            .apply(ControlCommand::Seek(5 * 16384 + 100))
+            .unwrap();
+        assert_eq!(picker.pick(&vec![0xff]), Some(5));
+        controls
+            .apply(ControlCommand::Deadline(2, Duration::from_secs(1)))
+            .unwrap();
+        /* Pieces past the end are ignored */
+        controls
+            .apply(ControlCommand::Deadline(99, Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(picker.pick(&vec![0xff]), Some(2));
+        controls
+            .apply(ControlCommand::Priority(vec![0], Priority::Skip))
+            .unwrap();
+        assert_eq!(picker.pick(&vec![0xff]), None);
+
+        controls
+            .apply(parse_command("upload-limit 1M").unwrap())
+            .unwrap();
+        controls
+            .apply(parse_command("peer-download-limit 10K").unwrap())
+            .unwrap();
+        assert_eq!(controls.limits.upload.rate.get(), Some(1024 * 1024));
+        assert_eq!(controls.limits.peer_download.get(), Some(10 * 1024));
+    }
+}
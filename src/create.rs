@@ -0,0 +1,243 @@
+use crate::storage::Storage;
+use crate::torrentfile::*;
+use crate::verify::map_pieces;
+use serde_bencode::ser;
+use serde_bytes::ByteBuf;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static MIN_PIECE_LENGTH: u32 = 16 * 1024;
+static MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+/* Automatic piece sizes aim for about this many pieces */
+static TARGET_PIECES: u64 = 1500;
+
+/* Everything in a new torrent apart from the data itself */
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    /* Picked from the total size when not set */
+    pub piece_length: Option<u32>,
+    /* The first tracker of the first tier also becomes announce */
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    pub web_seeds: Vec<String>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> CreateOptions {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .ok();
+        CreateOptions {
+            piece_length: None,
+            announce_list: vec![],
+            comment: None,
+            created_by: Some(format!("rust-torrent/{}", env!("CARGO_PKG_VERSION"))),
+            creation_date: now,
+            private: false,
+            web_seeds: vec![],
+        }
+    }
+}
+
+/* The smallest power of two piece size that keeps the piece count near TARGET_PIECES */
+pub fn auto_piece_length(total: u64) -> u32 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && total / piece_length as u64 > TARGET_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/* Build bencoded metainfo for the file or directory at path */
+pub fn create(path: &Path, options: &CreateOptions) -> Result<Vec<u8>> {
+    let path = &fs::canonicalize(path)?;
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name.to_string(),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} has no usable file name", path.display()),
+            ))
+        }
+    };
+    let metadata = fs::metadata(path)?;
+    let (files, storage) = if metadata.is_dir() {
+        let mut files = vec![];
+        walk(path, &mut vec![], &mut files)?;
+        let total = files.iter().map(|f| f.Length).sum();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        (files.to_vec(), Storage::new(parent, &name, &files, total))
+    } else {
+        (vec![], Storage::new(path, &name, &[], metadata.len()))
+    };
+    let total: u64 = storage.files().iter().map(|f| f.Length).sum();
+    if total == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} contains no data", path.display()),
+        ));
+    }
+
+    let piece_length = match options.piece_length {
+        Some(n) if n < MIN_PIECE_LENGTH || !n.is_power_of_two() => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "piece length must be a power of two of at least 16 KiB",
+            ))
+        }
+        Some(n) => n,
+        None => auto_piece_length(total),
+    };
+    let num_pieces = total.div_ceil(piece_length as u64) as usize;
+    let hashes = map_pieces(num_pieces, |index| {
+        let begin = index as u64 * piece_length as u64;
+        let end = total.min(begin + piece_length as u64);
+        storage
+            .read(begin, (end - begin) as usize)
+            .map(|buf| sha1_hash(&buf))
+    });
+    let mut pieces = Vec::with_capacity(num_pieces * 20);
+    for hash in hashes {
+        pieces.extend(hash?);
+    }
+
+    let info = BencodeInfo::new(
+        name,
+        piece_length,
+        ByteBuf::from(pieces),
+        total,
+        &files,
+        options.private,
+    );
+    let bto = BencodeTorrent::new(info, options);
+    ser::to_bytes(&bto).map_err(Error::other)
+}
+
+/* Collect the regular files under dir in a stable order, with paths relative to the root */
+fn walk(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<FileEntry>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        let component = match entry.file_name().and_then(|n| n.to_str()) {
+            Some(component) => component.to_string(),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", entry.display()),
+                ))
+            }
+        };
+        prefix.push(component);
+        let metadata = fs::metadata(&entry)?;
+        if metadata.is_dir() {
+            walk(&entry, prefix, files)?;
+        } else if metadata.is_file() {
+            files.push(FileEntry {
+                Path: prefix.to_vec(),
+                Length: metadata.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::verify;
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(1000), 16 * 1024);
+        assert_eq!(auto_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(auto_piece_length(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(auto_piece_length(1 << 50), 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_create_directory_round_trip() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let dir = root.join("album");
+        fs::create_dir_all(dir.join("disc 2")).unwrap();
+        fs::write(dir.join("b.flac"), vec![7; 40000]).unwrap();
+        fs::write(dir.join("a.txt"), b"liner notes").unwrap();
+        fs::write(dir.join("disc 2").join("c.flac"), vec![9; 20000]).unwrap();
+
+        let options = CreateOptions {
+            announce_list: vec![
+                vec!["http://a.example/announce".to_string()],
+                vec!["udp://b.example:80".to_string()],
+            ],
+            comment: Some("test album".to_string()),
+            private: true,
+            web_seeds: vec!["http://seed.example/".to_string()],
+            ..CreateOptions::default()
+        };
+        let encoded = create(&dir, &options).unwrap();
+        let torrent_file = from_bytes(&encoded).unwrap();
+
+        /* The info-hash of the file we wrote matches the one of the dictionary we built */
+        let bto = serde_bencode::de::from_bytes::<BencodeTorrent>(&encoded).unwrap();
+        assert_eq!(torrent_file.InfoHash, bto.info.hash());
+        assert_eq!(torrent_file.Announce, "http://a.example/announce");
+        assert_eq!(torrent_file.AnnounceList.len(), 2);
+        assert_eq!(torrent_file.Name, "album");
+        assert_eq!(torrent_file.PieceLength, 16 * 1024);
+        assert_eq!(torrent_file.Length, 60011);
+        assert_eq!(torrent_file.PieceHashes.len(), 4);
+        let paths: Vec<String> = torrent_file
+            .Files
+            .iter()
+            .map(|f| f.Path.join("/"))
+            .collect();
+        assert_eq!(paths, vec!["a.txt", "b.flac", "disc 2/c.flac"]);
+        let text = String::from_utf8_lossy(&encoded);
+        assert!(text.contains("7:privatei1e"));
+        assert!(text.contains("7:comment10:test album"));
+        assert!(text.contains("8:url-listl20:http://seed.example/e"));
+        assert!(text.contains("13:creation datei"));
+
+        /* The source directory is exactly what the torrent describes */
+        assert!(verify(&torrent_file, &root).is_complete());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_create_single_file() {
+        let path = std::env::temp_dir().join(format!("rust-torrent-{}.iso", rand::random::<u32>()));
+        fs::write(&path, vec![1; 100000]).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(32 * 1024),
+            ..CreateOptions::default()
+        };
+        let torrent_file = from_bytes(&create(&path, &options).unwrap()).unwrap();
+        assert!(torrent_file.Files.is_empty());
+        assert_eq!(torrent_file.Length, 100000);
+        assert_eq!(torrent_file.PieceHashes.len(), 4);
+        assert!(verify(&torrent_file, &path).is_complete());
+
+        let options = CreateOptions {
+            piece_length: Some(20000),
+            ..CreateOptions::default()
+        };
+        assert!(create(&path, &options).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_create_empty_directory() {
+        let dir = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(create(&dir, &CreateOptions::default()).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+extern crate serde_bencode;
+use crate::message::*;
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+
+/* Extended message id reserved for the extended handshake itself */
+pub(crate) static EXTENDED_HANDSHAKE_ID: u8 = 0;
+/* Number of outstanding requests we advertise in reqq */
+pub(crate) static DEFAULT_REQQ: i64 = 250;
+
+/* Payload of the extended handshake, m maps extension names to the ids the sender expects */
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub(crate) m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) v: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reqq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) metadata_size: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    /* Id the remote peer wants us to use for an extension, None if it doesn't support it */
+    pub(crate) fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&id) if id > 0 && id <= u8::MAX as i64 => Some(id as u8),
+            _ => None,
+        }
+    }
+}
+
+/* A protocol extension negotiated through the extended handshake. Handlers return payloads to
+send back, the registry addresses them with the id the peer assigned to the extension. */
+pub trait ExtensionHandler: Send + Sync {
+    /* Name the extension is advertised under in the m dictionary, e.g. ut_metadata */
+    fn name(&self) -> &str;
+
+    /* Add extension specific keys such as metadata_size to our extended handshake */
+    fn extend_handshake(&self, _hs: &mut ExtendedHandshake) {}
+
+    /* Called once the peer's extended handshake arrives and it supports this extension */
+    fn on_handshake(&mut self, _hs: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+
+    /* Called for every message the peer sends to our id for this extension */
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+/* Extensions plugged into a connection, local ids are assigned in registration order from 1 */
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    remote_ids: Vec<Option<u8>>,
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) -> u8 {
+        self.handlers.push(handler);
+        self.remote_ids.push(None);
+        self.handlers.len() as u8
+    }
+
+    /* Build our extended handshake, yourip is the peer's address as we see it */
+    pub(crate) fn handshake(&self, yourip: &[u8]) -> ExtendedHandshake {
+        let mut hs = ExtendedHandshake {
+            v: Some(format!("rust-torrent {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(DEFAULT_REQQ),
+            yourip: Some(ByteBuf::from(yourip.to_vec())),
+            ..ExtendedHandshake::default()
+        };
+        for (i, handler) in self.handlers.iter().enumerate() {
+            hs.m.insert(handler.name().to_string(), i as i64 + 1);
+            handler.extend_handshake(&mut hs);
+        }
+        hs
+    }
+
+    fn address(&self, index: usize, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        match self.remote_ids[index] {
+            Some(remote_id) => payloads
+                .iter()
+                .map(|p| format_extended(remote_id, p))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /* Record the ids the peer assigned and let supported handlers start talking */
+    pub(crate) fn on_handshake(&mut self, hs: &ExtendedHandshake) -> Result<Vec<Message>> {
+        let mut replies = vec![];
+        for i in 0..self.handlers.len() {
+            self.remote_ids[i] = hs.extension_id(self.handlers[i].name());
+            if self.remote_ids[i].is_some() {
+                let payloads = self.handlers[i].on_handshake(hs)?;
+                replies.append(&mut self.address(i, payloads));
+            }
+        }
+        Ok(replies)
+    }
+
+    /* Route an extended message to the handler registered under its id */
+    pub(crate) fn dispatch(&mut self, msg: &Message) -> Result<Vec<Message>> {
+        let local_id = match msg.payload.first() {
+            Some(&id) if id != EXTENDED_HANDSHAKE_ID => id as usize,
+            _ => return Ok(vec![]),
+        };
+        if local_id > self.handlers.len() {
+            return Ok(vec![]);
+        }
+        let payloads = self.handlers[local_id - 1].on_message(&msg.payload[1..])?;
+        Ok(self.address(local_id - 1, payloads))
+    }
+}
+
+pub(crate) fn format_extended_handshake(hs: &ExtendedHandshake) -> Message {
+    format_extended(EXTENDED_HANDSHAKE_ID, &ser::to_bytes(hs).unwrap())
+}
+
+pub(crate) fn parse_extended_handshake(msg: &Message) -> Result<ExtendedHandshake> {
+    if msg.id != MESSAGE_EXTENDED || msg.payload.first() != Some(&EXTENDED_HANDSHAKE_ID) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "expected extended handshake",
+        ));
+    }
+    match de::from_bytes::<ExtendedHandshake>(&msg.payload[1..]) {
+        Ok(hs) => Ok(hs),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoHandler {
+        name: &'static str,
+    }
+
+    impl ExtensionHandler for EchoHandler {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn on_handshake(&mut self, _hs: &ExtendedHandshake) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![b"hello".to_vec()])
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    fn test_registry() -> ExtensionRegistry {
+        let mut registry = ExtensionRegistry::default();
+        assert_eq!(
+            registry.register(Box::new(EchoHandler { name: "ut_echo" })),
+            1
+        );
+        assert_eq!(
+            registry.register(Box::new(EchoHandler { name: "lt_other" })),
+            2
+        );
+        registry
+    }
+
+    #[test]
+    fn test_format_extended_handshake() {
+        let mut hs = ExtendedHandshake::default();
+        hs.m.insert("ut_metadata".to_string(), 1);
+        hs.metadata_size = Some(31235);
+        let msg = format_extended_handshake(&hs);
+        assert_eq!(msg.id, MESSAGE_EXTENDED);
+        assert_eq!(
+            &msg.payload[..],
+            &b"\x00d1:md11:ut_metadatai1ee13:metadata_sizei31235ee"[..]
+        );
+    }
+
+    #[test]
+    fn test_parse_extended_handshake_ignores_unknown_keys() {
+        let msg = format_extended(
+            0,
+            b"d1:md6:ut_pexi2e11:ut_metadatai3ee1:pi6881e4:reqqi500e1:v9:Foo 1.2.36:yourip4:\x7f\x00\x00\x01e",
+        );
+        let hs = parse_extended_handshake(&msg).unwrap();
+        assert_eq!(hs.extension_id("ut_metadata"), Some(3));
+        assert_eq!(hs.extension_id("ut_pex"), Some(2));
+        assert_eq!(hs.extension_id("lt_donthave"), None);
+        assert_eq!(hs.metadata_size, None);
+        assert_eq!(hs.reqq, Some(500));
+        assert_eq!(hs.v, Some("Foo 1.2.3".to_string()));
+        assert_eq!(hs.yourip, Some(ByteBuf::from(vec![127, 0, 0, 1])));
+    }
+
+    #[test]
+    fn test_extension_id_disabled() {
+        let msg = format_extended(0, b"d1:md11:ut_metadatai0eee");
+        let hs = parse_extended_handshake(&msg).unwrap();
+        assert_eq!(hs.extension_id("ut_metadata"), None);
+    }
+
+    #[test]
+    fn test_parse_extended_handshake_wrong_id() {
+        let msg = format_extended(2, b"de");
+        assert!(parse_extended_handshake(&msg).is_err());
+    }
+
+    #[test]
+    fn test_registry_handshake() {
+        let registry = test_registry();
+        let hs = registry.handshake(&[10, 0, 0, 2]);
+        assert_eq!(hs.extension_id("ut_echo"), Some(1));
+        assert_eq!(hs.extension_id("lt_other"), Some(2));
+        assert_eq!(hs.reqq, Some(DEFAULT_REQQ));
+        assert_eq!(hs.yourip, Some(ByteBuf::from(vec![10, 0, 0, 2])));
+        assert!(hs.v.unwrap().starts_with("rust-torrent"));
+    }
+
+    #[test]
+    fn test_registry_on_handshake_uses_remote_ids() {
+        let mut registry = test_registry();
+        let mut peer_hs = ExtendedHandshake::default();
+        peer_hs.m.insert("ut_echo".to_string(), 7);
+        let replies = registry.on_handshake(&peer_hs).unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].payload, b"\x07hello".to_vec());
+    }
+
+    #[test]
+    fn test_registry_dispatch() {
+        let mut registry = test_registry();
+        let mut peer_hs = ExtendedHandshake::default();
+        peer_hs.m.insert("ut_echo".to_string(), 7);
+        registry.on_handshake(&peer_hs).unwrap();
+
+        let replies = registry.dispatch(&format_extended(1, b"ping")).unwrap();
+        assert_eq!(replies[0].payload, b"\x07ping".to_vec());
+        /* lt_other isn't supported by the peer, so its replies are dropped */
+        assert!(registry
+            .dispatch(&format_extended(2, b"ping"))
+            .unwrap()
+            .is_empty());
+        assert!(registry
+            .dispatch(&format_extended(9, b"ping"))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -6,26 +6,46 @@ use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::net::{TcpListener, TcpStream};
 #[allow(unused_imports)]
 use std::thread;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/* Reserved bits are numbered from the right of the 8 reserved bytes, as in the BEPs */
+#[allow(dead_code)]
+pub(crate) static DHT_BIT: usize = 0;
+#[allow(dead_code)]
+pub(crate) static FAST_EXTENSION_BIT: usize = 2;
+pub(crate) static EXTENSION_PROTOCOL_BIT: usize = 20;
 
 pub struct Handshake {
     pub(crate) pstr: Vec<u8>,
+    pub(crate) reserved: [u8; 8],
     pub(crate) info_hash: Vec<u8>,
     pub(crate) peer_id: Vec<u8>,
 }
 
+impl Handshake {
+    pub(crate) fn set_reserved_bit(&mut self, bit: usize) {
+        self.reserved[7 - bit / 8] |= 1 << (bit % 8);
+    }
+
+    pub(crate) fn has_reserved_bit(&self, bit: usize) -> bool {
+        self.reserved[7 - bit / 8] & (1 << (bit % 8)) != 0
+    }
+}
+
 #[allow(dead_code)]
 pub fn new_handshake() -> Handshake {
     Handshake {
         pstr: String::from("BitTorrent protocol").into_bytes(),
+        reserved: [0; 8],
         info_hash: vec![0, 1],
         peer_id: vec![0, 1],
     }
 }
 
-#[allow(dead_code)]
 pub fn new_handshake_with_input(info_hash: Vec<u8>, peer_id: Vec<u8>) -> Handshake {
     Handshake {
         pstr: String::from("BitTorrent protocol").into_bytes(),
+        reserved: [0; 8],
         info_hash,
         peer_id,
     }
@@ -35,8 +55,7 @@ pub fn serialize_handshake(hs: &Handshake) -> Vec<u8> {
     buf.push(hs.pstr.len() as u8);
     let mut pstr_as_vec: Vec<u8> = hs.pstr.clone();
     buf.append(&mut pstr_as_vec);
-    let mut reserved_bytes = vec![0; 8];
-    buf.append(&mut reserved_bytes);
+    buf.extend_from_slice(&hs.reserved);
     let mut info_hash_as_vec: Vec<u8> = hs.info_hash.clone();
     buf.append(&mut info_hash_as_vec);
     let mut peer_id_as_vec: Vec<u8> = hs.peer_id.clone();
@@ -44,42 +63,60 @@ pub fn serialize_handshake(hs: &Handshake) -> Vec<u8> {
     buf
 }
 
-pub fn read_handshake(reader: &mut TcpStream) -> Result<Handshake> {
+/* Read the handshake a peer opens the connection with. Only the handshake is read, the messages
+after it are left for MessageCodec. */
+pub(crate) async fn receive_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Handshake> {
     let mut length_buffer: [u8; 1] = [0; 1];
-    match reader.read_exact(&mut length_buffer) {
-        Err(e) => return Err(e),
-        _ => (),
-    }
-    let pstrlen = length_buffer[0];
-    let reader_error = Error::new(ErrorKind::InvalidData, "unexpected infohash");
+    reader.read_exact(&mut length_buffer).await?;
+    let pstrlen = length_buffer[0] as usize;
     if pstrlen == 0 {
-        return Err(reader_error);
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected infohash"));
     }
-    let mut handshakebuf: Vec<u8> = vec![0; 48 + (pstrlen as usize)];
-    match reader.read_exact(&mut handshakebuf) {
-        Err(e) => return Err(e),
-        _ => (),
+    let mut handshakebuf: Vec<u8> = vec![0; 48 + pstrlen];
+    reader.read_exact(&mut handshakebuf).await?;
+    Ok(parse_handshake(pstrlen, &handshakebuf))
+}
+
+/* Blocking read used by the peers the tests stand up */
+#[cfg(test)]
+pub fn read_handshake(reader: &mut TcpStream) -> Result<Handshake> {
+    let mut length_buffer: [u8; 1] = [0; 1];
+    reader.read_exact(&mut length_buffer)?;
+    let pstrlen = length_buffer[0] as usize;
+    if pstrlen == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected infohash"));
     }
+    let mut handshakebuf: Vec<u8> = vec![0; 48 + pstrlen];
+    reader.read_exact(&mut handshakebuf)?;
+    Ok(parse_handshake(pstrlen, &handshakebuf))
+}
+
+/* Split what follows the pstr length byte into the fields of a handshake */
+fn parse_handshake(pstrlen: usize, handshakebuf: &[u8]) -> Handshake {
     let mut infohash = Vec::new();
     let mut peerid = Vec::new();
     let mut pstr = Vec::new();
+    let mut reserved = [0; 8];
     for (i, x) in handshakebuf.iter().enumerate() {
-        if i < pstrlen as usize {
+        if i < pstrlen {
             pstr.push(x.to_owned());
         }
-        if i >= (pstrlen as usize + 8) && i < (pstrlen as usize + 8 + 20) {
+        if i >= pstrlen && i < (pstrlen + 8) {
+            reserved[i - pstrlen] = x.to_owned();
+        }
+        if i >= (pstrlen + 8) && i < (pstrlen + 8 + 20) {
             infohash.push(x.to_owned());
         }
-        if i >= (pstrlen as usize + 8 + 20) {
+        if i >= (pstrlen + 8 + 20) {
             peerid.push(x.to_owned());
         }
     }
-    let h = Handshake {
-        pstr: pstr,
+    Handshake {
+        pstr,
+        reserved,
         info_hash: infohash,
         peer_id: peerid,
-    };
-    Ok(h)
+    }
 }
 
 #[cfg(test)]
@@ -97,6 +134,7 @@ mod tests {
         let h = new_handshake_with_input(input_info_hash.to_vec(), input_peer_id.to_vec());
         let expected = Handshake {
             pstr: String::from("BitTorrent protocol").into_bytes(),
+            reserved: [0; 8],
             info_hash: input_info_hash.to_vec(),
             peer_id: input_peer_id.to_vec(),
         };
@@ -114,6 +152,7 @@ mod tests {
         let h = new_handshake_with_input(input_info_hash.to_vec(), input_peer_id.to_vec());
         let expected = Handshake {
             pstr: String::from("BitTorrent protocol").into_bytes(),
+            reserved: [0; 8],
             info_hash: input_info_hash.to_vec(),
             peer_id: input_peer_id.to_vec(),
         };
@@ -131,6 +170,7 @@ mod tests {
         let h = new_handshake_with_input(input_info_hash.to_vec(), input_peer_id.to_vec());
         let expected = Handshake {
             pstr: String::from("BitTorrent protocol").into_bytes(),
+            reserved: [0; 8],
             info_hash: input_info_hash.to_vec(),
             peer_id: input_peer_id.to_vec(),
         };
@@ -166,6 +206,7 @@ mod tests {
         let input_pstr = String::from("BitTorrent protocol, but cooler?");
         let input = Handshake {
             pstr: input_pstr.into_bytes(),
+            reserved: [0; 8],
             info_hash: input_info_hash.to_vec(),
             peer_id: input_peer_id.to_vec(),
         };
@@ -178,6 +219,28 @@ mod tests {
         assert_eq!(serialize_handshake(&input), output);
     }
 
+    #[test]
+    fn test_serialize_extension_bit() {
+        let mut input = new_handshake_with_input(vec![0; 20], vec![1; 20]);
+        input.set_reserved_bit(EXTENSION_PROTOCOL_BIT);
+        let output = serialize_handshake(&input);
+        assert_eq!(&output[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0]);
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let mut input = new_handshake_with_input(vec![0; 20], vec![1; 20]);
+        assert!(!input.has_reserved_bit(EXTENSION_PROTOCOL_BIT));
+        input.set_reserved_bit(DHT_BIT);
+        input.set_reserved_bit(FAST_EXTENSION_BIT);
+        input.set_reserved_bit(63);
+        assert_eq!(input.reserved, [0x80, 0, 0, 0, 0, 0, 0, 0x05]);
+        assert!(input.has_reserved_bit(DHT_BIT));
+        assert!(input.has_reserved_bit(FAST_EXTENSION_BIT));
+        assert!(!input.has_reserved_bit(1));
+        assert!(!input.has_reserved_bit(EXTENSION_PROTOCOL_BIT));
+    }
+
     #[test]
     fn test_read_default_pstr() {
         let output_info_hash: [u8; 20] = [
@@ -189,6 +252,7 @@ mod tests {
         let output_pstr = String::from("BitTorrent protocol");
         let output = Handshake {
             pstr: output_pstr.into_bytes(),
+            reserved: [0; 8],
             info_hash: output_info_hash.to_vec(),
             peer_id: output_peer_id.to_vec(),
         };
@@ -220,7 +284,7 @@ mod tests {
                         190, 76, 80, 188, 90, 16, 44, 247, 23, 128, 49, 0, 116, 1, 2, 3, 4, 5, 6,
                         7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
                     ];
-                    stream.write(&input).unwrap();
+                    stream.write_all(&input).unwrap();
                 }
                 Err(e) => {
                     println!("Error: {}", e);
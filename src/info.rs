@@ -0,0 +1,182 @@
+use crate::magnet::encode_base32;
+use crate::torrentfile::TorrentFile;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use time::OffsetDateTime;
+
+/* Sizes in bytes with a binary unit for readability, e.g. "1.50 MiB (1572864 bytes)" */
+fn format_size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {} ({} bytes)", size, units[unit], bytes)
+}
+
+/* Every tracker url, tier by tier, falling back to announce when there is no announce-list */
+fn trackers(torrent_file: &TorrentFile) -> Vec<Vec<String>> {
+    if !torrent_file.AnnounceList.is_empty() {
+        torrent_file.AnnounceList.to_vec()
+    } else if !torrent_file.Announce.is_empty() {
+        vec![vec![torrent_file.Announce.to_owned()]]
+    } else {
+        vec![]
+    }
+}
+
+/* Human readable summary of a torrent's metadata */
+pub fn describe(torrent_file: &TorrentFile) -> String {
+    let mut out = String::new();
+    let t = torrent_file;
+    writeln!(out, "Name:          {}", t.Name).unwrap();
+    writeln!(out, "Info hash:     {}", hex::encode(&t.InfoHash)).unwrap();
+    writeln!(out, "Base32 hash:   {}", encode_base32(&t.InfoHash)).unwrap();
+    writeln!(out, "Total size:    {}", format_size(t.Length)).unwrap();
+    writeln!(
+        out,
+        "Pieces:        {} x {}",
+        t.PieceHashes.len(),
+        format_size(t.PieceLength as u64)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "Private:       {}",
+        if t.Private { "yes" } else { "no" }
+    )
+    .unwrap();
+    if let Some(date) = t.CreationDate {
+        let date = OffsetDateTime::from_unix_timestamp(date).format("%Y-%m-%d %H:%M:%S UTC");
+        writeln!(out, "Created:       {}", date).unwrap();
+    }
+    if let Some(created_by) = &t.CreatedBy {
+        writeln!(out, "Created by:    {}", created_by).unwrap();
+    }
+    if let Some(comment) = &t.Comment {
+        writeln!(out, "Comment:       {}", comment).unwrap();
+    }
+    let tiers = trackers(t);
+    if !tiers.is_empty() {
+        writeln!(out, "Trackers:").unwrap();
+        for (tier, urls) in tiers.iter().enumerate() {
+            for url in urls {
+                writeln!(out, "  tier {}: {}", tier + 1, url).unwrap();
+            }
+        }
+    }
+    if !t.WebSeeds.is_empty() {
+        writeln!(out, "Web seeds:").unwrap();
+        for url in &t.WebSeeds {
+            writeln!(out, "  {}", url).unwrap();
+        }
+    }
+    /* Numbered for --select and --file-priority */
+    writeln!(out, "Files:").unwrap();
+    if t.Files.is_empty() {
+        writeln!(out, "  {:>4}  {:>14}  {}", 0, t.Length, t.Name).unwrap();
+    }
+    for (index, f) in t.Files.iter().enumerate() {
+        writeln!(
+            out,
+            "  {:>4}  {:>14}  {}/{}",
+            index,
+            f.Length,
+            t.Name,
+            f.Path.join("/")
+        )
+        .unwrap();
+    }
+    out
+}
+
+/* The serialized TorrentFile with the hashes in readable form instead of byte arrays */
+pub fn to_json(torrent_file: &TorrentFile) -> Value {
+    let mut value = serde_json::to_value(torrent_file).unwrap();
+    let object = value.as_object_mut().unwrap();
+    object.remove("PieceHashes");
+    object.insert(
+        "InfoHash".to_string(),
+        json!(hex::encode(&torrent_file.InfoHash)),
+    );
+    object.insert(
+        "InfoHashBase32".to_string(),
+        json!(encode_base32(&torrent_file.InfoHash)),
+    );
+    object.insert(
+        "PieceCount".to_string(),
+        json!(torrent_file.PieceHashes.len()),
+    );
+    object.insert("Trackers".to_string(), json!(trackers(torrent_file)));
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::FileEntry;
+
+    fn test_torrent() -> TorrentFile {
+        TorrentFile {
+            Announce: "http://a.example/announce".to_string(),
+            InfoHash: (0..20).collect(),
+            PieceHashes: vec![vec![0; 20]; 3],
+            PieceLength: 262144,
+            Length: 700000,
+            Name: "album".to_string(),
+            Files: vec![
+                FileEntry {
+                    Path: vec!["a.flac".to_string()],
+                    Length: 600000,
+                },
+                FileEntry {
+                    Path: vec!["scans".to_string(), "cover.jpg".to_string()],
+                    Length: 100000,
+                },
+            ],
+            WebSeeds: vec!["http://seed.example/".to_string()],
+            Private: true,
+            CreationDate: Some(1600000000),
+            Comment: Some("test album".to_string()),
+            ..TorrentFile::default()
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let text = describe(&test_torrent());
+        assert!(text.contains("Info hash:     000102030405060708090a0b0c0d0e0f10111213\n"));
+        assert!(text.contains("Base32 hash:   AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT\n"));
+        assert!(text.contains("Total size:    683.59 KiB (700000 bytes)\n"));
+        assert!(text.contains("Pieces:        3 x 256.00 KiB (262144 bytes)\n"));
+        assert!(text.contains("Private:       yes\n"));
+        assert!(text.contains("Created:       2020-09-13 12:26:40 UTC\n"));
+        assert!(text.contains("Comment:       test album\n"));
+        assert!(text.contains("  tier 1: http://a.example/announce\n"));
+        assert!(text.contains("  http://seed.example/\n"));
+        assert!(text.contains("     1          100000  album/scans/cover.jpg\n"));
+        assert!(!text.contains("Created by"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let value = to_json(&test_torrent());
+        assert_eq!(value["Name"], "album");
+        assert_eq!(
+            value["InfoHash"],
+            "000102030405060708090a0b0c0d0e0f10111213"
+        );
+        assert_eq!(value["InfoHashBase32"], "AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT");
+        assert_eq!(value["PieceCount"], 3);
+        assert_eq!(value["Private"], true);
+        assert_eq!(value["Files"][1]["Path"], json!(["scans", "cover.jpg"]));
+        assert_eq!(value["Trackers"], json!([["http://a.example/announce"]]));
+        assert!(value.get("PieceHashes").is_none());
+        assert!(value.get("TrackerIds").is_none());
+    }
+}
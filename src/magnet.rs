@@ -0,0 +1,251 @@
+extern crate hex;
+use crate::metadata::fetch_metadata;
+use crate::peers::Peer;
+use crate::priority::parse_file_list;
+use crate::torrentfile::*;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use url::Url;
+
+/* Parsed magnet:?xt=urn:btih:... link */
+#[derive(Debug, PartialEq)]
+pub struct Magnet {
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) name: String,
+    pub(crate) trackers: Vec<String>,
+    pub(crate) peers: Vec<Peer>,
+    /* Files to download from so= (BEP 53), None for all of them */
+    pub(crate) select_only: Option<Vec<usize>>,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid magnet link: {}", reason),
+    )
+}
+
+/* Encode RFC 4648 base32 without padding, the inverse of decode_base32 */
+pub(crate) fn encode_base32(input: &[u8]) -> String {
+    let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in input {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(alphabet[(buffer >> bits) as usize & 31] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(alphabet[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+/* Decode RFC 4648 base32 without padding, as used by 32 character btih hashes */
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            b'A'..=b'Z' => c.to_ascii_uppercase() - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn parse_btih(xt: &str) -> Option<Vec<u8>> {
+    let hash = xt.strip_prefix("urn:btih:")?;
+    match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    }
+}
+
+/* Parse a magnet URI, keeping its info-hash, display name, trackers and direct peers */
+pub fn parse_magnet(uri: &str) -> Result<Magnet, Error> {
+    let url = match Url::parse(uri) {
+        Ok(url) => url,
+        Err(e) => return Err(invalid(&e.to_string())),
+    };
+    if url.scheme() != "magnet" {
+        return Err(invalid("expected magnet scheme"));
+    }
+    let mut magnet = Magnet {
+        info_hash: vec![],
+        name: String::new(),
+        trackers: vec![],
+        peers: vec![],
+        select_only: None,
+    };
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "xt" => {
+                if let Some(info_hash) = parse_btih(&value) {
+                    magnet.info_hash = info_hash;
+                }
+            }
+            "dn" => magnet.name = value.into_owned(),
+            "tr" => magnet.trackers.push(value.into_owned()),
+            "x.pe" => match value.parse::<SocketAddr>() {
+                Ok(addr) => magnet.peers.push(Peer { addr }),
+                Err(_) => debug!("Ignoring unsupported peer address {}", value),
+            },
+            "so" => match parse_file_list(&value) {
+                Some(list) => magnet.select_only.get_or_insert_with(Vec::new).extend(list),
+                None => debug!("Ignoring invalid file selection {}", value),
+            },
+            _ => {}
+        }
+    }
+    if magnet.info_hash.len() != 20 {
+        return Err(invalid("missing urn:btih info-hash"));
+    }
+    Ok(magnet)
+}
+
+impl Magnet {
+    /* Each tr parameter is announced to as its own tier */
+    fn announce_list(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|t| vec![t.to_owned()]).collect()
+    }
+
+    /* Direct peers from the link followed by everything its trackers return */
+    fn find_peers(&self, peer_id: &[u8], port: u16) -> Vec<Peer> {
+        let mut peers = self.peers.to_vec();
+        /* left is unknown until the metadata has been fetched */
+        let mut stub = TorrentFile {
+            Announce: self.trackers.first().cloned().unwrap_or_default(),
+            AnnounceList: self.announce_list(),
+            InfoHash: self.info_hash.to_vec(),
+            PieceHashes: vec![],
+            PieceLength: 0,
+            Length: 0,
+            Name: self.name.to_owned(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
+        };
+        match stub.request_peers(peer_id.to_vec(), port) {
+            Ok(found) => {
+                for peer in found {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            Err(e) => info!("No tracker responded: {}", e),
+        }
+        peers
+    }
+
+    /* Fetch the info dictionary from the swarm and build a TorrentFile from it. The output path
+    depends on the metadata, so it is only worked out once that has arrived. */
+    pub fn download_to(
+        &self,
+        destination: &Destination,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let peerid = generate_peer_id();
+        let peers = self.find_peers(&peerid, options.port);
+        info!("Fetching metadata from {} peers", peers.len());
+        let info = fetch_metadata(&peers, &self.info_hash, &peerid)?;
+        let announce = self.trackers.first().cloned().unwrap_or_default();
+        let mut torrent_file = from_info_bytes(announce, &info)?;
+        torrent_file.AnnounceList = self.announce_list();
+        let path = destination.path_for(&torrent_file);
+        /* A selection on the command line replaces the one in the link */
+        let mut options = options.clone();
+        if options.files.only.is_none() {
+            options.files.only = self.select_only.clone();
+        }
+        torrent_file.download_from_peers(path, peerid, peers, &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_parse_magnet_hex() {
+        let magnet = parse_magnet("magnet:?xt=urn:btih:dee86a7fa6f286a9d74c362014616a0ff5e4843d&dn=archlinux-2019.12.01-x86_64.iso&tr=http%3A%2F%2Ftracker.archlinux.org%3A6969%2Fannounce&tr=udp%3A%2F%2Ftracker.example.org%3A1337&x.pe=127.0.0.1:6881&x.pe=[::1]:51413").unwrap();
+        assert_eq!(
+            magnet,
+            Magnet {
+                info_hash: vec![
+                    222, 232, 106, 127, 166, 242, 134, 169, 215, 76, 54, 32, 20, 97, 106, 15, 245,
+                    228, 132, 61
+                ],
+                name: "archlinux-2019.12.01-x86_64.iso".to_string(),
+                trackers: vec![
+                    "http://tracker.archlinux.org:6969/announce".to_string(),
+                    "udp://tracker.example.org:1337".to_string(),
+                ],
+                peers: vec![
+                    Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6881),
+                    Peer::new(Ipv6Addr::LOCALHOST, 51413),
+                ],
+                select_only: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_magnet_base32() {
+        let magnet = parse_magnet("magnet:?xt=urn:btih:33UGU75G6KDKTV2MGYQBIYLKB726JBB5").unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "dee86a7fa6f286a9d74c362014616a0ff5e4843d"
+        );
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_magnet_select_only() {
+        let magnet =
+            parse_magnet("magnet:?xt=urn:btih:33UGU75G6KDKTV2MGYQBIYLKB726JBB5&so=0,2,4-6&so=9")
+                .unwrap();
+        assert_eq!(magnet.select_only, Some(vec![0, 2, 4, 5, 6, 9]));
+        let magnet =
+            parse_magnet("magnet:?xt=urn:btih:33UGU75G6KDKTV2MGYQBIYLKB726JBB5&so=x").unwrap();
+        assert_eq!(magnet.select_only, None);
+    }
+
+    #[test]
+    fn test_parse_magnet_invalid() {
+        assert!(parse_magnet("magnet:?dn=nohash").is_err());
+        assert!(parse_magnet("magnet:?xt=urn:btih:1234").is_err());
+        assert!(parse_magnet(
+            "http://example.org/?xt=urn:btih:dee86a7fa6f286a9d74c362014616a0ff5e4843d"
+        )
+        .is_err());
+        assert!(parse_magnet("not a uri").is_err());
+    }
+
+    #[test]
+    fn test_decode_base32() {
+        assert_eq!(decode_base32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert!(decode_base32("1NVALID").is_none());
+        assert_eq!(encode_base32(b"foobar"), "MZXW6YTBOI");
+        let hash: Vec<u8> = (0..20).collect();
+        assert_eq!(decode_base32(&encode_base32(&hash)).unwrap(), hash);
+    }
+}
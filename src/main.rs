@@ -1,20 +1,84 @@
+/* Declared first so its macros are visible in every other module */
+#[macro_use]
+mod log;
+mod bencode;
 mod bitfield;
+mod blocks;
+mod choker;
+mod cli;
 mod client;
+mod control;
+mod create;
+mod extension;
 mod handshake;
+mod info;
+mod magnet;
 mod message;
+mod metadata;
 mod p2p;
 mod peers;
+mod picker;
+mod priority;
+mod ratelimit;
+mod resume;
+mod runtime;
+mod seeder;
+mod storage;
 mod torrentfile;
 mod tracker;
+mod udp_tracker;
+mod verify;
+use cli::Command;
 use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 use torrentfile::*;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let in_path = &args[1];
-    let out_path = &args[2];
-    println!(
-        "{}",
+/* Hash-check existing data, exiting with 1 unless every piece is intact */
+fn run_verify(torrent: &str, path: &str) {
+    let torrent_file = match open(torrent.to_owned()) {
+        Ok(torrent_file) => torrent_file,
+        Err(e) => fail(&e.to_string()),
+    };
+    let report = verify::verify(&torrent_file, Path::new(path));
+    report.print();
+    if !report.is_complete() {
+        process::exit(1);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1);
+}
+
+/* Write a new .torrent for existing data */
+fn run_create(path: &str, torrent: &str, options: &create::CreateOptions) {
+    let result = create::create(Path::new(path), options)
+        .and_then(|encoded| fs::write(torrent, encoded))
+        .and_then(|_| open(torrent.to_owned()));
+    match result {
+        Ok(torrent_file) => println!(
+            "Created {} with info-hash {}",
+            torrent,
+            hex::encode(&torrent_file.InfoHash)
+        ),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+fn run_info(torrent: &str, json: bool) {
+    match open(torrent.to_owned()) {
+        Ok(torrent_file) if json => println!("{:#}", info::to_json(&torrent_file)),
+        Ok(torrent_file) => print!("{}", info::describe(&torrent_file)),
+        Err(e) => fail(&e.to_string()),
+    }
+}
+
+fn run_download(input: &str, destination: &Destination, options: &DownloadOptions) {
+    ratelimit::apply_global(&options.limits);
+    info!(
         r"                                                                                 
         ___  __  ____________   __________  ___  ___  _____  ________
         / _ \/ / / / __/_  __/__/_  __/ __ \/ _ \/ _ \/ __/ |/ /_  __/
@@ -23,6 +87,43 @@ fn main() {
    "
     );
 
-    let mut torrent_file = open(in_path.to_owned()).unwrap();
-    torrent_file.download_to_file(out_path.to_owned()).unwrap();
+    let result = if input.starts_with("magnet:") {
+        magnet::parse_magnet(input).and_then(|magnet| magnet.download_to(destination, options))
+    } else {
+        open(input.to_owned()).and_then(|mut torrent_file| {
+            let path = destination.path_for(&torrent_file);
+            torrent_file.download_to_file(path, options)
+        })
+    };
+    if let Err(e) = result {
+        fail(&e.to_string());
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    /* Usage errors exit with 2, failures of the command itself with 1 */
+    let cli = match cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(message) => {
+            eprintln!("Error: {}\nRun rust-torrent --help for usage", message);
+            process::exit(2);
+        }
+    };
+    log::set_verbosity(cli.verbosity);
+    match &cli.command {
+        Command::Download {
+            input,
+            destination,
+            options,
+        } => run_download(input, destination, options),
+        Command::Create {
+            path,
+            torrent,
+            options,
+        } => run_create(path, torrent, options),
+        Command::Info { torrent, json } => run_info(torrent, *json),
+        Command::Verify { torrent, path } => run_verify(torrent, path),
+        Command::Help => println!("{}", cli::USAGE),
+    }
 }
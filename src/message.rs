@@ -1,4 +1,5 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
 #[allow(unused_imports)]
 use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
 #[allow(unused_imports)]
@@ -6,9 +7,14 @@ use std::net::{TcpListener, TcpStream};
 #[allow(unused_imports)]
 use std::thread;
 use std::vec::Vec;
+use tokio_util::codec::{Decoder, Encoder};
 
 type MessageID = u8;
 
+/* Larger than any block or bitfield we expect, a peer announcing more is treated as broken
+rather than buffered */
+static MAX_MESSAGE_LENGTH: usize = 2 * 1024 * 1024;
+
 #[allow(dead_code)]
 pub static MESSAGE_CHOKE: MessageID = 0;
 pub static MESSAGE_UNCHOKE: MessageID = 1;
@@ -18,9 +24,9 @@ pub static MESSAGE_HAVE: MessageID = 4;
 pub static MESSAGE_BITFIELD: MessageID = 5;
 pub static MESSAGE_REQUEST: MessageID = 6;
 pub static MESSAGE_PIECE: MessageID = 7;
-#[allow(dead_code)]
 pub static MESSAGE_CANCEL: MessageID = 8;
 pub static MESSAGE_EMPTY: MessageID = 9;
+pub static MESSAGE_EXTENDED: MessageID = 20;
 
 pub struct Message {
     pub(crate) id: MessageID,
@@ -41,24 +47,53 @@ pub fn format_request(index: u32, begin: u32, length: u32) -> Message {
     payload.write_u32::<BigEndian>(index).unwrap();
     payload.write_u32::<BigEndian>(begin).unwrap();
     payload.write_u32::<BigEndian>(length).unwrap();
-    let msg = Message {
+    Message {
         id: MESSAGE_REQUEST,
-        payload: payload,
-    };
+        payload,
+    }
+}
+
+/* Withdraw a request, sent once the block arrived from another peer */
+pub fn format_cancel(index: u32, begin: u32, length: u32) -> Message {
+    let mut msg = format_request(index, begin, length);
+    msg.id = MESSAGE_CANCEL;
     msg
 }
 
 pub fn format_have(index: u32) -> Message {
     let mut payload: Vec<u8> = vec![];
     payload.write_u32::<BigEndian>(index).unwrap();
-    let msg = Message {
+    Message {
         id: MESSAGE_HAVE,
-        payload: payload,
-    };
-    msg
+        payload,
+    }
 }
 
-pub fn parse_piece(index: u32, buf: &mut Vec<u8>, msg: &Message) -> Result<u32> {
+pub fn format_piece(index: u32, begin: u32, block: &[u8]) -> Message {
+    let mut payload: Vec<u8> = Vec::with_capacity(8 + block.len());
+    payload.write_u32::<BigEndian>(index).unwrap();
+    payload.write_u32::<BigEndian>(begin).unwrap();
+    payload.extend_from_slice(block);
+    Message {
+        id: MESSAGE_PIECE,
+        payload,
+    }
+}
+
+/* Wrap an extension protocol payload, extended_id 0 is the extended handshake */
+pub fn format_extended(extended_id: u8, payload: &[u8]) -> Message {
+    let mut buf: Vec<u8> = vec![extended_id];
+    buf.extend_from_slice(payload);
+    Message {
+        id: MESSAGE_EXTENDED,
+        payload: buf,
+    }
+}
+
+/* Copies the block into a buffer holding the whole piece. Download workers share partial pieces
+through Blocks and use parse_block instead. */
+#[allow(dead_code)]
+pub fn parse_piece(index: u32, buf: &mut [u8], msg: &Message) -> Result<u32> {
     //Err(Error::new(ErrorKind::InvalidData, "Unexpected ID"))
 
     if msg.id != MESSAGE_PIECE {
@@ -89,10 +124,25 @@ pub fn parse_piece(index: u32, buf: &mut Vec<u8>, msg: &Message) -> Result<u32>
             "data too long for offset",
         ));
     }
-    for k in 0..data.len() {
-        buf[begin as usize + k] = data[k];
+    buf[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+    Ok(data.len() as u32)
+}
+
+/* The index, begin and data of a PIECE message, whatever piece it belongs to */
+pub(crate) fn parse_block(msg: &Message) -> Result<(u32, u32, &[u8])> {
+    if msg.id != MESSAGE_PIECE {
+        return Err(Error::new(ErrorKind::InvalidData, "Expected Piece"));
+    }
+    if msg.payload.len() < 8 {
+        return Err(Error::new(ErrorKind::InvalidData, "Payload too short"));
     }
-    return Ok(data.len() as u32);
+    let index = Cursor::new(&msg.payload[0..4])
+        .read_u32::<BigEndian>()
+        .unwrap();
+    let begin = Cursor::new(&msg.payload[4..8])
+        .read_u32::<BigEndian>()
+        .unwrap();
+    Ok((index, begin, &msg.payload[8..]))
 }
 
 pub(crate) fn parse_have(msg: &Message) -> Result<u32> {
@@ -109,9 +159,28 @@ pub(crate) fn parse_have(msg: &Message) -> Result<u32> {
     }
 }
 
+/* index, begin and length of a request (or cancel) message */
+pub(crate) fn parse_request(msg: &Message) -> Result<(u32, u32, u32)> {
+    if msg.id != MESSAGE_REQUEST && msg.id != MESSAGE_CANCEL {
+        return Err(Error::new(ErrorKind::InvalidData, "unexpected ID"));
+    }
+    if msg.payload.len() != 12 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Incorrect payload length, length must equal 12",
+        ));
+    }
+    let mut payload_read = Cursor::new(&msg.payload);
+    Ok((
+        payload_read.read_u32::<BigEndian>().unwrap(),
+        payload_read.read_u32::<BigEndian>().unwrap(),
+        payload_read.read_u32::<BigEndian>().unwrap(),
+    ))
+}
+
+/* Message::default() is a keep-alive, other ids are sent even when their payload is empty */
 pub(crate) fn serialize_message(msg: &Message) -> Vec<u8> {
-    let compare: Vec<u8> = vec![];
-    if msg.payload == compare {
+    if msg.id == MESSAGE_EMPTY && msg.payload.is_empty() {
         vec![0; 4]
     } else {
         let length: u32 = (msg.payload.len() + 1) as u32;
@@ -120,15 +189,56 @@ pub(crate) fn serialize_message(msg: &Message) -> Vec<u8> {
         let mut buf_ending: Vec<u8> = vec![0; length as usize];
         buf.append(&mut buf_ending);
         buf[4] = msg.id;
-        let mut counter = 5;
-        for i in &msg.payload {
-            buf[counter] = *i;
-            counter = counter + 1;
-        }
+        buf[5..].copy_from_slice(&msg.payload);
         buf
     }
 }
 
+/* Frames the length prefixed messages of a peer connection. A decoded message is taken off the
+buffer only once all of it has arrived, so nothing is lost when a read is given up halfway. */
+#[derive(Default)]
+pub(crate) struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = Cursor::new(&src[0..4]).read_u32::<BigEndian>().unwrap() as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(Error::new(ErrorKind::InvalidData, "message too long"));
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(4 + length);
+        let msg = if length == 0 {
+            Message::default()
+        } else {
+            Message {
+                id: frame[4],
+                payload: frame[5..].to_vec(),
+            }
+        };
+        Ok(Some(msg))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&serialize_message(&msg));
+        Ok(())
+    }
+}
+
+/* Blocking read used by the peers the tests stand up, connections use MessageCodec */
+#[cfg(test)]
 pub fn read_message(reader: &mut TcpStream) -> Result<Message> {
     let mut length_buffer = vec![0; 4];
     let mut reader_error = Error::new(ErrorKind::InvalidData, "unexpected ID");
@@ -138,7 +248,7 @@ pub fn read_message(reader: &mut TcpStream) -> Result<Message> {
         Ok(_s) => ((), ()),
         Err(_err) => return Err(reader_error), //LOOK HERE
     };
-    if reader_error_change == true {
+    if reader_error_change {
         Err(reader_error)
     } else {
         let mut length_buffer_to_cursor = Cursor::new(length_buffer);
@@ -151,7 +261,7 @@ pub fn read_message(reader: &mut TcpStream) -> Result<Message> {
                 Ok(_s) => ((), ()),
                 Err(_err) => (reader_error = _err, reader_error_change = true),
             };
-            if reader_error_change == true {
+            if reader_error_change {
                 Err(reader_error)
             } else {
                 let output_id = message_buffer[0];
@@ -181,7 +291,8 @@ fn name(msg: &Message) -> String {
             6 => String::from("Request"),
             7 => String::from("Piece"),
             8 => String::from("Cancel"),
-            _ => String::from(format!("Unknown#{}", msg.id)),
+            20 => String::from("Extended"),
+            _ => format!("Unknown#{}", msg.id),
         }
     }
 }
@@ -225,6 +336,14 @@ mod tests {
         //assert_eq!(expected.Payload, msg.Payload);
     }
 
+    #[test]
+    fn test_format_extended() {
+        let msg = format_extended(3, b"d8:msg_typei0e5:piecei0ee");
+        assert_eq!(msg.id, MESSAGE_EXTENDED);
+        assert_eq!(msg.payload[0], 3);
+        assert_eq!(&msg.payload[1..], b"d8:msg_typei0e5:piecei0ee");
+    }
+
     #[test]
     fn test_parse_piece_normal() {
         let input_index = 4;
@@ -372,15 +491,74 @@ mod tests {
 
     #[test]
     fn test_serialize_empty() {
-        let input_payload: Vec<u8> = vec![];
+        let output: Vec<u8> = vec![0, 0, 0, 0];
+        assert_eq!(serialize_message(&Message::default()), output);
+    }
+
+    #[test]
+    fn test_serialize_without_payload() {
         let input_message = Message {
-            id: 0,
-            payload: input_payload,
+            id: MESSAGE_UNCHOKE,
+            payload: vec![],
         };
-        let output: Vec<u8> = vec![0, 0, 0, 0];
+        let output: Vec<u8> = vec![0, 0, 0, 1, 1];
         assert_eq!(serialize_message(&input_message), output);
     }
 
+    #[test]
+    fn test_format_piece() {
+        let msg = format_piece(4, 567, b"abc");
+        assert_eq!(msg.id, MESSAGE_PIECE);
+        assert_eq!(
+            msg.payload,
+            vec![0, 0, 0, 4, 0, 0, 0x02, 0x37, b'a', b'b', b'c']
+        );
+        let mut buf = vec![0; 600];
+        assert_eq!(parse_piece(4, &mut buf, &msg).unwrap(), 3);
+        assert_eq!(&buf[567..570], b"abc");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let msg = format_request(4, 567, 16384);
+        assert_eq!(parse_request(&msg).unwrap(), (4, 567, 16384));
+        assert!(parse_request(&format_have(4)).is_err());
+    }
+
+    #[test]
+    fn test_codec_waits_for_whole_message() {
+        let mut codec = MessageCodec;
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&serialize_message(&format_have(7))[..6]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&[0, 0, 7, 0, 0, 0, 0]);
+        let have = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(parse_have(&have).unwrap(), 7);
+        let keep_alive = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(keep_alive.id, MESSAGE_EMPTY);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        let mut codec = MessageCodec;
+        let mut buf = BytesMut::new();
+        codec
+            .encode(format_piece(1, 16384, b"block"), &mut buf)
+            .unwrap();
+        codec.encode(Message::default(), &mut buf).unwrap();
+        let piece = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(parse_block(&piece).unwrap(), (1, 16384, &b"block"[..]));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().id, MESSAGE_EMPTY);
+    }
+
+    #[test]
+    fn test_codec_rejects_oversized_message() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0x7f, 0xff, 0xff, 0xff, 7]);
+        assert!(MessageCodec.decode(&mut buf).is_err());
+    }
+
     // #[test]
     // fn test_read_message_too_short() {
     //     thread::spawn(move || {
@@ -608,6 +786,17 @@ mod tests {
         assert_eq!(test_string, output_string);
     }
 
+    #[test]
+    fn test_string_extended() {
+        let test_message = Message {
+            id: MESSAGE_EXTENDED,
+            payload: vec![0, 1, 2],
+        };
+        let test_string = String::from("Extended [3]");
+        let output_string = string(&test_message);
+        assert_eq!(test_string, output_string);
+    }
+
     #[test]
     fn test_string_unknown() {
         let test_message = Message {
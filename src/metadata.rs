@@ -0,0 +1,422 @@
+extern crate serde_bencode;
+use crate::bencode;
+use crate::client::*;
+use crate::extension::*;
+use crate::peers::Peer;
+use crate::runtime;
+use crate::torrentfile::sha1_hash;
+use serde_bencode::{de, ser};
+use serde_derive::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static METADATA_PIECE_SIZE: usize = 16384;
+/* A peer that sends nothing for this long while we wait for metadata is given up on */
+static METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+/* Refuse to allocate for info dictionaries larger than this */
+static MAX_METADATA_SIZE: i64 = 16 * 1024 * 1024;
+
+static MSG_TYPE_REQUEST: i64 = 0;
+static MSG_TYPE_DATA: i64 = 1;
+static MSG_TYPE_REJECT: i64 = 2;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MetadataMessage {
+    msg_type: i64,
+    piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/* Split a ut_metadata payload into its bencoded header and any trailing piece data */
+fn parse_metadata_message(payload: &[u8]) -> Result<(MetadataMessage, &[u8]), Error> {
+    let header_end = bencode::value_end(payload, 0)?;
+    match de::from_bytes::<MetadataMessage>(&payload[..header_end]) {
+        Ok(header) => Ok((header, &payload[header_end..])),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+fn format_metadata_message(msg_type: i64, piece: i64, total_size: Option<i64>) -> Vec<u8> {
+    let msg = MetadataMessage {
+        msg_type,
+        piece,
+        total_size,
+    };
+    ser::to_bytes(&msg).unwrap()
+}
+
+/* ut_metadata extension (BEP 9). Fetches the info dictionary into result and, once the
+metadata is known, serves it to peers that request it. */
+pub struct MetadataHandler {
+    info_hash: Vec<u8>,
+    metadata: Vec<u8>,
+    received: Vec<bool>,
+    complete: bool,
+    result: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl MetadataHandler {
+    pub(crate) fn fetching(
+        info_hash: &[u8],
+        result: Arc<Mutex<Option<Vec<u8>>>>,
+    ) -> MetadataHandler {
+        MetadataHandler {
+            info_hash: info_hash.to_vec(),
+            metadata: vec![],
+            received: vec![],
+            complete: false,
+            result,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn serving(info: Vec<u8>) -> MetadataHandler {
+        MetadataHandler {
+            info_hash: sha1_hash(&info),
+            received: vec![true; info.len().div_ceil(METADATA_PIECE_SIZE)],
+            metadata: info,
+            complete: true,
+            result: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn store_piece(&mut self, piece: i64, data: &[u8]) -> Result<(), Error> {
+        let index = piece as usize;
+        if piece < 0 || index >= self.received.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unexpected metadata piece",
+            ));
+        }
+        let begin = index * METADATA_PIECE_SIZE;
+        let expected = METADATA_PIECE_SIZE.min(self.metadata.len() - begin);
+        if data.len() != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "metadata piece has the wrong length",
+            ));
+        }
+        self.metadata[begin..begin + expected].copy_from_slice(data);
+        self.received[index] = true;
+        if self.received.contains(&false) {
+            return Ok(());
+        }
+        if sha1_hash(&self.metadata) != self.info_hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "metadata does not match info-hash",
+            ));
+        }
+        self.complete = true;
+        *self.result.lock().unwrap() = Some(self.metadata.to_vec());
+        Ok(())
+    }
+}
+
+impl ExtensionHandler for MetadataHandler {
+    fn name(&self) -> &str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, hs: &mut ExtendedHandshake) {
+        if self.complete {
+            hs.metadata_size = Some(self.metadata.len() as i64);
+        }
+    }
+
+    /* Request every piece of the metadata as soon as the peer tells us its size */
+    fn on_handshake(&mut self, hs: &ExtendedHandshake) -> Result<Vec<Vec<u8>>, Error> {
+        if self.complete {
+            return Ok(vec![]);
+        }
+        let size = match hs.metadata_size {
+            Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size as usize,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "missing or invalid metadata_size",
+                ))
+            }
+        };
+        self.metadata = vec![0; size];
+        self.received = vec![false; size.div_ceil(METADATA_PIECE_SIZE)];
+        Ok((0..self.received.len())
+            .map(|piece| format_metadata_message(MSG_TYPE_REQUEST, piece as i64, None))
+            .collect())
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let (header, data) = parse_metadata_message(payload)?;
+        if header.msg_type == MSG_TYPE_REQUEST {
+            let index = header.piece as usize;
+            if !self.complete || header.piece < 0 || index >= self.received.len() {
+                return Ok(vec![format_metadata_message(
+                    MSG_TYPE_REJECT,
+                    header.piece,
+                    None,
+                )]);
+            }
+            let begin = index * METADATA_PIECE_SIZE;
+            let end = (begin + METADATA_PIECE_SIZE).min(self.metadata.len());
+            let mut reply = format_metadata_message(
+                MSG_TYPE_DATA,
+                header.piece,
+                Some(self.metadata.len() as i64),
+            );
+            reply.extend_from_slice(&self.metadata[begin..end]);
+            return Ok(vec![reply]);
+        }
+        if header.msg_type == MSG_TYPE_REJECT && !self.complete {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "peer rejected metadata request",
+            ));
+        }
+        if header.msg_type == MSG_TYPE_DATA && !self.complete {
+            self.store_piece(header.piece, data)?;
+        }
+        Ok(vec![])
+    }
+}
+
+async fn fetch_metadata_from_peer(
+    peer: &Peer,
+    info_hash: &[u8],
+    peer_id: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let result = Arc::new(Mutex::new(None));
+    let mut extensions = ExtensionRegistry::default();
+    extensions.register(Box::new(MetadataHandler::fetching(
+        info_hash,
+        result.clone(),
+    )));
+    let mut c = new_client_with_extensions(peer, peer_id, info_hash, extensions).await?;
+    if c.wait_for_extended_handshake()
+        .await?
+        .extension_id("ut_metadata")
+        .is_none()
+    {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "peer does not support ut_metadata",
+        ));
+    }
+    loop {
+        if let Some(metadata) = result.lock().unwrap().take() {
+            return Ok(metadata);
+        }
+        if c.poll(METADATA_TIMEOUT).await?.is_none() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+    }
+}
+
+/* Fetch the info dictionary of a torrent from the first peer able to provide it */
+pub(crate) fn fetch_metadata(
+    peers: &[Peer],
+    info_hash: &[u8],
+    peer_id: &[u8],
+) -> Result<Vec<u8>, Error> {
+    for peer in peers {
+        match runtime::block_on(fetch_metadata_from_peer(peer, info_hash, peer_id)) {
+            Ok(metadata) => return Ok(metadata),
+            Err(e) => debug!("Could not fetch metadata from {}: {}", peer.addr, e),
+        }
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        "no peer provided the torrent metadata",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::*;
+    use crate::message::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn test_metadata() -> Vec<u8> {
+        let mut info =
+            b"d6:lengthi100000e4:name8:file.bin12:piece lengthi16384e6:pieces140:".to_vec();
+        info.extend_from_slice(&[7; 140]);
+        let mut padding = b"7:comment20000:".to_vec();
+        padding.extend_from_slice(&[b'x'; 20000]);
+        info.extend_from_slice(&padding);
+        info.push(b'e');
+        info
+    }
+
+    /* Minimal seed that answers ut_metadata requests, optionally rejecting them */
+    fn mock_metadata_peer(listener: TcpListener, metadata: Vec<u8>, reject: bool) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let received = read_handshake(&mut stream).unwrap();
+        assert!(received.has_reserved_bit(EXTENSION_PROTOCOL_BIT));
+        let mut reply = new_handshake_with_input(received.info_hash.clone(), vec![9; 20]);
+        reply.set_reserved_bit(EXTENSION_PROTOCOL_BIT);
+        stream.write_all(&serialize_handshake(&reply)).unwrap();
+        let ours = parse_extended_handshake(&read_message(&mut stream).unwrap()).unwrap();
+        let local_id = ours.extension_id("ut_metadata").unwrap();
+
+        let bitfield = Message {
+            id: MESSAGE_BITFIELD,
+            payload: vec![0xff],
+        };
+        stream.write_all(&serialize_message(&bitfield)).unwrap();
+        let mut hs = ExtendedHandshake::default();
+        hs.m.insert("ut_metadata".to_string(), 3);
+        hs.metadata_size = Some(metadata.len() as i64);
+        stream
+            .write_all(&serialize_message(&format_extended_handshake(&hs)))
+            .unwrap();
+
+        loop {
+            let msg = match read_message(&mut stream) {
+                Ok(msg) => msg,
+                Err(_) => return,
+            };
+            if msg.id != MESSAGE_EXTENDED || msg.payload[0] != 3 {
+                continue;
+            }
+            let (req, _) = parse_metadata_message(&msg.payload[1..]).unwrap();
+            let piece = req.piece as usize;
+            let mut payload;
+            if reject {
+                payload = ser::to_bytes(&MetadataMessage {
+                    msg_type: MSG_TYPE_REJECT,
+                    piece: req.piece,
+                    total_size: None,
+                })
+                .unwrap();
+            } else {
+                payload = ser::to_bytes(&MetadataMessage {
+                    msg_type: MSG_TYPE_DATA,
+                    piece: req.piece,
+                    total_size: Some(metadata.len() as i64),
+                })
+                .unwrap();
+                let begin = piece * METADATA_PIECE_SIZE;
+                let end = (begin + METADATA_PIECE_SIZE).min(metadata.len());
+                payload.extend_from_slice(&metadata[begin..end]);
+            }
+            let reply = format_extended(local_id, &payload);
+            if stream.write_all(&serialize_message(&reply)).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn start_mock(metadata: Vec<u8>, reject: bool) -> Peer {
+        start_mock_on("127.0.0.1:0", metadata, reject)
+    }
+
+    fn start_mock_on(addr: &str, metadata: Vec<u8>, reject: bool) -> Peer {
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || mock_metadata_peer(listener, metadata, reject));
+        Peer { addr }
+    }
+
+    #[test]
+    fn test_parse_metadata_message_with_data() {
+        let payload = b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc";
+        let (header, data) = parse_metadata_message(payload).unwrap();
+        assert_eq!(
+            header,
+            MetadataMessage {
+                msg_type: 1,
+                piece: 0,
+                total_size: Some(3),
+            }
+        );
+        assert_eq!(data, b"abc");
+    }
+
+    #[test]
+    fn test_fetch_metadata() {
+        let metadata = test_metadata();
+        let info_hash = sha1_hash(&metadata);
+        let peer = start_mock(metadata.clone(), false);
+        let fetched = fetch_metadata(&[peer], &info_hash, &[1; 20]).unwrap();
+        assert_eq!(fetched, metadata);
+    }
+
+    #[test]
+    fn test_fetch_metadata_over_ipv6() {
+        let metadata = test_metadata();
+        let info_hash = sha1_hash(&metadata);
+        let peer = start_mock_on("[::1]:0", metadata.clone(), false);
+        assert!(peer.addr.is_ipv6());
+        let fetched = fetch_metadata(&[peer], &info_hash, &[1; 20]).unwrap();
+        assert_eq!(fetched, metadata);
+    }
+
+    #[test]
+    fn test_fetch_metadata_wrong_hash() {
+        let metadata = test_metadata();
+        let info_hash = vec![0; 20];
+        let peer = start_mock(metadata, false);
+        assert!(runtime::block_on(fetch_metadata_from_peer(&peer, &info_hash, &[1; 20])).is_err());
+    }
+
+    #[test]
+    fn test_fetch_metadata_rejected() {
+        let metadata = test_metadata();
+        let info_hash = sha1_hash(&metadata);
+        let peer = start_mock(metadata, true);
+        let err =
+            runtime::block_on(fetch_metadata_from_peer(&peer, &info_hash, &[1; 20])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_serving_handler() {
+        let metadata = test_metadata();
+        let mut handler = MetadataHandler::serving(metadata.clone());
+        let mut hs = ExtendedHandshake::default();
+        handler.extend_handshake(&mut hs);
+        assert_eq!(hs.metadata_size, Some(metadata.len() as i64));
+
+        let replies = handler
+            .on_message(&format_metadata_message(MSG_TYPE_REQUEST, 1, None))
+            .unwrap();
+        let (header, data) = parse_metadata_message(&replies[0]).unwrap();
+        assert_eq!(header.msg_type, MSG_TYPE_DATA);
+        assert_eq!(data, &metadata[METADATA_PIECE_SIZE..]);
+
+        let replies = handler
+            .on_message(&format_metadata_message(MSG_TYPE_REQUEST, 5, None))
+            .unwrap();
+        let (header, _) = parse_metadata_message(&replies[0]).unwrap();
+        assert_eq!(header.msg_type, MSG_TYPE_REJECT);
+    }
+
+    #[test]
+    fn test_fetching_handler_rejects_requests() {
+        let mut handler = MetadataHandler::fetching(&[0; 20], Arc::new(Mutex::new(None)));
+        let replies = handler
+            .on_message(&format_metadata_message(MSG_TYPE_REQUEST, 0, None))
+            .unwrap();
+        let (header, _) = parse_metadata_message(&replies[0]).unwrap();
+        assert_eq!(header.msg_type, MSG_TYPE_REJECT);
+    }
+
+    #[test]
+    fn test_fetching_handler_rejects_pieces_out_of_range() {
+        let mut handler = MetadataHandler::fetching(&[0; 20], Arc::new(Mutex::new(None)));
+        let hs = ExtendedHandshake {
+            metadata_size: Some(100),
+            ..ExtendedHandshake::default()
+        };
+        handler.on_handshake(&hs).unwrap();
+        for piece in &[-1, 1] {
+            let mut message = format_metadata_message(MSG_TYPE_DATA, *piece, Some(100));
+            message.extend_from_slice(&[0; 100]);
+            assert!(handler.on_message(&message).is_err());
+        }
+    }
+}
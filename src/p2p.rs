@@ -1,18 +1,31 @@
 #![allow(non_snake_case)]
 extern crate crypto;
 use crate::bitfield::*;
+use crate::blocks::{BlockWorker, Blocks, QueueDepth};
 use crate::client::*;
 use crate::message::*;
 use crate::peers::*;
-use crossbeam_channel::unbounded;
+use crate::picker::{PeerAvailability, PiecePicker};
+use crate::ratelimit::TorrentLimits;
+use crate::resume::Resume;
+use crate::runtime;
+use crate::seeder::Seeder;
+use crate::storage::Storage;
+use crate::torrentfile::FileEntry;
+use crossbeam_channel::{Receiver, TryRecvError};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use std::collections::VecDeque;
 use std::io::{Error, ErrorKind};
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio::time::delay_for;
 
-static MAX_BLOCK_SIZE: u32 = 16384;
-static MAX_BACK_LOG: u32 = 5;
+/* Outbound connections kept open at once unless configured otherwise */
+pub(crate) static DEFAULT_MAX_PEERS: usize = 50;
 
 #[derive(Clone)]
 pub struct Torrent {
@@ -21,8 +34,46 @@ pub struct Torrent {
     pub(crate) info_hash: Vec<u8>,
     pub(crate) piece_hashes: Vec<Vec<u8>>,
     pub(crate) piece_length: u32,
-    pub(crate) length: u32,
+    pub(crate) length: u64,
     pub(crate) name: String,
+    pub(crate) stats: Arc<TransferStats>,
+    /* Peers found by later tracker announces, workers are started for the ones we haven't seen */
+    pub(crate) peer_updates: Receiver<Vec<Peer>>,
+    /* Serves our verified pieces to the peers we download from, subject to the choker */
+    pub(crate) seeder: Option<Seeder>,
+    /* Outbound connections at once, the other peers wait until a connection closes */
+    pub(crate) max_peers: usize,
+    /* Shared with the seeder, every connection also gets limits of its own */
+    pub(crate) limits: TorrentLimits,
+}
+
+/* Byte counters reported to trackers, shared between the download workers and the tracker session */
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub(crate) uploaded: AtomicU64,
+    pub(crate) downloaded: AtomicU64,
+    pub(crate) left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> TransferStats {
+        TransferStats {
+            left: AtomicU64::new(left),
+            ..TransferStats::default()
+        }
+    }
+
+    pub(crate) fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
 }
 
 pub struct PieceWork {
@@ -36,95 +87,84 @@ pub struct PieceResult {
     pub(crate) buf: Vec<u8>,
 }
 
-pub struct PieceProgress {
-    pub(crate) index: u32,
-    pub(crate) buf: Vec<u8>,
-    pub(crate) downloaded: u32,
-    pub(crate) requested: u32,
-    pub(crate) backlog: u32,
+/* A contiguous part of a torrent byte range that falls inside a single file */
+#[derive(Debug, PartialEq)]
+pub struct FileSpan {
+    pub(crate) file_index: usize,
+    pub(crate) file_offset: u64,
+    pub(crate) torrent_offset: u64,
+    pub(crate) length: u64,
 }
 
-impl PieceProgress {
-    fn read_message(&mut self, c: &mut Client) -> Result<u32, Error> {
-        match c.read() {
-            Ok(msg) => match msg.id {
-                1 => {
-                    c.choked = false;
-                    Ok(1)
-                }
-                0 => {
-                    c.choked = true;
-                    Ok(1)
-                }
-                4 => match parse_have(&msg) {
-                    Ok(index) => {
-                        c.bitfield = set_piece(&c.bitfield, index as usize);
-                        drop(c);
-                        Ok(1)
-                    }
-                    Err(e) => Err(e),
-                },
-                7 => match parse_piece(self.index, &mut self.buf, &msg) {
-                    Ok(n) => {
-                        self.downloaded += n;
-                        self.backlog -= 1;
-                        drop(c);
-                        Ok(1)
-                    }
-                    Err(e) => Err(e),
-                },
-                _ => Ok(2),
-            },
-            Err(e) => Err(e),
-        }
+/* A download connection registered with the choker, unregistered when the connection goes away */
+struct Registration {
+    seeder: Seeder,
+    id: usize,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.seeder.choker.unregister(self.id);
     }
 }
 
-fn attempt_download_piece(c: &mut Client, pw: &PieceWork) -> Result<Vec<u8>, Error> {
-    let mut state = PieceProgress {
-        index: pw.index,
-        buf: vec![0; pw.length as usize],
-        downloaded: 0,
-        requested: 0,
-        backlog: 0,
-    };
-    c.conn
-        .set_write_timeout(Some(Duration::new(30, 0)))
-        .unwrap();
-    c.conn.set_read_timeout(Some(Duration::new(30, 0))).unwrap();
-    while state.downloaded < pw.length {
-        if !c.choked.clone() {
-            while state.backlog < MAX_BACK_LOG && state.requested < pw.length {
-                let mut block_size = MAX_BLOCK_SIZE;
-                if (pw.length - state.requested) < block_size {
-                    block_size = pw.length - state.requested;
-                }
-                match c.send_request(&pw.index, &state.requested, &block_size) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                }
-                state.backlog += 1;
-                state.requested += block_size;
-            }
-        }
-        match state.read_message(c) {
-            Ok(_) => {}
-            Err(e) => return Err(e),
+/* What the download workers of a torrent share */
+#[derive(Clone)]
+struct SharedWork {
+    picker: PiecePicker,
+    blocks: Blocks,
+    results: mpsc::UnboundedSender<PieceResult>,
+}
+
+/* How long a read waits before the worker looks at the picker and its pieces again */
+static POLL_INTERVAL: Duration = Duration::from_secs(1);
+/* A peer that sends no block of its pieces for this long is given up on */
+static PIECE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/* Act on a message from a peer we download from. Returns the number of block bytes it brought,
+whether or not they were still needed. */
+async fn handle_message(
+    c: &mut Client,
+    msg: &Message,
+    reg: Option<&Registration>,
+    blocks: &Blocks,
+    worker: &BlockWorker,
+    availability: &mut PeerAvailability,
+) -> Result<u64, Error> {
+    if msg.id == MESSAGE_UNCHOKE {
+        c.choked = false;
+    } else if msg.id == MESSAGE_CHOKE {
+        /* The peer drops our requests, they are sent again after the unchoke */
+        c.choked = true;
+        blocks.forget(worker.id);
+    } else if msg.id == MESSAGE_HAVE {
+        let index = parse_have(msg)?;
+        mark_piece(&mut c.bitfield, index as usize);
+        availability.add(index as usize);
+    } else if msg.id == MESSAGE_PIECE {
+        let (index, begin, data) = parse_block(msg)?;
+        if let Some(reg) = reg {
+            reg.seeder.choker.record_download(reg.id, data.len() as u64);
         }
+        blocks.received(worker.id, index, begin, data);
+        return Ok(data.len() as u64);
+    } else if let Some(reg) = reg {
+        reg.seeder.handle_message(c, reg.id, msg).await?;
     }
-    c.conn
-        .set_write_timeout(Some(Duration::new(1000, 0)))
-        .unwrap();
-    c.conn
-        .set_read_timeout(Some(Duration::new(1000, 0)))
-        .unwrap();
-    Ok(state.buf.clone())
+    Ok(0)
 }
 
-fn check_integrity(pw: &PieceWork, buf: Vec<u8>) -> Result<(), Error> {
+/* Remove the pieces whose blocks have all arrived */
+fn take_done(pieces: &mut Vec<PieceWork>, blocks: &Blocks) -> Vec<PieceWork> {
+    let (done, rest) = pieces.drain(..).partition(|pw| blocks.is_done(pw.index));
+    *pieces = rest;
+    done
+}
+
+pub(crate) fn check_integrity(pw: &PieceWork, buf: &[u8]) -> Result<(), Error> {
     let reader_error = Error::new(ErrorKind::InvalidData, "unexpected infohash");
     let mut h = Sha1::new();
-    h.input(&buf);
+    h.input(buf);
     let hash_output = hex::decode(h.result_str()).unwrap();
     if hash_output != pw.hash {
         Err(reader_error)
@@ -133,135 +173,678 @@ fn check_integrity(pw: &PieceWork, buf: Vec<u8>) -> Result<(), Error> {
     }
 }
 
-impl Torrent {
+/* Map the torrent byte range [begin, end) onto the files it covers, in file order */
+pub(crate) fn file_spans(files: &[FileEntry], begin: u64, end: u64) -> Vec<FileSpan> {
+    let mut spans = vec![];
+    let mut file_begin = 0;
+    for (file_index, f) in files.iter().enumerate() {
+        let file_end = file_begin + f.Length;
+        if f.Length > 0 && file_end > begin && file_begin < end {
+            let start = begin.max(file_begin);
+            let stop = end.min(file_end);
+            spans.push(FileSpan {
+                file_index,
+                file_offset: start - file_begin,
+                torrent_offset: start,
+                length: stop - start,
+            });
+        }
+        file_begin = file_end;
+    }
+    spans
+}
 
-    
-    fn start_download_work(
-        &mut self,
-        peer: Peer,
-        workQueue: (
-            crossbeam_channel::Sender<PieceWork>,
-            crossbeam_channel::Receiver<PieceWork>,
-        ),
-        results: crossbeam_channel::Sender<PieceResult>,
-    ) {
-        let mut c = match new_client(&peer, &self.peer_id, &self.info_hash) {
+impl Torrent {
+    async fn start_download_work(&self, peer: Peer, work: SharedWork) {
+        let mut c = match new_client(&peer, &self.peer_id, &self.info_hash).await {
             Ok(c) => c,
             Err(_) => {
-                println!("Could not handshake, disconnecting");
+                debug!("Could not handshake, disconnecting");
                 return;
             }
         };
+        c.set_limits(self.limits.connection());
 
-        c.send_unchoke().unwrap();
-        c.send_interested().unwrap();
-        println!("Completed handshake with {}\n", peer.ip);
-
-        loop {
-            let pw = match workQueue.1.recv() {
-                Ok(pw) => pw,
-                Err(_) => return,
-            };
+        /* Whether the peer gets unchoked is up to the choker */
+        let reg = self.seeder.as_ref().map(|seeder| Registration {
+            seeder: seeder.clone(),
+            id: seeder.choker.register(c.writer()),
+        });
+        if c.send_interested().is_err() {
+            return;
+        }
+        debug!("Completed handshake with {}\n", peer.addr);
 
-            let bf = &mut c.bitfield.clone();
+        let worker = work.blocks.register(c.writer());
+        let mut pieces = vec![];
+        let result = self
+            .download_pieces(&mut c, reg.as_ref(), &work, &worker, &mut pieces)
+            .await;
+        if let Err(e) = result {
+            debug!("Disconnecting from {}: {}", peer.addr, e);
+        }
+        self.release_pieces(&work, &worker, &mut pieces);
+    }
 
-            if !has_piece(bf, pw.index as usize) {
-                workQueue.0.send(pw).unwrap();
-                continue;
+    /* Keep enough requests in flight to fill the peer's queue, spread over as many pieces as that
+    takes, and hand in each piece as its last block arrives */
+    async fn download_pieces(
+        &self,
+        c: &mut Client,
+        reg: Option<&Registration>,
+        work: &SharedWork,
+        worker: &BlockWorker,
+        pieces: &mut Vec<PieceWork>,
+    ) -> Result<(), Error> {
+        let (picker, blocks) = (&work.picker, &work.blocks);
+        let mut availability = picker.add_peer(&c.bitfield);
+        let mut queue = QueueDepth::new();
+        let mut last_block = Instant::now();
+        while !picker.is_complete() {
+            for pw in take_done(pieces, blocks) {
+                if let Some(buf) = blocks.leave(worker.id, pw.index) {
+                    if self.finish_piece(work, &pw, buf) {
+                        c.send_have(pw.index)?;
+                    }
+                } else {
+                    /* Another worker finished the piece in end-game */
+                    picker.abort(pw.index);
+                }
             }
-
-            let buf = match attempt_download_piece(&mut c, &pw) {
-                Ok(buf) => buf,
-                Err(_) => {
-                    println!("Exiting");
-                    workQueue.0.send(pw).unwrap();
-                    return;
+            if c.choked {
+                /* Peers that will send the blocks can carry on with our pieces */
+                self.release_pieces(work, worker, pieces);
+            } else {
+                let reqq = c.peer_extensions.as_ref().and_then(|hs| hs.reqq);
+                let depth = queue.depth(reqq);
+                while blocks.outstanding(worker.id) < depth {
+                    let request = pieces.iter().find_map(|pw| {
+                        blocks
+                            .next_request(worker.id, pw.index)
+                            .map(|(begin, length)| (pw.index, begin, length))
+                    });
+                    if let Some((index, begin, length)) = request {
+                        c.send_request(&index, &begin, &length)?;
+                        continue;
+                    }
+                    /* Every block of our pieces is requested, so start on another one */
+                    let held: Vec<u32> = pieces.iter().map(|pw| pw.index).collect();
+                    let index = match picker.pick(&c.bitfield) {
+                        Some(index) => index,
+                        None => match picker.pick_endgame(&c.bitfield, &held) {
+                            Some(index) => index,
+                            None => break,
+                        },
+                    };
+                    let pw = PieceWork {
+                        index,
+                        hash: self.piece_hashes[index as usize].to_vec(),
+                        length: self.calculate_piece_size(index),
+                    };
+                    blocks.join(index, pw.length);
+                    pieces.push(pw);
                 }
-            };
-
-            let pindex1 = pw.index.clone();
-            let pindex2 = pw.index.clone();
-            let cbuf = buf.clone();
-            match check_integrity(&pw, buf) {
-                Err(_) => {
-                    println!("Piece {} failed integrity check", pindex1);
-                    workQueue.0.send(pw).unwrap();
-                    continue;
+            }
+            if let Some(msg) = c.poll(POLL_INTERVAL).await? {
+                let received =
+                    handle_message(c, &msg, reg, blocks, worker, &mut availability).await?;
+                if received > 0 {
+                    queue.record(received);
+                    last_block = Instant::now();
                 }
-                Ok(_) => {
-                    c.send_have(pindex1).unwrap();
-                    results
-                        .send(PieceResult {
-                            index: pindex2,
-                            buf: cbuf,
-                        })
-                        .unwrap();
+            }
+            if pieces.is_empty() {
+                /* Idle while there is nothing to fetch from this peer */
+                last_block = Instant::now();
+            } else if last_block.elapsed() > PIECE_TIMEOUT {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "peer stopped sending blocks",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /* Check a piece whose blocks are all in and pass it on for writing. Returns false if it failed
+    the check, another worker completed it first or the download stopped. */
+    fn finish_piece(&self, work: &SharedWork, pw: &PieceWork, buf: Vec<u8>) -> bool {
+        self.stats
+            .downloaded
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        if check_integrity(pw, &buf).is_err() {
+            debug!("Piece {} failed integrity check", pw.index);
+            work.picker.reject(pw.index);
+            return false;
+        }
+        /* A piece given back in end-game can be picked again before it is completed */
+        if !work.picker.complete(pw.index) {
+            return false;
+        }
+        /* Fails once the download loop has given up */
+        work.results
+            .send(PieceResult {
+                index: pw.index,
+                buf,
+            })
+            .is_ok()
+    }
+
+    /* Give up the worker's pieces. The blocks that arrived are kept for whoever picks them next. */
+    fn release_pieces(&self, work: &SharedWork, worker: &BlockWorker, pieces: &mut Vec<PieceWork>) {
+        for pw in pieces.drain(..) {
+            match work.blocks.leave(worker.id, pw.index) {
+                Some(buf) => {
+                    self.finish_piece(work, &pw, buf);
                 }
+                None => work.picker.abort(pw.index),
             }
         }
     }
 
-    fn calculate_bounds_for_piece(&self, index: u32) -> (u32, u32) {
-        let begin = index * self.piece_length;
-        let mut end = begin + self.piece_length;
+    pub(crate) fn calculate_bounds_for_piece(&self, index: u32) -> (u64, u64) {
+        let begin = index as u64 * self.piece_length as u64;
+        let mut end = begin + self.piece_length as u64;
         if end > self.length {
             end = self.length;
         }
         (begin, end)
     }
 
-    fn calculate_piece_size(&self, index: u32) -> u32 {
+    pub(crate) fn calculate_piece_size(&self, index: u32) -> u32 {
         let (begin, end) = self.calculate_bounds_for_piece(index);
-        end - begin
+        (end - begin) as u32
+    }
+
+    fn spawn_worker(&self, peer: Peer, work: &SharedWork, exits: &mpsc::UnboundedSender<Peer>) {
+        let self_copy = self.clone();
+        let workCopy = work.clone();
+        let exitsCopy = exits.clone();
+        runtime::spawn(async move {
+            self_copy.start_download_work(peer, workCopy).await;
+            let _ = exitsCopy.send(peer);
+        });
     }
 
-    /* initialize channels, fill work queue with work, create thread for each peer , put together data as work is done */
-    pub fn download(&mut self) -> Result<Vec<u8>, Error> {
-        println!("Starting download for {}", self.name);
-        let workQueue: (
-            crossbeam_channel::Sender<PieceWork>,
-            crossbeam_channel::Receiver<PieceWork>,
-        ) = unbounded();
-        let results: (
-            crossbeam_channel::Sender<PieceResult>,
-            crossbeam_channel::Receiver<PieceResult>,
-        ) = unbounded();
-        for index in 0..self.piece_hashes.len() {
-            let length = self.calculate_piece_size(index as u32);
-            let hash = self.piece_hashes[index].clone();
-            let work = PieceWork {
-                index: (index as u32),
-                hash,
-                length,
+    /* initialize channels, start a task for each peer, write each piece to storage as it is done.
+    Pieces resume already has are skipped. The picker decides the order and can be steered from
+    other threads while the download runs. */
+    pub fn download(
+        &mut self,
+        storage: &Storage,
+        resume: &mut Resume,
+        picker: &PiecePicker,
+    ) -> Result<(), Error> {
+        runtime::block_on(self.coordinate(storage, resume, picker))
+    }
+
+    /* Keep up to max_peers workers running and write the pieces they hand in. Gives up once every
+    peer is gone and no tracker is left to announce to, or when the user presses Ctrl-C. */
+    async fn coordinate(
+        &mut self,
+        storage: &Storage,
+        resume: &mut Resume,
+        picker: &PiecePicker,
+    ) -> Result<(), Error> {
+        info!("Starting download for {}", self.name);
+        let (results, mut finished) = mpsc::unbounded_channel();
+        let (exits, mut exited) = mpsc::unbounded_channel();
+        let work = SharedWork {
+            picker: picker.clone(),
+            blocks: Blocks::default(),
+            results,
+        };
+        let mut peers_in_box = self.peers.to_owned();
+        let mut waiting: VecDeque<Peer> = peers_in_box.iter().copied().collect();
+        let mut active = 0;
+        let mut announcing = true;
+        let mut interrupted = Box::pin(signal::ctrl_c());
+        while !picker.is_complete() {
+            loop {
+                match self.peer_updates.try_recv() {
+                    Ok(found) => {
+                        for peer in found {
+                            if !peers_in_box.contains(&peer) {
+                                peers_in_box.push(peer);
+                                waiting.push_back(peer);
+                            }
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        announcing = false;
+                        break;
+                    }
+                }
+            }
+            while active < self.max_peers {
+                match waiting.pop_front() {
+                    Some(peer) => {
+                        self.spawn_worker(peer, &work, &exits);
+                        active += 1;
+                    }
+                    None => break,
+                }
+            }
+            if active == 0 && !announcing {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "no peers left to download from",
+                ));
+            }
+            let res = tokio::select! {
+                res = finished.recv() => match res {
+                    Some(res) => res,
+                    None => continue,
+                },
+                peer = exited.recv() => {
+                    if let Some(peer) = peer {
+                        active -= 1;
+                        /* The peer is tried again if a later announce returns it */
+                        peers_in_box.retain(|known| *known != peer);
+                    }
+                    continue;
+                }
+                /* Priorities can change and trackers return peers without any piece arriving */
+                _ = delay_for(POLL_INTERVAL) => continue,
+                signaled = &mut interrupted => {
+                    signaled?;
+                    resume.save(storage)?;
+                    return Err(Error::new(ErrorKind::Interrupted, "download interrupted"));
+                }
             };
-            workQueue.0.send(work).unwrap();
-        }
-        let peers_in_box = self.peers.to_owned();
-        for peer in peers_in_box {
-            let mut self_copy = self.clone();
-            let workQueueCopy = (workQueue.0.clone(), workQueue.1.clone());
-            let resultsCopy = results.0.clone();
-            thread::spawn(move || {
-                self_copy.start_download_work(peer.clone(), workQueueCopy, resultsCopy);
-            });
-        }
-        let mut buffer: Vec<u8> = vec![0; self.length as usize];
-        let mut done_pieces = 0;
-        let num_of_hashes = self.piece_hashes.len();
-        while done_pieces < num_of_hashes {
-            let res = results.1.recv().unwrap();
             let (begin, _) = self.calculate_bounds_for_piece(res.index);
-            for k in 0..res.buf.len() {
-                buffer[begin as usize + k] = res.buf[k];
+            storage.write(begin, &res.buf)?;
+            resume.add_piece(res.index as usize, storage)?;
+            if let Some(seeder) = &self.seeder {
+                seeder.add_piece(res.index);
             }
-            done_pieces = done_pieces + 1;
-            let percent = ((done_pieces as f64) / (self.piece_hashes.len() as f64)) * (100 as f64);
-            let num_of_workers = self.peers.len();
-            println!(
+            self.stats
+                .left
+                .fetch_sub(res.buf.len() as u64, Ordering::Relaxed);
+            let (done_pieces, wanted_pieces) = picker.progress();
+            let percent = ((done_pieces as f64) / (wanted_pieces as f64)) * 100.0;
+            info!(
                 "{:.2}% downloaded piece {} from {} peers\n",
-                percent, res.index, num_of_workers
+                percent, res.index, active
             );
         }
-        Ok(buffer)
+        resume.save(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::*;
+    use crate::ratelimit::{LimitOptions, Profile};
+    use crate::resume::Resume;
+    use crate::torrentfile::{sha1_hash, TorrentFile};
+    use crossbeam_channel::{never, unbounded};
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Write;
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    fn test_files() -> Vec<FileEntry> {
+        vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["empty".to_string()],
+                Length: 0,
+            },
+            FileEntry {
+                Path: vec!["sub".to_string(), "b.txt".to_string()],
+                Length: 7,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_file_spans_across_boundary() {
+        let spans = file_spans(&test_files(), 4, 8);
+        assert_eq!(
+            spans,
+            vec![
+                FileSpan {
+                    file_index: 0,
+                    file_offset: 4,
+                    torrent_offset: 4,
+                    length: 1,
+                },
+                FileSpan {
+                    file_index: 2,
+                    file_offset: 0,
+                    torrent_offset: 5,
+                    length: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_spans_inside_one_file() {
+        let spans = file_spans(&test_files(), 6, 12);
+        assert_eq!(
+            spans,
+            vec![FileSpan {
+                file_index: 2,
+                file_offset: 1,
+                torrent_offset: 6,
+                length: 6,
+            }]
+        );
+    }
+
+    fn torrent_with_length(piece_length: u32, length: u64) -> Torrent {
+        let num_pieces = length.div_ceil(piece_length as u64) as usize;
+        Torrent {
+            peers: vec![],
+            peer_id: vec![0; 20],
+            info_hash: vec![0; 20],
+            piece_hashes: vec![vec![0; 20]; num_pieces],
+            piece_length,
+            length,
+            name: "large.img".to_string(),
+            stats: Arc::new(TransferStats::new(length)),
+            peer_updates: never(),
+            seeder: None,
+            max_peers: DEFAULT_MAX_PEERS,
+            limits: TorrentLimits::default(),
+        }
+    }
+
+    #[test]
+    fn test_calculate_bounds_for_piece_at_4gib() {
+        let four_gib: u64 = 4 * 1024 * 1024 * 1024;
+        let torrent = torrent_with_length(1 << 20, four_gib + 1000);
+        assert_eq!(torrent.piece_hashes.len(), 4097);
+        assert_eq!(
+            torrent.calculate_bounds_for_piece(4095),
+            (four_gib - (1 << 20), four_gib)
+        );
+        assert_eq!(
+            torrent.calculate_bounds_for_piece(4096),
+            (four_gib, four_gib + 1000)
+        );
+        assert_eq!(torrent.calculate_piece_size(4095), 1 << 20);
+        assert_eq!(torrent.calculate_piece_size(4096), 1000);
+    }
+
+    #[test]
+    fn test_calculate_bounds_for_piece_past_32_bits() {
+        let length: u64 = 40 * 1024 * 1024 * 1024;
+        let torrent = torrent_with_length(4 << 20, length);
+        let last = torrent.piece_hashes.len() as u32 - 1;
+        assert_eq!(
+            torrent.calculate_bounds_for_piece(last),
+            (length - (4 << 20), length)
+        );
+    }
+
+    #[test]
+    fn test_file_spans_at_4gib() {
+        let four_gib: u64 = 4 * 1024 * 1024 * 1024;
+        let files = vec![
+            FileEntry {
+                Path: vec!["disk.img".to_string()],
+                Length: four_gib + 10,
+            },
+            FileEntry {
+                Path: vec!["disk.sha256".to_string()],
+                Length: 64,
+            },
+        ];
+        let spans = file_spans(&files, four_gib, four_gib + 20);
+        assert_eq!(
+            spans,
+            vec![
+                FileSpan {
+                    file_index: 0,
+                    file_offset: four_gib,
+                    torrent_offset: four_gib,
+                    length: 10,
+                },
+                FileSpan {
+                    file_index: 1,
+                    file_offset: 0,
+                    torrent_offset: four_gib + 10,
+                    length: 10,
+                },
+            ]
+        );
+    }
+
+    enum Behaviour {
+        Answer,
+        /* Takes requests but never answers them */
+        Stall,
+        /* Answers this many requests and then closes the connection */
+        Drop(usize),
+    }
+
+    /* A local peer that has every piece and unchokes us straight away. It passes on every message it
+    gets and answers requests from data as its behaviour says. */
+    fn mock_peer(
+        data: Vec<u8>,
+        piece_length: u32,
+        behaviour: Behaviour,
+    ) -> (Peer, Receiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (messages, received) = unbounded();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let hs = read_handshake(&mut conn).unwrap();
+            let reply = new_handshake_with_input(hs.info_hash, vec![9; 20]);
+            conn.write_all(&serialize_handshake(&reply)).unwrap();
+            let num_pieces = (data.len() as u32).div_ceil(piece_length) as usize;
+            let bitfield = Message {
+                id: MESSAGE_BITFIELD,
+                payload: vec![0xff; num_pieces.div_ceil(8)],
+            };
+            let unchoke = Message {
+                id: MESSAGE_UNCHOKE,
+                payload: vec![],
+            };
+            for msg in [bitfield, unchoke] {
+                conn.write_all(&serialize_message(&msg)).unwrap();
+            }
+            let mut answered = 0;
+            while let Ok(msg) = read_message(&mut conn) {
+                let request = if msg.id == MESSAGE_REQUEST {
+                    Some(parse_request(&msg).unwrap())
+                } else {
+                    None
+                };
+                /* Passed on first so the test has seen a request by the time its block arrives */
+                let _ = messages.send(msg);
+                let answer = match behaviour {
+                    Behaviour::Answer => true,
+                    Behaviour::Stall => false,
+                    Behaviour::Drop(n) => answered < n,
+                };
+                if let (true, Some((index, begin, length))) = (answer, request) {
+                    answered += 1;
+                    let offset = (index * piece_length + begin) as usize;
+                    let piece = format_piece(index, begin, &data[offset..offset + length as usize]);
+                    if conn.write_all(&serialize_message(&piece)).is_err() {
+                        break;
+                    }
+                }
+                if let Behaviour::Drop(n) = behaviour {
+                    if answered == n {
+                        /* Closed without a reset so the blocks already sent are still read */
+                        let _ = conn.shutdown(Shutdown::Write);
+                        while read_message(&mut conn).is_ok() {}
+                        break;
+                    }
+                }
+            }
+        });
+        (Peer { addr }, received)
+    }
+
+    /* A torrent of data in one piece, downloaded to a temporary file. Returns the paths of the
+    file and its resume data. */
+    fn single_piece_torrent(
+        data: &[u8],
+        peers: Vec<Peer>,
+        peer_updates: Receiver<Vec<Peer>>,
+    ) -> (Torrent, Storage, Resume, [std::path::PathBuf; 2]) {
+        let out = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let length = data.len() as u64;
+        let torrent_file = TorrentFile {
+            InfoHash: vec![7; 20],
+            PieceHashes: vec![sha1_hash(data)],
+            PieceLength: length as u32,
+            Length: length,
+            Name: "file.bin".to_string(),
+            ..TorrentFile::default()
+        };
+        let storage = Storage::new(&out, "file.bin", &[], length);
+        storage.allocate().unwrap();
+        let resume_path = Resume::path_for(&out, &torrent_file);
+        let resume = Resume::load_or_recheck(resume_path.clone(), &torrent_file, &storage);
+        let torrent = Torrent {
+            peers,
+            peer_id: vec![3; 20],
+            info_hash: vec![7; 20],
+            piece_hashes: torrent_file.PieceHashes.to_vec(),
+            piece_length: length as u32,
+            length,
+            name: "file.bin".to_string(),
+            stats: Arc::new(TransferStats::new(length)),
+            peer_updates,
+            seeder: None,
+            max_peers: 2,
+            limits: TorrentLimits::default(),
+        };
+        (torrent, storage, resume, [out, resume_path])
+    }
+
+    #[test]
+    fn test_endgame_cancels_requests_at_slow_peer() {
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let (slow, slow_messages) = mock_peer(data.clone(), 40000, Behaviour::Stall);
+        let (fast, _fast_messages) = mock_peer(data.clone(), 40000, Behaviour::Answer);
+        let (updates, peer_updates) = unbounded();
+        let (mut torrent, storage, mut resume, paths) =
+            single_piece_torrent(&data, vec![slow], peer_updates);
+
+        /* The fast peer only turns up once the slow one was asked for every block of the only
+        piece, so the piece can only be finished in end-game */
+        let watcher = thread::spawn(move || {
+            let requests = slow_messages
+                .iter()
+                .filter(|msg| msg.id == MESSAGE_REQUEST)
+                .take(3)
+                .count();
+            assert_eq!(requests, 3);
+            updates.send(vec![fast]).unwrap();
+            slow_messages
+        });
+        let started = std::time::Instant::now();
+        let picker = PiecePicker::new(1, |index| resume.has_piece(index));
+        torrent.download(&storage, &mut resume, &picker).unwrap();
+        assert!(started.elapsed() < PIECE_TIMEOUT);
+        assert_eq!(fs::read(&paths[0]).unwrap(), data);
+
+        let slow_messages = watcher.join().unwrap();
+        let mut cancels = HashSet::new();
+        while cancels.len() < 3 {
+            let msg = slow_messages.recv_timeout(Duration::from_secs(5)).unwrap();
+            if msg.id == MESSAGE_CANCEL {
+                cancels.insert(parse_request(&msg).unwrap());
+            }
+        }
+        let expected: HashSet<(u32, u32, u32)> =
+            [(0, 0, 16384), (0, 16384, 16384), (0, 32768, 7232)]
+                .iter()
+                .copied()
+                .collect();
+        assert_eq!(cancels, expected);
+        for f in paths {
+            fs::remove_file(f).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partial_piece_survives_disconnect() {
+        let data: Vec<u8> = (0..65536).map(|i| (i % 241) as u8).collect();
+        let (flaky, flaky_messages) = mock_peer(data.clone(), 65536, Behaviour::Drop(2));
+        let (fast, fast_messages) = mock_peer(data.clone(), 65536, Behaviour::Answer);
+        let (updates, peer_updates) = unbounded();
+        let (mut torrent, storage, mut resume, paths) =
+            single_piece_torrent(&data, vec![flaky], peer_updates);
+
+        /* The second peer turns up once the first one sent half the piece and went away */
+        thread::spawn(move || {
+            for _ in flaky_messages.iter() {}
+            updates.send(vec![fast]).unwrap();
+        });
+        let picker = PiecePicker::new(1, |index| resume.has_piece(index));
+        torrent.download(&storage, &mut resume, &picker).unwrap();
+        assert_eq!(fs::read(&paths[0]).unwrap(), data);
+
+        let requested: Vec<(u32, u32, u32)> = fast_messages
+            .try_iter()
+            .filter(|msg| msg.id == MESSAGE_REQUEST)
+            .map(|msg| parse_request(&msg).unwrap())
+            .collect();
+        assert_eq!(requested, vec![(0, 32768, 16384), (0, 49152, 16384)]);
+        for f in paths {
+            fs::remove_file(f).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_download_fails_without_peers_or_trackers() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 233) as u8).collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gone = Peer {
+            addr: listener.local_addr().unwrap(),
+        };
+        drop(listener);
+        /* The tracker session ended, so no more peers are coming */
+        let (_, peer_updates) = unbounded();
+        let (mut torrent, storage, mut resume, paths) =
+            single_piece_torrent(&data, vec![gone], peer_updates);
+        let picker = PiecePicker::new(1, |index| resume.has_piece(index));
+        let err = torrent
+            .download(&storage, &mut resume, &picker)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        /* Nothing was written, so there is no resume file */
+        fs::remove_file(&paths[0]).unwrap();
+    }
+
+    #[test]
+    fn test_peer_download_limit() {
+        let data: Vec<u8> = (0..65536).map(|i| (i % 239) as u8).collect();
+        let (peer, _messages) = mock_peer(data.clone(), 65536, Behaviour::Answer);
+        let (mut torrent, storage, mut resume, paths) =
+            single_piece_torrent(&data, vec![peer], never());
+        torrent.limits = TorrentLimits::new(&LimitOptions {
+            peer: Profile {
+                download: Some(32768),
+                upload: None,
+            },
+            ..LimitOptions::default()
+        });
+
+        /* A second's worth arrives at once, the other half a second later */
+        let started = std::time::Instant::now();
+        let picker = PiecePicker::new(1, |index| resume.has_piece(index));
+        torrent.download(&storage, &mut resume, &picker).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert_eq!(fs::read(&paths[0]).unwrap(), data);
+        for f in paths {
+            fs::remove_file(f).unwrap();
+        }
     }
 }
@@ -3,78 +3,96 @@ use byteorder::{BigEndian, ReadBytesExt};
 use std::fmt;
 #[allow(unused_imports)]
 use std::io::{Cursor, Error, ErrorKind, Read, Result};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 #[allow(unused_imports)]
 use std::str;
 use std::vec::Vec;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
-    pub port: u16,
+    pub addr: SocketAddr,
 }
 
 impl Peer {
-    pub(crate) fn get_socket_address(&self) -> SocketAddrV4 {
-        return SocketAddrV4::new(self.ip, self.port);
+    pub(crate) fn new<I: Into<IpAddr>>(ip: I, port: u16) -> Peer {
+        Peer {
+            addr: SocketAddr::new(ip.into(), port),
+        }
+    }
+
+    pub(crate) fn get_socket_address(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /* Address bytes as sent in yourip, 4 bytes for IPv4 and 16 for IPv6 */
+    pub(crate) fn ip_octets(&self) -> Vec<u8> {
+        match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }
     }
 }
 
 /* convert byte vec representation of peers into vec of peer structs */
 pub(crate) fn unmarshal(peers_bin: Vec<u8>) -> Result<Vec<Peer>> {
-    let peer_size = 6;
-    let num_peers = peers_bin.len() / peer_size;
+    unmarshal_compact(&peers_bin, 4)
+}
+
+/* Same as unmarshal for the 18 byte entries of peers6 (BEP 7) */
+pub(crate) fn unmarshal6(peers_bin: Vec<u8>) -> Result<Vec<Peer>> {
+    unmarshal_compact(&peers_bin, 16)
+}
+
+/* Compact peers are an address of ip_size bytes followed by a big endian port */
+fn unmarshal_compact(peers_bin: &[u8], ip_size: usize) -> Result<Vec<Peer>> {
+    let peer_size = ip_size + 2;
     let peer_error = Error::new(ErrorKind::InvalidData, "Malformed Peers");
     if peers_bin.len() % peer_size != 0 {
-        Err(peer_error)
-    } else {
-        let mut peers: Vec<Peer> = vec![
-            Peer {
-                ip: Ipv4Addr::new(1, 1, 1, 1),
-                port: 0
-            };
-            num_peers
-        ];
-        let mut port_vec = vec![];
-        for i in 0..num_peers {
-            let offset = i * peer_size;
-            port_vec.push(peers_bin[offset + 4]);
-            port_vec.push(peers_bin[offset + 5]);
-            let port_vc = &port_vec[0..2].to_vec();
-            let mut port_cursor = Cursor::new(port_vc);
-            peers[i].ip = Ipv4Addr::new(
-                peers_bin[offset],
-                peers_bin[offset + 1],
-                peers_bin[offset + 2],
-                peers_bin[offset + 3],
-            );
-            peers[i].port = port_cursor.read_u16::<BigEndian>().unwrap();
-            port_vec = vec![];
-        }
-        Ok(peers)
+        return Err(peer_error);
+    }
+    let mut peers: Vec<Peer> = Vec::with_capacity(peers_bin.len() / peer_size);
+    for entry in peers_bin.chunks(peer_size) {
+        let ip = if ip_size == 4 {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(&entry[0..4]);
+            IpAddr::from(octets)
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&entry[0..16]);
+            IpAddr::from(octets)
+        };
+        let mut port_cursor = Cursor::new(&entry[ip_size..]);
+        peers.push(Peer::new(ip, port_cursor.read_u16::<BigEndian>().unwrap()));
+    }
+    Ok(peers)
+}
+
+/* Peer from a dictionary model tracker response, ip is an IPv4 or IPv6 address or a DNS name */
+pub(crate) fn unmarshal_dict(ip: &str, port: u16) -> Result<Peer> {
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Ok(Peer::new(ip, port));
+    }
+    match (ip, port).to_socket_addrs()?.next() {
+        Some(addr) => Ok(Peer { addr }),
+        None => Err(Error::new(ErrorKind::InvalidData, "Malformed Peers")),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn test_unmarshal_correctly_parse_peers() {
         let input = vec![127, 0, 0, 1, 0x00, 0x50, 1, 1, 1, 1, 0x01, 0xbb];
-        let peer_one = Peer {
-            ip: Ipv4Addr::new(127, 0, 0, 1),
-            port: 80,
-        };
-        let peer_two = Peer {
-            ip: Ipv4Addr::new(1, 1, 1, 1),
-            port: 443,
-        };
+        let peer_one = Peer::new(Ipv4Addr::new(127, 0, 0, 1), 80);
+        let peer_two = Peer::new(Ipv4Addr::new(1, 1, 1, 1), 443);
         let mut output: Vec<Peer> = vec![];
         output.push(peer_one);
         output.push(peer_two);
         match unmarshal(input) {
-            Ok(_s) => assert_eq!(_s[0].ip, output[0].ip),
+            Ok(_s) => assert_eq!(_s, output),
             Err(_err) => assert_eq!(1, 2),
         }
     }
@@ -90,11 +108,46 @@ mod tests {
 
     #[test]
     fn test_get_socket_address() {
-        let input = Peer {
-            ip: Ipv4Addr::new(127, 0, 0, 1),
-            port: 8080,
-        };
-        let expected_output = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080);
+        let input = Peer::new(Ipv4Addr::new(127, 0, 0, 1), 8080);
+        let expected_output: SocketAddr = "127.0.0.1:8080".parse().unwrap();
         assert_eq!(input.get_socket_address(), expected_output);
     }
+
+    #[test]
+    fn test_unmarshal6() {
+        let mut input = vec![0; 15];
+        input.extend_from_slice(&[1, 0x1A, 0xE1]);
+        input.extend_from_slice(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x00, 0x50,
+        ]);
+        assert_eq!(
+            unmarshal6(input).unwrap(),
+            vec![
+                Peer::new(Ipv6Addr::LOCALHOST, 6881),
+                Peer::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80),
+            ]
+        );
+        assert!(unmarshal6(vec![0; 6]).is_err());
+    }
+
+    #[test]
+    fn test_unmarshal_dict() {
+        assert_eq!(
+            unmarshal_dict("::1", 6881).unwrap(),
+            Peer::new(Ipv6Addr::LOCALHOST, 6881)
+        );
+        assert_eq!(
+            unmarshal_dict("10.0.0.2", 51413).unwrap(),
+            Peer::new(Ipv4Addr::new(10, 0, 0, 2), 51413)
+        );
+    }
+
+    #[test]
+    fn test_ip_octets() {
+        assert_eq!(
+            Peer::new(Ipv4Addr::new(10, 0, 0, 2), 1).ip_octets(),
+            vec![10, 0, 0, 2]
+        );
+        assert_eq!(Peer::new(Ipv6Addr::LOCALHOST, 1).ip_octets().len(), 16);
+    }
 }
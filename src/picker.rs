@@ -0,0 +1,464 @@
+use crate::bitfield::*;
+use crate::priority::Priority;
+use rand::seq::SliceRandom;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/* Pieces picked at random before switching to rarest first. A new download gets a few complete
+pieces to trade quickly instead of everyone starting on the same rare ones. */
+static RANDOM_FIRST_PIECES: usize = 4;
+/* Pieces ahead of the read position fetched in order in sequential mode */
+pub(crate) static DEFAULT_READ_AHEAD: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PieceState {
+    Wanted,
+    /* Handed to workers that haven't finished it yet */
+    Active,
+    Done,
+}
+
+struct PickerState {
+    pieces: Vec<PieceState>,
+    /* Number of connected peers that have each piece */
+    availability: Vec<u32>,
+    /* Workers downloading each active piece, more than one in end-game */
+    downloaders: Vec<u32>,
+    /* Pieces with blocks kept from a worker that went away */
+    started: Vec<bool>,
+    /* Skipped pieces are never picked, otherwise higher priorities go first */
+    priorities: Vec<Priority>,
+    done: usize,
+    /* Pieces a reader needs by a certain time, fetched before anything else */
+    deadlines: Vec<Option<Instant>>,
+    /* Size of the sequential window, None for plain rarest first */
+    read_ahead: Option<usize>,
+    /* The window starts at the first missing piece from here */
+    position: usize,
+}
+
+/* Decides which piece each download worker fetches next, shared between all of them */
+#[derive(Clone)]
+pub struct PiecePicker {
+    state: Arc<Mutex<PickerState>>,
+}
+
+/* The pieces of one peer that are counted in the availability, removed again when the peer goes
+away */
+pub struct PeerAvailability {
+    picker: PiecePicker,
+    counted: Bitfield,
+}
+
+impl PeerAvailability {
+    /* Count a piece the peer announced with HAVE after its bitfield */
+    pub fn add(&mut self, index: usize) {
+        let mut state = self.picker.state.lock().unwrap();
+        if index < state.pieces.len() && !has_piece(&self.counted, index) {
+            mark_piece(&mut self.counted, index);
+            state.availability[index] += 1;
+        }
+    }
+}
+
+impl Drop for PeerAvailability {
+    fn drop(&mut self) {
+        let mut state = self.picker.state.lock().unwrap();
+        for index in 0..state.pieces.len() {
+            if has_piece(&self.counted, index) {
+                state.availability[index] -= 1;
+            }
+        }
+    }
+}
+
+impl PiecePicker {
+    /* have tells which pieces are already on disk */
+    pub fn new(num_pieces: usize, have: impl Fn(usize) -> bool) -> PiecePicker {
+        let pieces: Vec<PieceState> = (0..num_pieces)
+            .map(|index| {
+                if have(index) {
+                    PieceState::Done
+                } else {
+                    PieceState::Wanted
+                }
+            })
+            .collect();
+        let done = pieces.iter().filter(|&&s| s == PieceState::Done).count();
+        PiecePicker {
+            state: Arc::new(Mutex::new(PickerState {
+                pieces,
+                availability: vec![0; num_pieces],
+                downloaders: vec![0; num_pieces],
+                started: vec![false; num_pieces],
+                priorities: vec![Priority::Normal; num_pieces],
+                done,
+                deadlines: vec![None; num_pieces],
+                read_ahead: None,
+                position: 0,
+            })),
+        }
+    }
+
+    /* Count the pieces in a new peer's bitfield, later HAVEs are added one at a time */
+    pub fn add_peer(&self, bitfield: &Bitfield) -> PeerAvailability {
+        let mut state = self.state.lock().unwrap();
+        let num_pieces = state.pieces.len();
+        let mut counted = vec![0; num_pieces.div_ceil(8)];
+        for index in 0..num_pieces {
+            if has_piece(bitfield, index) {
+                mark_piece(&mut counted, index);
+                state.availability[index] += 1;
+            }
+        }
+        PeerAvailability {
+            picker: self.clone(),
+            counted,
+        }
+    }
+
+    /* Can be changed at any time, pieces already being downloaded are finished */
+    pub fn set_priorities(&self, priorities: Vec<Priority>) {
+        self.state.lock().unwrap().priorities = priorities;
+    }
+
+    /* Fetch the pieces from the read position onwards in order, read_ahead pieces at a time, and
+    everything else rarest first. None goes back to rarest first for all pieces. */
+    pub fn set_sequential(&self, read_ahead: Option<usize>) {
+        self.state.lock().unwrap().read_ahead = read_ahead;
+    }
+
+    /* Move the sequential window, for instance when a reader seeks. Turns the window on with the
+    default read-ahead if it was off. */
+    pub fn set_position(&self, index: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.position = index as usize;
+        state.read_ahead.get_or_insert(DEFAULT_READ_AHEAD);
+    }
+
+    /* Fetch a piece before any piece without a deadline or with a later one. Indices past the last
+    piece are ignored. */
+    pub fn set_deadline(&self, index: u32, deadline: Instant) {
+        if let Some(slot) = self.state.lock().unwrap().deadlines.get_mut(index as usize) {
+            *slot = Some(deadline);
+        }
+    }
+
+    pub fn clear_deadline(&self, index: u32) {
+        if let Some(slot) = self.state.lock().unwrap().deadlines.get_mut(index as usize) {
+            *slot = None;
+        }
+    }
+
+    /* The wanted piece the peer has with the earliest deadline, else the first one of the highest
+    priority in the sequential window, else a started one of the highest priority, else the rarest
+    one of the highest priority with ties broken at random. None when the peer has nothing we still
+    need. */
+    pub fn pick(&self, bitfield: &Bitfield) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let candidates: Vec<usize> = (0..state.pieces.len())
+            .filter(|&index| {
+                state.pieces[index] == PieceState::Wanted
+                    && state.priorities[index] != Priority::Skip
+                    && has_piece(bitfield, index)
+            })
+            .collect();
+        let index = choose(&state, candidates)?;
+        state.pieces[index] = PieceState::Active;
+        state.downloaders[index] = 1;
+        state.started[index] = true;
+        Some(index as u32)
+    }
+
+    /* End-game: once every wanted piece has been handed out, a peer with nothing left to pick helps
+    with an active piece it has, the one with the fewest workers first. The blocks of the piece are
+    then requested from several peers and whichever copy arrives first is kept. held are the pieces
+    the worker is already on. */
+    pub fn pick_endgame(&self, bitfield: &Bitfield, held: &[u32]) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let unpicked = (0..state.pieces.len()).any(|index| {
+            state.pieces[index] == PieceState::Wanted && state.priorities[index] != Priority::Skip
+        });
+        if unpicked {
+            return None;
+        }
+        let index = (0..state.pieces.len())
+            .filter(|&index| {
+                state.pieces[index] == PieceState::Active
+                    && state.priorities[index] != Priority::Skip
+                    && has_piece(bitfield, index)
+                    && !held.contains(&(index as u32))
+            })
+            .min_by_key(|&index| state.downloaders[index])?;
+        state.downloaders[index] += 1;
+        Some(index as u32)
+    }
+
+    /* Returns false if another worker completed the piece first */
+    pub fn complete(&self, index: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.pieces[index as usize] == PieceState::Done {
+            return false;
+        }
+        state.pieces[index as usize] = PieceState::Done;
+        state.downloaders[index as usize] = 0;
+        state.started[index as usize] = false;
+        state.deadlines[index as usize] = None;
+        state.done += 1;
+        true
+    }
+
+    /* A worker stops working on a piece without completing it. Once no worker is left on it another
+    one can pick it again, and finish it from the blocks that already arrived. */
+    pub fn abort(&self, index: u32) {
+        let mut state = self.state.lock().unwrap();
+        let index = index as usize;
+        if state.pieces[index] == PieceState::Active {
+            state.downloaders[index] = state.downloaders[index].saturating_sub(1);
+            if state.downloaders[index] == 0 {
+                state.pieces[index] = PieceState::Wanted;
+            }
+        }
+    }
+
+    /* A piece failed the hash check and has to be downloaded from scratch */
+    pub fn reject(&self, index: u32) {
+        self.abort(index);
+        self.state.lock().unwrap().started[index as usize] = false;
+    }
+
+    /* Every piece that isn't skipped is done */
+    pub fn is_complete(&self) -> bool {
+        let (done, wanted) = self.progress();
+        done == wanted
+    }
+
+    /* Done and total pieces, leaving out skipped pieces that haven't been downloaded */
+    pub fn progress(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        let wanted = (0..state.pieces.len())
+            .filter(|&index| {
+                state.pieces[index] == PieceState::Done || state.priorities[index] != Priority::Skip
+            })
+            .count();
+        (state.done, wanted)
+    }
+}
+
+/* candidates are in index order */
+fn choose(state: &PickerState, candidates: Vec<usize>) -> Option<usize> {
+    let urgent = candidates
+        .iter()
+        .filter(|&&index| state.deadlines[index].is_some())
+        .min_by_key(|&&index| state.deadlines[index]);
+    if let Some(&index) = urgent {
+        return Some(index);
+    }
+    let highest = candidates
+        .iter()
+        .map(|&index| state.priorities[index])
+        .max()?;
+    let candidates: Vec<usize> = candidates
+        .into_iter()
+        .filter(|&index| state.priorities[index] == highest)
+        .collect();
+    if let Some(read_ahead) = state.read_ahead {
+        let start = (state.position..state.pieces.len())
+            .find(|&index| state.pieces[index] != PieceState::Done)
+            .unwrap_or(state.pieces.len());
+        let next = candidates
+            .iter()
+            .find(|&&index| index >= start && index < start + read_ahead);
+        if let Some(&index) = next {
+            return Some(index);
+        }
+    }
+    /* Finishing a piece is cheaper than starting one and frees the memory of its blocks */
+    if let Some(&index) = candidates.iter().find(|&&index| state.started[index]) {
+        return Some(index);
+    }
+    let choices: Vec<usize> = if state.done < RANDOM_FIRST_PIECES {
+        candidates
+    } else {
+        let rarest = candidates
+            .iter()
+            .map(|&index| state.availability[index])
+            .min()?;
+        candidates
+            .into_iter()
+            .filter(|&index| state.availability[index] == rarest)
+            .collect()
+    };
+    choices.choose(&mut rand::thread_rng()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_rarest_first() {
+        let picker = PiecePicker::new(10, |index| index < RANDOM_FIRST_PIECES);
+        let everything = vec![0xff, 0xc0];
+        let _a = picker.add_peer(&everything);
+        let _b = picker.add_peer(&vec![0b1111_1110, 0b1000_0000]);
+        /* A HAVE for piece 5 arrives after the peer's empty bitfield */
+        let mut c = picker.add_peer(&vec![0, 0]);
+        c.add(5);
+        c.add(5);
+        c.add(10);
+
+        /* 7 and 9 are rarest, then 4, 6 and 8, then 5 */
+        let mut rarest = vec![
+            picker.pick(&everything).unwrap(),
+            picker.pick(&everything).unwrap(),
+        ];
+        rarest.sort_unstable();
+        assert_eq!(rarest, vec![7, 9]);
+        picker.abort(9);
+        assert_eq!(picker.pick(&everything), Some(9));
+        let mut next: Vec<u32> = (0..3).map(|_| picker.pick(&everything).unwrap()).collect();
+        next.sort_unstable();
+        assert_eq!(next, vec![4, 6, 8]);
+        assert_eq!(picker.pick(&everything), Some(5));
+        assert_eq!(picker.pick(&everything), None);
+    }
+
+    #[test]
+    fn test_pick_only_what_peer_has() {
+        let picker = PiecePicker::new(8, |_| false);
+        assert_eq!(picker.pick(&vec![0]), None);
+        assert_eq!(picker.pick(&vec![0b0000_0100]), Some(5));
+        assert_eq!(picker.pick(&vec![0b0000_0100]), None);
+        picker.complete(5);
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn test_first_pieces_are_random() {
+        /* The first picks ignore availability, so over many tries more than one piece comes up */
+        let mut seen = vec![];
+        for _ in 0..50 {
+            let picker = PiecePicker::new(16, |_| false);
+            let _common = picker.add_peer(&vec![0xff, 0xfe]);
+            let index = picker.pick(&vec![0xff, 0xff]).unwrap();
+            if !seen.contains(&index) {
+                seen.push(index);
+            }
+        }
+        assert!(seen.len() > 1);
+    }
+
+    #[test]
+    fn test_sequential_window() {
+        let picker = PiecePicker::new(12, |index| index < RANDOM_FIRST_PIECES);
+        picker.set_sequential(Some(3));
+        let everything = vec![0xff, 0xf0];
+        /* Piece 11 is the rarest, but the window comes first */
+        let _common = picker.add_peer(&vec![0xff, 0xe0]);
+        assert_eq!(picker.pick(&everything), Some(4));
+        assert_eq!(picker.pick(&everything), Some(5));
+        /* A peer without 6 gets the rarest piece outside the window */
+        assert_eq!(picker.pick(&vec![0b1111_1101, 0xf0]), Some(11));
+        assert_eq!(picker.pick(&everything), Some(6));
+        /* The window slides once its first piece is done */
+        picker.complete(4);
+        assert_eq!(picker.pick(&everything), Some(7));
+
+        /* A seek moves the window */
+        picker.set_position(9);
+        assert_eq!(picker.pick(&everything), Some(9));
+    }
+
+    #[test]
+    fn test_deadlines_come_first() {
+        let picker = PiecePicker::new(8, |_| false);
+        picker.set_sequential(Some(2));
+        let now = Instant::now();
+        picker.set_deadline(6, now + std::time::Duration::from_secs(2));
+        picker.set_deadline(5, now + std::time::Duration::from_secs(1));
+        picker.set_deadline(3, now);
+        picker.clear_deadline(3);
+        assert_eq!(picker.pick(&vec![0xff]), Some(5));
+        assert_eq!(picker.pick(&vec![0xff]), Some(6));
+        assert_eq!(picker.pick(&vec![0xff]), Some(0));
+    }
+
+    #[test]
+    fn test_priorities() {
+        let picker = PiecePicker::new(4, |index| index == 0);
+        let mut priorities = vec![
+            Priority::Normal,
+            Priority::Low,
+            Priority::Skip,
+            Priority::High,
+        ];
+        picker.set_priorities(priorities.to_vec());
+        assert_eq!(picker.progress(), (1, 3));
+        assert_eq!(picker.pick(&vec![0xf0]), Some(3));
+        assert_eq!(picker.pick(&vec![0xf0]), Some(1));
+        assert_eq!(picker.pick(&vec![0xf0]), None);
+        picker.complete(1);
+        picker.complete(3);
+        assert!(picker.is_complete());
+
+        /* Unskipping a piece while downloading makes it wanted again */
+        priorities[2] = Priority::Low;
+        picker.set_priorities(priorities);
+        assert!(!picker.is_complete());
+        assert_eq!(picker.pick(&vec![0xf0]), Some(2));
+    }
+
+    #[test]
+    fn test_peer_removal_and_completion() {
+        let picker = PiecePicker::new(2, |index| index == 0);
+        {
+            let _peer = picker.add_peer(&vec![0b1100_0000]);
+            assert_eq!(picker.state.lock().unwrap().availability, vec![1, 1]);
+        }
+        assert_eq!(picker.state.lock().unwrap().availability, vec![0, 0]);
+        assert_eq!(picker.pick(&vec![0b1100_0000]), Some(1));
+        picker.complete(1);
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn test_endgame() {
+        let picker = PiecePicker::new(3, |index| index == 0);
+        assert_eq!(picker.pick(&vec![0b0100_0000]), Some(1));
+        /* Piece 2 hasn't been handed out yet */
+        assert_eq!(picker.pick_endgame(&vec![0b0100_0000], &[]), None);
+        assert_eq!(picker.pick(&vec![0b0010_0000]), Some(2));
+
+        assert_eq!(picker.pick(&vec![0b0110_0000]), None);
+        assert_eq!(picker.pick_endgame(&vec![0b0010_0000], &[]), Some(2));
+        /* Piece 1 has fewer workers now */
+        assert_eq!(picker.pick_endgame(&vec![0b0110_0000], &[]), Some(1));
+        assert_eq!(picker.pick_endgame(&vec![0b0110_0000], &[]), Some(1));
+
+        /* The piece stays active until its last worker gives up */
+        picker.abort(2);
+        assert_eq!(picker.pick(&vec![0b0010_0000]), None);
+        picker.abort(2);
+        assert_eq!(picker.pick(&vec![0b0010_0000]), Some(2));
+
+        assert!(picker.complete(1));
+        assert!(!picker.complete(1));
+        picker.abort(1);
+        assert_eq!(picker.pick_endgame(&vec![0b0110_0000], &[]), Some(2));
+        assert_eq!(picker.pick_endgame(&vec![0b0110_0000], &[2]), None);
+    }
+
+    #[test]
+    fn test_started_pieces_first() {
+        let picker = PiecePicker::new(8, |index| index < RANDOM_FIRST_PIECES);
+        let everything = vec![0xff];
+        let _a = picker.add_peer(&vec![0b1111_1110]);
+        /* 7 is the rarest, but 5 was started by a worker that went away */
+        assert_eq!(picker.pick(&vec![0b0000_0100]), Some(5));
+        picker.abort(5);
+        assert_eq!(picker.pick(&everything), Some(5));
+        picker.reject(5);
+        assert_eq!(picker.pick(&everything), Some(7));
+    }
+}
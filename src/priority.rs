@@ -0,0 +1,248 @@
+use crate::p2p::file_spans;
+use crate::picker::PiecePicker;
+use crate::storage::Storage;
+use std::io::Result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/* How much a file is wanted. A piece gets the highest priority of the files it overlaps, so a
+piece shared with a wanted file is downloaded even if the other file is skipped. */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Priority, String> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!(
+                "unknown priority {}, expected skip, low, normal or high",
+                s
+            )),
+        }
+    }
+}
+
+/* Parse a list of file indices and ranges such as "0,2,4-6", the format of BEP 53 so= */
+pub fn parse_file_list(list: &str) -> Option<Vec<usize>> {
+    let mut indices = vec![];
+    for part in list.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.trim().parse().ok()?;
+                let last: usize = last.trim().parse().ok()?;
+                if first > last {
+                    return None;
+                }
+                indices.extend(first..=last);
+            }
+            None => indices.push(part.trim().parse().ok()?),
+        }
+    }
+    Some(indices)
+}
+
+/* File priorities as given on the command line or in a magnet link, before the number of files
+is known */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileSelection {
+    /* Download only these files, None for all of them */
+    pub only: Option<Vec<usize>>,
+    /* Applied on top of only, later entries win */
+    pub priorities: Vec<(Vec<usize>, Priority)>,
+}
+
+impl FileSelection {
+    /* Indices past the last file are ignored */
+    pub fn resolve(&self, num_files: usize) -> Vec<Priority> {
+        let mut priorities = match &self.only {
+            Some(only) => {
+                let mut priorities = vec![Priority::Skip; num_files];
+                for &index in only.iter().filter(|&&index| index < num_files) {
+                    priorities[index] = Priority::Normal;
+                }
+                priorities
+            }
+            None => vec![Priority::Normal; num_files],
+        };
+        for (indices, priority) in &self.priorities {
+            for &index in indices.iter().filter(|&&index| index < num_files) {
+                priorities[index] = *priority;
+            }
+        }
+        priorities
+    }
+}
+
+/* The priority of every piece, the highest of the files it overlaps */
+pub fn piece_priorities(storage: &Storage, piece_length: u32, files: &[Priority]) -> Vec<Priority> {
+    let length: u64 = storage.files().iter().map(|f| f.Length).sum();
+    let num_pieces = length.div_ceil(piece_length as u64) as usize;
+    (0..num_pieces)
+        .map(|index| {
+            let begin = index as u64 * piece_length as u64;
+            let end = length.min(begin + piece_length as u64);
+            file_spans(storage.files(), begin, end)
+                .iter()
+                .map(|span| files[span.file_index])
+                .max()
+                .unwrap_or(Priority::Skip)
+        })
+        .collect()
+}
+
+/* The file priorities of a running download. Changing one reprioritises the pieces in the picker
+and creates the file on disk if it was skipped until now. */
+#[derive(Clone)]
+pub struct FilePriorities {
+    files: Arc<Mutex<Vec<Priority>>>,
+    storage: Arc<Storage>,
+    picker: PiecePicker,
+    piece_length: u32,
+}
+
+impl FilePriorities {
+    pub fn new(
+        storage: Arc<Storage>,
+        picker: PiecePicker,
+        piece_length: u32,
+        files: Vec<Priority>,
+    ) -> FilePriorities {
+        picker.set_priorities(piece_priorities(&storage, piece_length, &files));
+        FilePriorities {
+            files: Arc::new(Mutex::new(files)),
+            storage,
+            picker,
+            piece_length,
+        }
+    }
+
+    /* Indices past the last file are ignored */
+    pub fn set(&self, file_indices: &[usize], priority: Priority) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let num_files = files.len();
+        for &index in file_indices.iter().filter(|&&index| index < num_files) {
+            if priority != Priority::Skip {
+                self.storage.materialise(index)?;
+            }
+            files[index] = priority;
+        }
+        self.picker
+            .set_priorities(piece_priorities(&self.storage, self.piece_length, &files));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::FileEntry;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_file_list() {
+        assert_eq!(parse_file_list("0,2,4-6").unwrap(), vec![0, 2, 4, 5, 6]);
+        assert_eq!(parse_file_list("3").unwrap(), vec![3]);
+        assert!(parse_file_list("").is_none());
+        assert!(parse_file_list("4-2").is_none());
+        assert!(parse_file_list("a,1").is_none());
+        assert_eq!("high".parse::<Priority>().unwrap(), Priority::High);
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+
+    #[test]
+    fn test_resolve_selection() {
+        let selection = FileSelection {
+            only: Some(vec![1, 2, 9]),
+            priorities: vec![(vec![2, 3], Priority::High), (vec![3], Priority::Low)],
+        };
+        assert_eq!(
+            selection.resolve(4),
+            vec![
+                Priority::Skip,
+                Priority::Normal,
+                Priority::High,
+                Priority::Low
+            ]
+        );
+        assert_eq!(
+            FileSelection::default().resolve(2),
+            vec![Priority::Normal; 2]
+        );
+    }
+
+    #[test]
+    fn test_piece_priorities_take_the_highest_file() {
+        let files = vec![
+            FileEntry {
+                Path: vec!["a".to_string()],
+                Length: 6,
+            },
+            FileEntry {
+                Path: vec!["b".to_string()],
+                Length: 2,
+            },
+            FileEntry {
+                Path: vec!["c".to_string()],
+                Length: 5,
+            },
+        ];
+        let storage = Storage::new(Path::new("/tmp"), "multi", &files, 13);
+        let pieces = piece_priorities(
+            &storage,
+            4,
+            &[Priority::Skip, Priority::Low, Priority::High],
+        );
+        assert_eq!(
+            pieces,
+            vec![
+                Priority::Skip,
+                Priority::Low,
+                Priority::High,
+                Priority::High
+            ]
+        );
+    }
+
+    #[test]
+    fn test_change_priority_while_downloading() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let files = vec![
+            FileEntry {
+                Path: vec!["a".to_string()],
+                Length: 4,
+            },
+            FileEntry {
+                Path: vec!["b".to_string()],
+                Length: 4,
+            },
+        ];
+        let storage = Arc::new(Storage::new(&root, "multi", &files, 8));
+        storage.select(&[true, false]);
+        storage.allocate().unwrap();
+        let picker = PiecePicker::new(2, |_| false);
+        let priorities = FilePriorities::new(
+            storage.clone(),
+            picker.clone(),
+            4,
+            vec![Priority::Normal, Priority::Skip],
+        );
+        assert_eq!(picker.pick(&vec![0xc0]), Some(0));
+        assert_eq!(picker.pick(&vec![0xc0]), None);
+
+        priorities.set(&[1, 5], Priority::High).unwrap();
+        assert!(root.join("multi").join("b").exists());
+        assert_eq!(picker.pick(&vec![0xc0]), Some(1));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use crate::message::{Message, MESSAGE_EMPTY, MESSAGE_PIECE};
+use crate::runtime;
+use lazy_static::lazy_static;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::time::delay_for;
+
+/* How often the alternate speed schedule is looked at */
+static SCHEDULE_CHECK: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref GLOBAL: GlobalLimits = GlobalLimits::default();
+}
+
+/* A rate in bytes per second that can be changed while it is in use, shared by every limiter
+following it. 0 is stored for unlimited. */
+#[derive(Clone, Debug, Default)]
+pub struct Rate {
+    bytes_per_second: Arc<AtomicU64>,
+}
+
+impl Rate {
+    pub fn new(rate: Option<u64>) -> Rate {
+        let shared = Rate::default();
+        shared.set(rate);
+        shared
+    }
+
+    /* None or 0 means unlimited */
+    pub fn set(&self, rate: Option<u64>) {
+        self.bytes_per_second
+            .store(rate.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        match self.bytes_per_second.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+}
+
+struct Bucket {
+    /* At most one second's worth of the rate, the most that can be spent in one burst */
+    tokens: f64,
+    last: Instant,
+}
+
+/* Token bucket shared by every connection it applies to. Callers may overdraw it, the
+overdraft is paid back by sleeping, so large blocks are never starved by small ones. */
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub(crate) rate: Rate,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> RateLimiter {
+        RateLimiter::new(None)
+    }
+}
+
+impl RateLimiter {
+    /* None or a rate of 0 means unlimited */
+    pub fn new(rate: Option<u64>) -> RateLimiter {
+        RateLimiter::following(&Rate::new(rate))
+    }
+
+    /* A limiter with a bucket of its own whose rate is changed through rate, so one setting can
+    cap every peer separately */
+    pub fn following(rate: &Rate) -> RateLimiter {
+        RateLimiter {
+            rate: rate.clone(),
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: f64::MAX,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /* Takes effect for the next bytes acquired */
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.rate.set(rate);
+    }
+
+    /* Take bytes from the bucket and return how long the caller has to wait for them. Connections
+    go through RateLimits, which applies several limiters at once. */
+    fn take(&self, bytes: u64) -> Duration {
+        let mut b = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(b.last).as_secs_f64();
+        b.last = now;
+        let rate = match self.rate.get() {
+            Some(rate) => rate as f64,
+            None => {
+                /* Limiting again later starts with a full bucket */
+                b.tokens = f64::MAX;
+                return Duration::from_secs(0);
+            }
+        };
+        b.tokens = (b.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if b.tokens < 0.0 {
+            Duration::from_secs_f64(-b.tokens / rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/* Every limiter that applies to some traffic, such as the global, torrent and peer ones. Bytes are
+taken from all of them and the caller waits for the slowest. */
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    limiters: Vec<RateLimiter>,
+}
+
+impl RateLimits {
+    pub fn new(limiters: Vec<RateLimiter>) -> RateLimits {
+        RateLimits { limiters }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self
+            .limiters
+            .iter()
+            .map(|limiter| limiter.take(bytes))
+            .max();
+        pause(wait.unwrap_or_default()).await;
+    }
+}
+
+/* Only a task that is over its rate goes through the timer */
+async fn pause(wait: Duration) {
+    if wait > Duration::from_secs(0) {
+        delay_for(wait).await;
+    }
+}
+
+/* Download and upload rates in bytes per second, None for unlimited */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+/* The time of day the alternate profile is in effect, in minutes since local midnight. An end
+before the start wraps past midnight. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    start: u32,
+    end: u32,
+}
+
+impl Schedule {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/* HH:MM-HH:MM */
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Schedule, String> {
+        let error = || format!("expected a schedule such as 22:00-07:00, got {}", s);
+        let minute = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+            if hours < 24 && minutes < 60 {
+                Some(hours * 60 + minutes)
+            } else {
+                None
+            }
+        };
+        let (start, end) = s.split_once('-').ok_or_else(error)?;
+        Ok(Schedule {
+            start: minute(start).ok_or_else(error)?,
+            end: minute(end).ok_or_else(error)?,
+        })
+    }
+}
+
+/* Limits as configured, before any limiter exists */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LimitOptions {
+    /* Shared by every torrent in the process */
+    pub global: Profile,
+    pub torrent: Profile,
+    /* Applied to each peer connection separately */
+    pub peer: Profile,
+    /* Count message headers and other protocol messages, not just piece data */
+    pub count_overhead: bool,
+    /* Replaces the global profile while the schedule is in effect */
+    pub alternate: Profile,
+    pub schedule: Option<Schedule>,
+}
+
+/* The limiters shared by every torrent in the process */
+#[derive(Default)]
+pub struct GlobalLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+pub fn global() -> &'static GlobalLimits {
+    &GLOBAL
+}
+
+fn apply_profile(profile: &Profile) {
+    global().download.set_rate(profile.download);
+    global().upload.set_rate(profile.upload);
+}
+
+/* Set the global limits, switching to the alternate profile and back as scheduled */
+pub fn apply_global(options: &LimitOptions) {
+    apply_profile(&options.global);
+    let schedule = match options.schedule {
+        Some(schedule) => schedule,
+        None => return,
+    };
+    let (normal, alternate) = (options.global, options.alternate);
+    runtime::spawn(async move {
+        let mut in_effect = false;
+        loop {
+            let now = OffsetDateTime::try_now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+            let minute = now.hour() as u32 * 60 + now.minute() as u32;
+            if schedule.contains(minute) != in_effect {
+                in_effect = !in_effect;
+                if in_effect {
+                    info!("Switching to the alternate speed limits");
+                    apply_profile(&alternate);
+                } else {
+                    info!("Switching back to the normal speed limits");
+                    apply_profile(&normal);
+                }
+            }
+            delay_for(SCHEDULE_CHECK).await;
+        }
+    });
+}
+
+/* The limiters of one torrent, shared by its inbound and outbound connections. The rates can be
+changed while it runs. */
+#[derive(Clone, Default)]
+pub struct TorrentLimits {
+    pub(crate) download: RateLimiter,
+    pub(crate) upload: RateLimiter,
+    pub(crate) peer_download: Rate,
+    pub(crate) peer_upload: Rate,
+    pub(crate) count_overhead: bool,
+}
+
+impl TorrentLimits {
+    pub fn new(options: &LimitOptions) -> TorrentLimits {
+        TorrentLimits {
+            download: RateLimiter::new(options.torrent.download),
+            upload: RateLimiter::new(options.torrent.upload),
+            peer_download: Rate::new(options.peer.download),
+            peer_upload: Rate::new(options.peer.upload),
+            count_overhead: options.count_overhead,
+        }
+    }
+
+    /* The limits of a new peer connection, with a peer bucket of its own */
+    pub fn connection(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            download: RateLimits::new(vec![
+                global().download.clone(),
+                self.download.clone(),
+                RateLimiter::following(&self.peer_download),
+            ]),
+            upload: RateLimits::new(vec![
+                global().upload.clone(),
+                self.upload.clone(),
+                RateLimiter::following(&self.peer_upload),
+            ]),
+            count_overhead: self.count_overhead,
+        }
+    }
+}
+
+/* What limits the messages read and written on one peer connection. The default is unlimited. */
+#[derive(Clone, Default)]
+pub struct ConnectionLimits {
+    download: RateLimits,
+    upload: RateLimits,
+    count_overhead: bool,
+}
+
+impl ConnectionLimits {
+    /* Wait until the rates allow a message that was read */
+    pub(crate) async fn received(&self, msg: &Message) {
+        self.download.acquire(self.charge(msg)).await;
+    }
+
+    /* Wait until the rates allow a message to be written */
+    pub(crate) async fn sending(&self, msg: &Message) {
+        self.upload.acquire(self.charge(msg)).await;
+    }
+
+    /* Bytes a message counts for, the whole message on the wire with overhead counted and only
+    the block of a piece message otherwise */
+    fn charge(&self, msg: &Message) -> u64 {
+        if self.count_overhead {
+            if msg.id == MESSAGE_EMPTY {
+                4
+            } else {
+                5 + msg.payload.len() as u64
+            }
+        } else if msg.id == MESSAGE_PIECE {
+            msg.payload.len().saturating_sub(8) as u64
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{format_have, format_piece};
+    use crate::runtime::block_on;
+
+    #[test]
+    fn test_unlimited_never_blocks() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(None);
+        for _ in 0..1000 {
+            block_on(pause(limiter.take(1 << 30)));
+        }
+        block_on(pause(RateLimiter::new(Some(0)).take(1 << 30)));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_limits_to_rate() {
+        let limiter = RateLimiter::new(Some(100_000));
+        let start = Instant::now();
+        /* The first second's worth is a burst, the rest is paced */
+        block_on(pause(limiter.take(50_000)));
+        block_on(pause(limiter.take(50_000)));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        block_on(pause(limiter.clone().take(50_000)));
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn test_rate_changes_at_runtime() {
+        let limiter = RateLimiter::new(None);
+        block_on(pause(limiter.take(1 << 30)));
+        limiter.set_rate(Some(100_000));
+        assert_eq!(limiter.take(100_000), Duration::from_secs(0));
+        assert!(limiter.take(50_000) >= Duration::from_millis(450));
+        limiter.set_rate(None);
+        assert_eq!(limiter.take(1 << 30), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_slowest_limit_wins() {
+        let peer_rate = Rate::new(Some(100_000));
+        let torrent = RateLimiter::new(Some(1_000_000));
+        let first = RateLimits::new(vec![torrent.clone(), RateLimiter::following(&peer_rate)]);
+        let second = RateLimits::new(vec![torrent.clone(), RateLimiter::following(&peer_rate)]);
+        let start = Instant::now();
+        /* Each peer has a bucket of its own */
+        block_on(first.acquire(100_000));
+        block_on(second.acquire(100_000));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        /* Without a peer limit the torrent bucket, 800_000 left at 1_000_000 a second, decides */
+        peer_rate.set(None);
+        let start = Instant::now();
+        block_on(first.acquire(900_000));
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(90) && waited < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let night: Schedule = "22:00-07:30".parse().unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(7 * 60 + 29));
+        assert!(!night.contains(7 * 60 + 30));
+        assert!(!night.contains(12 * 60));
+        let lunch: Schedule = "12:00-13:00".parse().unwrap();
+        assert!(lunch.contains(12 * 60 + 30));
+        assert!(!lunch.contains(13 * 60));
+        assert!("25:00-07:00".parse::<Schedule>().is_err());
+        assert!("22:00".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_overhead_is_optional() {
+        let piece = format_piece(1, 0, &[0; 100]);
+        let have = format_have(1);
+        let payload_only = ConnectionLimits::default();
+        assert_eq!(payload_only.charge(&piece), 100);
+        assert_eq!(payload_only.charge(&have), 0);
+        let with_overhead = ConnectionLimits {
+            count_overhead: true,
+            ..ConnectionLimits::default()
+        };
+        assert_eq!(with_overhead.charge(&piece), 113);
+        assert_eq!(with_overhead.charge(&have), 9);
+        assert_eq!(with_overhead.charge(&Message::default()), 4);
+    }
+}
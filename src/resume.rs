@@ -0,0 +1,264 @@
+use crate::bitfield::*;
+use crate::storage::Storage;
+use crate::torrentfile::TorrentFile;
+use crate::verify::{check_piece, map_pieces, PieceStatus};
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/* Rewriting the resume file for every piece would be wasteful on large torrents */
+static SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/* Size and modification time of a file when the resume data was written, used to tell whether
+the data on disk was changed behind our back */
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct ResumeFile {
+    length: u64,
+    mtime: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BencodeResume {
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    pieces: ByteBuf,
+    files: Vec<ResumeFile>,
+}
+
+/* The pieces that are verified on disk, kept in a bencoded file next to the output so an
+interrupted download can pick up where it left off */
+pub struct Resume {
+    path: PathBuf,
+    info_hash: Vec<u8>,
+    pub(crate) have: Bitfield,
+    last_save: Instant,
+}
+
+impl Resume {
+    /* output.resume for single file torrents, name.resume beside the directory otherwise */
+    pub fn path_for(out: &Path, torrent_file: &TorrentFile) -> PathBuf {
+        if torrent_file.Files.is_empty() {
+            let mut name = out.as_os_str().to_owned();
+            name.push(".resume");
+            PathBuf::from(name)
+        } else {
+            out.join(format!("{}.resume", torrent_file.Name))
+        }
+    }
+
+    /* Trust the resume file if the files on disk are exactly as it describes. If they were
+    written to since it was saved, as after a crash, the recorded pieces may have changed too, so
+    like without usable resume data whatever is already there is hashed. */
+    pub fn load_or_recheck(path: PathBuf, torrent_file: &TorrentFile, storage: &Storage) -> Resume {
+        let have = match load(&path, torrent_file, storage) {
+            Some(have) => have,
+            None => recheck(torrent_file, storage),
+        };
+        Resume {
+            path,
+            info_hash: torrent_file.InfoHash.to_vec(),
+            have,
+            last_save: Instant::now(),
+        }
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        has_piece(&self.have, index)
+    }
+
+    /* Record a piece that was written to storage, saving now and then */
+    pub fn add_piece(&mut self, index: usize, storage: &Storage) -> Result<()> {
+        self.have = set_piece(&self.have, index);
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save(storage)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self, storage: &Storage) -> Result<()> {
+        let files = file_states(storage)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "output files are missing"))?;
+        let resume = BencodeResume {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: ByteBuf::from(self.have.to_vec()),
+            files,
+        };
+        let encoded = ser::to_bytes(&resume).map_err(Error::other)?;
+        /* Write and rename so a crash never leaves a truncated resume file behind */
+        let tmp = self.path.with_extension("resume.tmp");
+        fs::write(&tmp, encoded)?;
+        fs::rename(&tmp, &self.path)?;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+/* The pieces recorded for this torrent, None if the files changed since they were or the resume
+data doesn't fit the torrent */
+fn load(path: &Path, torrent_file: &TorrentFile, storage: &Storage) -> Option<Bitfield> {
+    let resume = de::from_bytes::<BencodeResume>(&fs::read(path).ok()?).ok()?;
+    if resume.info_hash.as_ref() != torrent_file.InfoHash.as_slice()
+        || resume.pieces.len() != torrent_file.PieceHashes.len().div_ceil(8)
+        || Some(resume.files) != file_states(storage)
+    {
+        return None;
+    }
+    Some(resume.pieces.into_vec())
+}
+
+/* The state of every file holding data, None if any of them doesn't exist */
+fn file_states(storage: &Storage) -> Option<Vec<ResumeFile>> {
+    storage
+        .data_paths()
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+            Some(ResumeFile {
+                length: metadata.len(),
+                mtime: mtime.as_nanos() as u64,
+            })
+        })
+        .collect()
+}
+
+/* Hash every piece that can be read from storage and return the ones that are intact */
+fn recheck(torrent_file: &TorrentFile, storage: &Storage) -> Bitfield {
+    let num_pieces = torrent_file.PieceHashes.len();
+    let mut have = vec![0; num_pieces.div_ceil(8)];
+    if !storage.data_paths().iter().any(|path| path.exists()) {
+        return have;
+    }
+    info!("Checking existing data for {}", torrent_file.Name);
+    let statuses = map_pieces(num_pieces, |index| {
+        check_piece(torrent_file, storage, index)
+    });
+    for (index, status) in statuses.into_iter().enumerate() {
+        if status == PieceStatus::Intact {
+            mark_piece(&mut have, index);
+        }
+    }
+    have
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::{sha1_hash, FileEntry};
+    use std::collections::HashMap;
+
+    /* Three 4 byte pieces over two files, only the files' directory is created */
+    fn test_torrent(root: &Path) -> (TorrentFile, Storage) {
+        let data = b"hello world!";
+        let files = vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["b.txt".to_string()],
+                Length: 7,
+            },
+        ];
+        let torrent_file = TorrentFile {
+            Announce: String::new(),
+            AnnounceList: vec![],
+            InfoHash: vec![3; 20],
+            PieceHashes: data.chunks(4).map(sha1_hash).collect(),
+            PieceLength: 4,
+            Length: 12,
+            Name: "multi".to_string(),
+            Files: files.to_vec(),
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
+        };
+        let storage = Storage::new(root, "multi", &files, 12);
+        (torrent_file, storage)
+    }
+
+    #[test]
+    fn test_recheck_without_resume_file() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let (torrent_file, storage) = test_torrent(&root);
+        let path = Resume::path_for(&root, &torrent_file);
+        assert_eq!(path, root.join("multi.resume"));
+
+        /* Nothing on disk yet */
+        let resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert_eq!(resume.have, vec![0]);
+
+        /* Pieces 0 and 2 are intact, piece 1 is corrupt */
+        storage.allocate().unwrap();
+        storage.write(0, b"hell").unwrap();
+        storage.write(4, b"o_wo").unwrap();
+        storage.write(8, b"rld!").unwrap();
+        let resume = Resume::load_or_recheck(path, &torrent_file, &storage);
+        assert!(resume.has_piece(0));
+        assert!(!resume.has_piece(1));
+        assert!(resume.has_piece(2));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_resume_file_trusted_until_files_change() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let (torrent_file, storage) = test_torrent(&root);
+        storage.allocate().unwrap();
+        let path = Resume::path_for(&root, &torrent_file);
+        let mut resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert_eq!(resume.have, vec![0]);
+
+        /* The resume file is believed without hashing, even though no data was written */
+        resume.add_piece(1, &storage).unwrap();
+        resume.save(&storage).unwrap();
+        let resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert!(resume.has_piece(1));
+
+        /* Once data is written after the save, as when the process died, every piece is hashed
+        and the recorded one that was never written is dropped. Sleep so the new mtime is
+        distinct. */
+        std::thread::sleep(Duration::from_millis(50));
+        storage.write(0, b"hell").unwrap();
+        let mut resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert!(resume.has_piece(0));
+        assert!(!resume.has_piece(1));
+        assert!(!resume.has_piece(2));
+
+        /* Resume data that doesn't fit the torrent is ignored */
+        resume.have = vec![0xff, 0xff];
+        resume.save(&storage).unwrap();
+        let mut resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert_eq!(resume.have, vec![0b1000_0000]);
+
+        /* A file of another size was replaced, so everything is hashed again */
+        resume.have = vec![0b1110_0000];
+        resume.save(&storage).unwrap();
+        fs::write(root.join("multi").join("b.txt"), b"o world!").unwrap();
+        let resume = Resume::load_or_recheck(path.clone(), &torrent_file, &storage);
+        assert_eq!(resume.have, vec![0b1000_0000]);
+
+        /* Resume data for another torrent is ignored */
+        let mut other = torrent_file.clone();
+        other.InfoHash = vec![4; 20];
+        let mut resume = Resume::load_or_recheck(path.clone(), &other, &storage);
+        resume.have = vec![0b1110_0000];
+        resume.save(&storage).unwrap();
+        let resume = Resume::load_or_recheck(path, &torrent_file, &storage);
+        assert_eq!(resume.have, vec![0b1000_0000]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_single_file_resume_path() {
+        let (mut torrent_file, _) = test_torrent(Path::new("/tmp"));
+        torrent_file.Files = vec![];
+        assert_eq!(
+            Resume::path_for(Path::new("/tmp/out.iso"), &torrent_file),
+            PathBuf::from("/tmp/out.iso.resume")
+        );
+    }
+}
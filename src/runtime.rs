@@ -0,0 +1,34 @@
+use lazy_static::lazy_static;
+use std::future::Future;
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinHandle;
+
+/* One pool of threads runs the peer connections, tracker announces and timers of every torrent
+in the process, started the first time something needs it */
+lazy_static! {
+    static ref RUNTIME: Runtime = Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .thread_name("rust-torrent-net")
+        .build()
+        .expect("could not start the network runtime");
+}
+
+fn runtime() -> &'static Runtime {
+    &RUNTIME
+}
+
+/* Run a task on the pool in the background */
+pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    runtime().spawn(future)
+}
+
+/* Wait for a future from blocking code such as the download loop. Sockets and timers it creates
+belong to the pool, so tasks it spawns outlive it. Must not be called from a task. */
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().enter(|| futures::executor::block_on(future))
+}
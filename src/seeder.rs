@@ -0,0 +1,324 @@
+use crate::bitfield::*;
+use crate::choker::Choker;
+use crate::client::*;
+use crate::extension::ExtensionRegistry;
+use crate::message::*;
+use crate::p2p::{Torrent, TransferStats};
+use crate::ratelimit::TorrentLimits;
+use crate::runtime;
+use crate::storage::Storage;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+/* Requests larger than this are treated as abuse and close the connection */
+static MAX_REQUEST_LENGTH: u32 = 131072;
+pub(crate) static DEFAULT_MAX_INBOUND_PEERS: usize = 50;
+
+/* Serves verified pieces from disk, both to peers that connect to us and to the peers we
+download from. The choker decides who gets served. */
+#[derive(Clone)]
+pub struct Seeder {
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+    num_pieces: usize,
+    piece_length: u32,
+    length: u64,
+    stats: Arc<TransferStats>,
+    storage: Arc<Storage>,
+    /* Pieces that have been verified and written, only these are served */
+    have: Arc<Mutex<Bitfield>>,
+    pub(crate) choker: Choker,
+    connections: Arc<AtomicUsize>,
+    pub(crate) max_inbound: usize,
+    limits: TorrentLimits,
+}
+
+impl Seeder {
+    pub fn new(torrent: &Torrent, storage: Arc<Storage>, choker: Choker) -> Seeder {
+        let num_pieces = torrent.piece_hashes.len();
+        Seeder {
+            info_hash: torrent.info_hash.to_vec(),
+            peer_id: torrent.peer_id.to_vec(),
+            num_pieces,
+            piece_length: torrent.piece_length,
+            length: torrent.length,
+            stats: torrent.stats.clone(),
+            storage,
+            have: Arc::new(Mutex::new(vec![0; num_pieces.div_ceil(8)])),
+            choker,
+            connections: Arc::new(AtomicUsize::new(0)),
+            max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+            limits: torrent.limits.clone(),
+        }
+    }
+
+    /* Start serving a piece once it is verified and on disk */
+    pub fn add_piece(&self, index: u32) {
+        mark_piece(&mut self.have.lock().unwrap(), index as usize);
+    }
+
+    fn bitfield(&self) -> Bitfield {
+        self.have.lock().unwrap().to_vec()
+    }
+
+    /* Accept peers on addr in the background and return the address actually bound */
+    pub fn listen(&self, addr: &str) -> Result<SocketAddr> {
+        let mut listener = runtime::block_on(TcpListener::bind(addr))?;
+        let local_addr = listener.local_addr()?;
+        let seeder = self.clone();
+        runtime::spawn(async move {
+            loop {
+                let conn = match listener.accept().await {
+                    Ok((conn, _)) => conn,
+                    Err(_) => continue,
+                };
+                if seeder.connections.load(Ordering::SeqCst) >= seeder.max_inbound {
+                    continue;
+                }
+                seeder.connections.fetch_add(1, Ordering::SeqCst);
+                let seeder_copy = seeder.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = seeder_copy.serve(conn).await {
+                        debug!("Inbound peer disconnected: {}", e);
+                    }
+                    seeder_copy.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /* Tell the peer about pieces that were completed since we last looked */
+    fn send_new_haves(&self, c: &Client, sent: &mut Bitfield) -> Result<()> {
+        let have = self.bitfield();
+        for index in 0..self.num_pieces {
+            if has_piece(&have, index) && !has_piece(sent, index) {
+                c.send_have(index as u32)?;
+                mark_piece(sent, index);
+            }
+        }
+        Ok(())
+    }
+
+    /* Read the block for a request, None if we don't have the piece yet. The disk is read on the
+    blocking pool so a slow disk doesn't hold up other connections. */
+    async fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Option<Vec<u8>>> {
+        let piece_begin = index as u64 * self.piece_length as u64;
+        let piece_end = self.length.min(piece_begin + self.piece_length as u64);
+        if index as usize >= self.num_pieces
+            || length == 0
+            || length > MAX_REQUEST_LENGTH
+            || piece_begin + begin as u64 + length as u64 > piece_end
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid request"));
+        }
+        if !has_piece(&self.have.lock().unwrap(), index as usize) {
+            return Ok(None);
+        }
+        let storage = self.storage.clone();
+        let read = move || storage.read(piece_begin + begin as u64, length as usize);
+        match task::spawn_blocking(read).await {
+            Ok(block) => Ok(Some(block?)),
+            Err(e) => Err(Error::other(e.to_string())),
+        }
+    }
+
+    /* Handle the messages that concern uploading on any connection registered with the choker */
+    pub(crate) async fn handle_message(
+        &self,
+        c: &Client,
+        choker_id: usize,
+        msg: &Message,
+    ) -> Result<()> {
+        if msg.id == MESSAGE_INTERESTED {
+            self.choker.set_interested(choker_id, true);
+        } else if msg.id == MESSAGE_NOT_INTERESTED {
+            self.choker.set_interested(choker_id, false);
+        } else if msg.id == MESSAGE_REQUEST && !self.choker.is_choked(choker_id) {
+            let (index, begin, length) = parse_request(msg)?;
+            if let Some(block) = self.read_block(index, begin, length).await? {
+                self.choker.record_upload(choker_id, block.len() as u64);
+                self.stats
+                    .uploaded
+                    .fetch_add(block.len() as u64, Ordering::Relaxed);
+                c.send_piece(index, begin, &block)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn serve(&self, conn: TcpStream) -> Result<()> {
+        let mut c = accept_client(
+            conn,
+            &self.peer_id,
+            &self.info_hash,
+            ExtensionRegistry::default(),
+        )
+        .await?;
+        c.set_limits(self.limits.connection());
+        let mut sent = self.bitfield();
+        c.send_bitfield(&sent)?;
+        let choker_id = self.choker.register(c.writer());
+        let result = self.serve_messages(&mut c, choker_id, &mut sent).await;
+        self.choker.unregister(choker_id);
+        result
+    }
+
+    async fn serve_messages(
+        &self,
+        c: &mut Client,
+        choker_id: usize,
+        sent: &mut Bitfield,
+    ) -> Result<()> {
+        loop {
+            let msg = c.read().await?;
+            self.send_new_haves(c, sent)?;
+            if msg.id == MESSAGE_BITFIELD {
+                c.bitfield = msg.payload;
+            } else if msg.id == MESSAGE_HAVE {
+                let index = parse_have(&msg)?;
+                mark_piece(&mut c.bitfield, index as usize);
+            } else {
+                self.handle_message(c, choker_id, &msg).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::Peer;
+    use crate::picker::PiecePicker;
+    use crate::resume::Resume;
+    use crate::torrentfile::{sha1_hash, TorrentFile};
+    use crossbeam_channel::never;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    /* A two piece torrent whose data is on disk, with only the first piece marked as served */
+    fn start_seeder(data: &[u8]) -> (Seeder, Peer, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        let stats = Arc::new(TransferStats::new(0));
+        let torrent = Torrent {
+            peers: vec![],
+            peer_id: vec![2; 20],
+            info_hash: vec![7; 20],
+            piece_hashes: vec![sha1_hash(&data[0..16]), sha1_hash(&data[16..])],
+            piece_length: 16,
+            length: data.len() as u64,
+            name: "file.bin".to_string(),
+            stats: stats.clone(),
+            peer_updates: never(),
+            seeder: None,
+            max_peers: 0,
+            limits: TorrentLimits::default(),
+        };
+        let storage = Arc::new(Storage::new(&path, "file.bin", &[], data.len() as u64));
+        let seeder = Seeder::new(&torrent, storage, Choker::new(4, stats));
+        seeder.add_piece(0);
+        let addr = seeder.listen("127.0.0.1:0").unwrap();
+        (seeder, Peer { addr }, path)
+    }
+
+    async fn read_until(c: &mut Client, id: u8) -> Message {
+        loop {
+            let msg = c.read().await.unwrap();
+            if msg.id == id {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn test_serves_verified_blocks() {
+        let data = b"0123456789abcdefghijklmnopqrst";
+        let (seeder, peer, path) = start_seeder(data);
+        runtime::block_on(async {
+            let mut c = new_client(&peer, &[1; 20], &[7; 20]).await.unwrap();
+            assert_eq!(c.bitfield, vec![0b1000_0000]);
+
+            c.send_interested().unwrap();
+            read_until(&mut c, MESSAGE_UNCHOKE).await;
+            c.send_request(&0, &4, &8).unwrap();
+            let piece = read_until(&mut c, MESSAGE_PIECE).await;
+            assert_eq!(piece.payload[8..], data[4..12]);
+            assert_eq!(seeder.stats.uploaded(), 8);
+
+            /* The second piece is announced with HAVE once it is added */
+            seeder.add_piece(1);
+            c.send_request(&1, &0, &14).unwrap();
+            let have = read_until(&mut c, MESSAGE_HAVE).await;
+            assert_eq!(parse_have(&have).unwrap(), 1);
+            let piece = read_until(&mut c, MESSAGE_PIECE).await;
+            assert_eq!(piece.payload[8..], data[16..]);
+        });
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_wrong_info_hash() {
+        let (_seeder, peer, path) = start_seeder(b"0123456789abcdefghijklmnopqrst");
+        assert!(runtime::block_on(new_client(&peer, &[1; 20], &[8; 20])).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_request_closes_connection() {
+        let (_seeder, peer, path) = start_seeder(b"0123456789abcdefghijklmnopqrst");
+        runtime::block_on(async {
+            let mut c = new_client(&peer, &[1; 20], &[7; 20]).await.unwrap();
+            c.send_interested().unwrap();
+            read_until(&mut c, MESSAGE_UNCHOKE).await;
+            c.send_request(&0, &8, &16).unwrap();
+            assert!(c.read().await.is_err());
+        });
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_download_from_seeder() {
+        let data = b"0123456789abcdefghijklmnopqrst";
+        let (seeder, peer, path) = start_seeder(data);
+        seeder.add_piece(1);
+        let out = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let torrent_file = TorrentFile {
+            InfoHash: vec![7; 20],
+            PieceHashes: vec![sha1_hash(&data[0..16]), sha1_hash(&data[16..])],
+            PieceLength: 16,
+            Length: data.len() as u64,
+            Name: "file.bin".to_string(),
+            ..TorrentFile::default()
+        };
+        let storage = Storage::new(&out, "file.bin", &[], data.len() as u64);
+        storage.allocate().unwrap();
+        let resume_path = Resume::path_for(&out, &torrent_file);
+        let mut resume = Resume::load_or_recheck(resume_path.clone(), &torrent_file, &storage);
+        let mut torrent = Torrent {
+            peers: vec![peer],
+            peer_id: vec![3; 20],
+            info_hash: vec![7; 20],
+            piece_hashes: torrent_file.PieceHashes.to_vec(),
+            piece_length: 16,
+            length: data.len() as u64,
+            name: "file.bin".to_string(),
+            stats: Arc::new(TransferStats::new(data.len() as u64)),
+            peer_updates: never(),
+            seeder: None,
+            max_peers: 1,
+            limits: TorrentLimits::default(),
+        };
+        let picker = PiecePicker::new(2, |index| resume.has_piece(index));
+        torrent.download(&storage, &mut resume, &picker).unwrap();
+        assert_eq!(fs::read(&out).unwrap(), data);
+        assert_eq!(torrent.stats.left(), 0);
+        for f in [path, out, resume_path] {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
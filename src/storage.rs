@@ -0,0 +1,376 @@
+use crate::p2p::{file_spans, FileSpan};
+use crate::torrentfile::FileEntry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/* Copying a file's data out of the parts file is done in chunks of this size */
+static COPY_CHUNK: u64 = 1024 * 1024;
+/* Handles kept open for writing, enough for the files the pieces in flight fall into */
+static MAX_OPEN_FILES: usize = 16;
+
+/* Write handles by file index, None standing for the parts file. The least recently used one is
+closed when a new file has to be opened. */
+#[derive(Default)]
+struct OpenFiles {
+    /* Most recently used last */
+    handles: Vec<(Option<usize>, File)>,
+}
+
+impl OpenFiles {
+    fn get(&mut self, key: Option<usize>, path: &Path) -> Result<&mut File> {
+        match self.handles.iter().position(|(open, _)| *open == key) {
+            Some(position) => {
+                let handle = self.handles.remove(position);
+                self.handles.push(handle);
+            }
+            None => {
+                /* Files are created by allocate, only the parts file appears on first use */
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(key.is_none())
+                    .truncate(false)
+                    .open(path)?;
+                if self.handles.len() == MAX_OPEN_FILES {
+                    self.handles.remove(0);
+                }
+                self.handles.push((key, file));
+            }
+        }
+        Ok(&mut self.handles.last_mut().unwrap().1)
+    }
+}
+
+/* Where a torrent's bytes live on disk. Single file torrents are one file at the destination
+path, multi-file torrents are the file tree under destination/name. Files that aren't selected
+are never created, the parts of their data that share a piece with a selected file are kept in
+destination/name.parts at their torrent offset instead. */
+pub struct Storage {
+    files: Vec<FileEntry>,
+    paths: Vec<PathBuf>,
+    parts: PathBuf,
+    /* Whether each file exists on disk or has its data in the parts file. Writers hold the read
+    lock so a file is never moved out of the parts file halfway through a write. */
+    materialised: RwLock<Vec<bool>>,
+    open_files: Mutex<OpenFiles>,
+}
+
+impl Storage {
+    pub fn new(path: &Path, name: &str, files: &[FileEntry], length: u64) -> Storage {
+        if files.is_empty() {
+            let mut parts = path.as_os_str().to_owned();
+            parts.push(".parts");
+            return Storage {
+                files: vec![FileEntry {
+                    Path: vec![name.to_string()],
+                    Length: length,
+                }],
+                paths: vec![path.to_path_buf()],
+                parts: PathBuf::from(parts),
+                materialised: RwLock::new(vec![true]),
+                open_files: Mutex::default(),
+            };
+        }
+        let root = path.join(name);
+        Storage {
+            files: files.to_vec(),
+            paths: files
+                .iter()
+                .map(|f| f.Path.iter().fold(root.to_path_buf(), |p, c| p.join(c)))
+                .collect(),
+            parts: path.join(format!("{}.parts", name)),
+            materialised: RwLock::new(vec![true; files.len()]),
+            open_files: Mutex::default(),
+        }
+    }
+
+    /* Choose the files that are created on disk, before allocating. Files that already exist
+    stay where they are. */
+    pub fn select(&self, selected: &[bool]) {
+        let mut materialised = self.materialised.write().unwrap();
+        for (index, path) in self.paths.iter().enumerate() {
+            materialised[index] = selected[index] || path.exists();
+        }
+    }
+
+    /* Create a file that wasn't selected so far and move its data out of the parts file */
+    pub fn materialise(&self, index: usize) -> Result<()> {
+        let mut materialised = self.materialised.write().unwrap();
+        if materialised[index] {
+            return Ok(());
+        }
+        let file = self.create_file(index)?;
+        let begin: u64 = self.files[..index].iter().map(|f| f.Length).sum();
+        let available = fs::metadata(&self.parts).map_or(0, |m| m.len());
+        let mut copied = 0;
+        while copied < self.files[index].Length && begin + copied < available {
+            let length = COPY_CHUNK
+                .min(self.files[index].Length - copied)
+                .min(available - begin - copied);
+            let mut buf = vec![0; length as usize];
+            let mut parts = File::open(&self.parts)?;
+            parts.seek(SeekFrom::Start(begin + copied))?;
+            parts.read_exact(&mut buf)?;
+            (&file).seek(SeekFrom::Start(copied))?;
+            (&file).write_all(&buf)?;
+            copied += length;
+        }
+        materialised[index] = true;
+        Ok(())
+    }
+
+    pub(crate) fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub(crate) fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /* The files holding data of this torrent, the parts file included once it exists */
+    pub(crate) fn data_paths(&self) -> Vec<PathBuf> {
+        let materialised = self.materialised.read().unwrap();
+        let mut paths: Vec<PathBuf> = (0..self.paths.len())
+            .filter(|&index| materialised[index])
+            .map(|index| self.paths[index].to_path_buf())
+            .collect();
+        if self.parts.exists() {
+            paths.push(self.parts.to_path_buf());
+        }
+        paths
+    }
+
+    /* The file and offset a span is stored at */
+    fn location(&self, materialised: &[bool], span: &FileSpan) -> (&Path, u64) {
+        if materialised[span.file_index] {
+            (&self.paths[span.file_index], span.file_offset)
+        } else {
+            (&self.parts, span.torrent_offset)
+        }
+    }
+
+    fn create_file(&self, index: usize) -> Result<File> {
+        if let Some(parent) = self.paths[index].parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.paths[index])?;
+        if file.metadata()?.len() != self.files[index].Length {
+            file.set_len(self.files[index].Length)?;
+        }
+        Ok(file)
+    }
+
+    fn check_bounds(&self, offset: u64, length: usize) -> Result<()> {
+        let total: u64 = self.files.iter().map(|f| f.Length).sum();
+        if offset + length as u64 > total {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "access past the end of the torrent",
+            ));
+        }
+        Ok(())
+    }
+
+    /* Create every selected file at its full size before the download starts. set_len leaves the
+    files sparse on filesystems that support it and keeps data that is already there. */
+    pub fn allocate(&self) -> Result<()> {
+        let materialised = self.materialised.read().unwrap();
+        for index in 0..self.files.len() {
+            if materialised[index] {
+                self.create_file(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    /* Write data at a torrent offset, crossing file boundaries as needed */
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.check_bounds(offset, data.len())?;
+        let materialised = self.materialised.read().unwrap();
+        let mut open_files = self.open_files.lock().unwrap();
+        for span in file_spans(&self.files, offset, offset + data.len() as u64) {
+            let (path, position) = self.location(&materialised, &span);
+            let key = Some(span.file_index).filter(|&index| materialised[index]);
+            let file = open_files.get(key, path)?;
+            file.seek(SeekFrom::Start(position))?;
+            let start = (span.torrent_offset - offset) as usize;
+            file.write_all(&data[start..start + span.length as usize])?;
+        }
+        Ok(())
+    }
+
+    /* Read length bytes starting at a torrent offset, crossing file boundaries as needed */
+    pub fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>> {
+        self.check_bounds(offset, length)?;
+        let mut buf = vec![0; length];
+        let materialised = self.materialised.read().unwrap();
+        for span in file_spans(&self.files, offset, offset + length as u64) {
+            let (path, position) = self.location(&materialised, &span);
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(position))?;
+            let start = (span.torrent_offset - offset) as usize;
+            file.read_exact(&mut buf[start..start + span.length as usize])?;
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_across_files() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        fs::create_dir_all(root.join("multi").join("sub")).unwrap();
+        File::create(root.join("multi").join("a.txt"))
+            .unwrap()
+            .write_all(b"hello")
+            .unwrap();
+        File::create(root.join("multi").join("sub").join("b.txt"))
+            .unwrap()
+            .write_all(b" world!")
+            .unwrap();
+        let files = vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["sub".to_string(), "b.txt".to_string()],
+                Length: 7,
+            },
+        ];
+        let storage = Storage::new(&root, "multi", &files, 12);
+        assert_eq!(storage.read(3, 6).unwrap(), b"lo wor");
+        assert_eq!(storage.read(0, 12).unwrap(), b"hello world!");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_read_single_file() {
+        let path = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"0123456789")
+            .unwrap();
+        let storage = Storage::new(&path, "ignored.bin", &[], 10);
+        assert_eq!(storage.read(8, 2).unwrap(), b"89");
+        assert!(storage.read(8, 4).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_allocate_and_write_across_files() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let files = vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["empty".to_string()],
+                Length: 0,
+            },
+            FileEntry {
+                Path: vec!["sub".to_string(), "b.txt".to_string()],
+                Length: 7,
+            },
+        ];
+        let storage = Storage::new(&root, "multi", &files, 12);
+        storage.allocate().unwrap();
+        let dir = root.join("multi");
+        assert_eq!(fs::metadata(dir.join("a.txt")).unwrap().len(), 5);
+        assert_eq!(fs::metadata(dir.join("empty")).unwrap().len(), 0);
+        assert_eq!(
+            fs::metadata(dir.join("sub").join("b.txt")).unwrap().len(),
+            7
+        );
+
+        /* Pieces arrive out of order */
+        storage.write(8, b"rld?").unwrap();
+        storage.write(0, b"hell").unwrap();
+        storage.write(4, b"o wo").unwrap();
+        storage.write(11, b"!").unwrap();
+        assert!(storage.write(10, b"abc").is_err());
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dir.join("sub").join("b.txt")).unwrap(), b" world!");
+
+        /* Allocating again keeps what was written */
+        storage.allocate().unwrap();
+        assert_eq!(storage.read(0, 12).unwrap(), b"hello world!");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_unselected_files_go_to_parts() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let files = vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["b.txt".to_string()],
+                Length: 7,
+            },
+        ];
+        let storage = Storage::new(&root, "multi", &files, 12);
+        storage.select(&[false, true]);
+        storage.allocate().unwrap();
+        let dir = root.join("multi");
+        assert!(!dir.join("a.txt").exists());
+        assert_eq!(storage.data_paths(), vec![dir.join("b.txt")]);
+
+        /* The boundary piece is split between the parts file and b.txt */
+        storage.write(4, b"o wo").unwrap();
+        assert_eq!(storage.read(4, 4).unwrap(), b"o wo");
+        assert_eq!(fs::read(dir.join("b.txt")).unwrap(), b" wo\0\0\0\0");
+        assert_eq!(fs::read(root.join("multi.parts")).unwrap(), b"\0\0\0\0o");
+        assert_eq!(storage.data_paths().len(), 2);
+
+        /* Selecting a.txt later moves its data out of the parts file */
+        storage.materialise(0).unwrap();
+        storage.write(0, b"hell").unwrap();
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(storage.read(0, 8).unwrap(), b"hello wo");
+
+        /* A new Storage picks up the file that exists now */
+        let storage = Storage::new(&root, "multi", &files, 12);
+        storage.select(&[false, true]);
+        assert_eq!(storage.read(0, 5).unwrap(), b"hello");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_write_handles_are_reused() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let files: Vec<FileEntry> = (0..MAX_OPEN_FILES + 2)
+            .map(|index| FileEntry {
+                Path: vec![format!("{}.bin", index)],
+                Length: 1,
+            })
+            .collect();
+        let storage = Storage::new(&root, "many", &files, files.len() as u64);
+        storage.allocate().unwrap();
+        storage.write(0, b"ab").unwrap();
+        let first = storage.open_files.lock().unwrap().handles.len();
+        storage.write(1, b"c").unwrap();
+        assert_eq!(storage.open_files.lock().unwrap().handles.len(), first);
+
+        /* Only the most recently written files stay open */
+        let data = vec![b'x'; files.len()];
+        storage.write(0, &data).unwrap();
+        let open_files = storage.open_files.lock().unwrap();
+        assert_eq!(open_files.handles.len(), MAX_OPEN_FILES);
+        assert_eq!(open_files.handles.last().unwrap().0, Some(files.len() - 1));
+        drop(open_files);
+        assert_eq!(storage.read(0, files.len()).unwrap(), data);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -4,25 +4,134 @@ extern crate crypto;
 extern crate hex;
 extern crate serde_bencode;
 extern crate serde_bytes;
+use crate::bencode;
+use crate::choker::{Choker, DEFAULT_UNCHOKE_SLOTS};
+use crate::control::{self, Controls};
+use crate::create::CreateOptions;
 use crate::p2p::*;
+use crate::peers::Peer;
+use crate::picker::PiecePicker;
+use crate::priority::{FilePriorities, FileSelection, Priority};
+use crate::ratelimit::{LimitOptions, TorrentLimits};
+use crate::resume::Resume;
+use crate::runtime;
+use crate::seeder::{Seeder, DEFAULT_MAX_INBOUND_PEERS};
+use crate::storage::Storage;
+use crate::tracker::TrackerSession;
+use crossbeam_channel::unbounded;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use rand::seq::SliceRandom;
 use serde_bencode::{de, ser};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal;
 
-static DEFAULT_PORT: u16 = 6881;
+pub(crate) static DEFAULT_PORT: u16 = 6881;
 
-#[derive(Debug, Deserialize, Serialize)]
+/* Settings for a download that don't come from the torrent itself */
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /* Keep serving the torrent once it is complete */
+    pub seed: bool,
+    /* Peers we upload to at once, including the optimistic unchoke */
+    pub unchoke_slots: usize,
+    /* Where incoming peers connect and what trackers are told */
+    pub port: u16,
+    pub max_peers: usize,
+    pub max_inbound: usize,
+    pub limits: LimitOptions,
+    /* Fetch pieces in order with this many pieces of read-ahead, None for rarest first */
+    pub sequential: Option<usize>,
+    pub files: FileSelection,
+    /* Take commands such as seek from standard input while running */
+    pub commands: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            seed: false,
+            unchoke_slots: DEFAULT_UNCHOKE_SLOTS,
+            port: DEFAULT_PORT,
+            max_peers: DEFAULT_MAX_PEERS,
+            max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+            limits: LimitOptions::default(),
+            sequential: None,
+            files: FileSelection::default(),
+            commands: false,
+        }
+    }
+}
+
+/* Where a download is written */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Destination {
+    /* The output file of a single file torrent, or the parent of a multi-file torrent's folder */
+    Path(String),
+    /* A directory the torrent is saved into under its own name */
+    Dir(String),
+}
+
+impl Destination {
+    pub fn path_for(&self, torrent_file: &TorrentFile) -> String {
+        match self {
+            Destination::Path(path) => path.to_owned(),
+            Destination::Dir(dir) if torrent_file.Files.is_empty() => Path::new(dir)
+                .join(&torrent_file.Name)
+                .to_string_lossy()
+                .into_owned(),
+            Destination::Dir(dir) => dir.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TorrentFile {
     pub(crate) Announce: String,
+    /* Tiers of backup trackers from announce-list (BEP 12), empty when only announce is given */
+    #[serde(default)]
+    pub(crate) AnnounceList: Vec<Vec<String>>,
     pub(crate) InfoHash: Vec<u8>,
     pub(crate) PieceHashes: Vec<Vec<u8>>,
     pub(crate) PieceLength: u32,
-    pub(crate) Length: u32,
+    pub(crate) Length: u64,
     pub(crate) Name: String,
+    /* Empty for single file torrents, otherwise the files under the Name directory in torrent order */
+    #[serde(default)]
+    pub(crate) Files: Vec<FileEntry>,
+    /* BEP 19 web seed urls */
+    #[serde(default)]
+    pub(crate) WebSeeds: Vec<String>,
+    #[serde(default)]
+    pub(crate) Private: bool,
+    /* Seconds since the unix epoch */
+    #[serde(default)]
+    pub(crate) CreationDate: Option<i64>,
+    #[serde(default)]
+    pub(crate) Comment: Option<String>,
+    #[serde(default)]
+    pub(crate) CreatedBy: Option<String>,
+    /* tracker id handed out by each announce url, echoed on later announces */
+    #[serde(skip)]
+    pub(crate) TrackerIds: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct FileEntry {
+    pub(crate) Path: Vec<String>,
+    pub(crate) Length: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BencodeFile {
+    length: u64,
+    path: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -31,10 +140,15 @@ pub struct BencodeInfo {
     pieces: ByteBuf,
     #[serde(rename = "piece length")]
     piecelength: u32,
-    #[serde(default)]
-    length: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    length: u64,
     #[serde(default)]
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<BencodeFile>>,
+    /* BEP 27, peers only come from the torrent's trackers */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
 }
 
 /* Struct for recieving results of a bencode deserialize */
@@ -42,56 +156,222 @@ pub struct BencodeInfo {
 pub struct BencodeTorrent {
     #[serde(default)]
     pub announce: String,
-    info: BencodeInfo,
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(
+        default,
+        rename = "created by",
+        skip_serializing_if = "Option::is_none"
+    )]
+    created_by: Option<String>,
+    #[serde(
+        default,
+        rename = "creation date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    creation_date: Option<i64>,
+    /* BEP 19 web seeds, a single url or a list of them */
+    #[serde(default, rename = "url-list", skip_serializing_if = "Option::is_none")]
+    url_list: Option<UrlList>,
+    pub(crate) info: BencodeInfo,
 }
 
-/* Deserialize bencoded file into BencodeTorrent object. */
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/* Read a bencoded metainfo file from disk and parse it into a TorrentFile */
 pub fn open(path: String) -> Result<TorrentFile, Error> {
-    let mut outfile = match File::open(path) {
-        Ok(outfile) => outfile,
-        Err(err) => return Err(err),
-    };
+    let mut outfile = File::open(path)?;
     let mut buffer: Vec<u8> = Vec::new();
-    outfile.read_to_end(&mut buffer).unwrap();
-    let mut bto = de::from_bytes::<BencodeTorrent>(&buffer).unwrap();
-    return bto.to_torrent_file();
+    outfile.read_to_end(&mut buffer)?;
+    from_bytes(&buffer)
+}
+
+/* Deserialize bencoded metainfo into BencodeTorrent object. The info-hash is taken over the
+info dictionary exactly as it appears in buf, so keys we don't model are still covered. */
+pub fn from_bytes(buf: &[u8]) -> Result<TorrentFile, Error> {
+    let bto = match de::from_bytes::<BencodeTorrent>(buf) {
+        Ok(bto) => bto,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    };
+    let (begin, end) = bencode::dict_value_span(buf, b"info")?;
+    bto.to_torrent_file(sha1_hash(&buf[begin..end]))
+}
+
+/* Build a TorrentFile from a bare info dictionary, as received from peers for magnet links */
+pub fn from_info_bytes(announce: String, info: &[u8]) -> Result<TorrentFile, Error> {
+    let info_dict = match de::from_bytes::<BencodeInfo>(info) {
+        Ok(info_dict) => info_dict,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    };
+    let bto = BencodeTorrent {
+        announce,
+        announce_list: vec![],
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        url_list: None,
+        info: info_dict,
+    };
+    bto.to_torrent_file(sha1_hash(info))
+}
+
+pub(crate) fn generate_peer_id() -> Vec<u8> {
+    let mut peerid: Vec<u8> = vec![0; 20];
+    for x in peerid.iter_mut() {
+        *x = rand::random()
+    }
+    peerid
+}
+
+pub(crate) fn sha1_hash(buf: &[u8]) -> Vec<u8> {
+    let mut h = Sha1::new();
+    h.input(buf);
+    hex::decode(h.result_str()).unwrap()
+}
+
+/* Reject path components that would escape the torrent's root directory */
+fn check_path_component(component: &str) -> Result<(), Error> {
+    if component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains('/')
+        || component.contains('\\')
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid path component {:?}", component),
+        ));
+    }
+    Ok(())
 }
 
 impl BencodeTorrent {
+    /* Metainfo for a torrent we are creating */
+    pub fn new(info: BencodeInfo, options: &CreateOptions) -> BencodeTorrent {
+        let announce = options
+            .announce_list
+            .iter()
+            .flatten()
+            .next()
+            .cloned()
+            .unwrap_or_default();
+        /* A single tracker needs no announce-list */
+        let announce_list = if options.announce_list.iter().flatten().count() > 1 {
+            options.announce_list.to_vec()
+        } else {
+            vec![]
+        };
+        BencodeTorrent {
+            announce,
+            announce_list,
+            comment: options.comment.to_owned(),
+            created_by: options.created_by.to_owned(),
+            creation_date: options.creation_date,
+            url_list: match options.web_seeds.len() {
+                0 => None,
+                _ => Some(UrlList::Many(options.web_seeds.to_vec())),
+            },
+            info,
+        }
+    }
 
     /* Convert BencodeTorrent to more useable struct TorrentFile */
-    pub fn to_torrent_file(&mut self) -> Result<TorrentFile, Error> {
-        let info_hash = self.info.hash();
-        let piece_hashes = match self.info.split_piece_hashes() {
-            Ok(piece_hashes) => piece_hashes,
-            Err(err) => return Err(err),
+    pub fn to_torrent_file(&self, info_hash: Vec<u8>) -> Result<TorrentFile, Error> {
+        let piece_hashes = self.info.split_piece_hashes()?;
+        let files = self.info.file_entries()?;
+        let length = if files.is_empty() {
+            self.info.length
+        } else {
+            files.iter().map(|f| f.Length).sum()
         };
-        let t = TorrentFile {
+        /* Trackers within a tier are tried in random order (BEP 12) */
+        let mut announce_list = self.announce_list.to_owned();
+        announce_list.retain(|tier| !tier.is_empty());
+        for tier in announce_list.iter_mut() {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        Ok(TorrentFile {
             Announce: self.announce.to_owned(),
+            AnnounceList: announce_list,
             InfoHash: info_hash,
             PieceHashes: piece_hashes,
             PieceLength: self.info.piecelength,
-            Length: self.info.length,
+            Length: length,
             Name: self.info.name.to_owned(),
-        };
-        return Ok(t);
+            Files: files,
+            WebSeeds: match &self.url_list {
+                Some(UrlList::One(url)) => vec![url.to_owned()],
+                Some(UrlList::Many(urls)) => urls.to_vec(),
+                None => vec![],
+            },
+            Private: self.info.private == Some(1),
+            CreationDate: self.creation_date,
+            Comment: self.comment.to_owned(),
+            CreatedBy: self.created_by.to_owned(),
+            TrackerIds: HashMap::new(),
+        })
     }
 }
 impl BencodeInfo {
-    pub fn hash(&mut self) -> Vec<u8> {
+    /* Info dictionary for a torrent we are creating, single file when files is empty */
+    pub fn new(
+        name: String,
+        piecelength: u32,
+        pieces: ByteBuf,
+        length: u64,
+        files: &[FileEntry],
+        private: bool,
+    ) -> BencodeInfo {
+        let (length, files) = if files.is_empty() {
+            (length, None)
+        } else {
+            let files = files
+                .iter()
+                .map(|f| BencodeFile {
+                    length: f.Length,
+                    path: f.Path.to_vec(),
+                })
+                .collect();
+            (0, Some(files))
+        };
+        BencodeInfo {
+            pieces,
+            piecelength,
+            length,
+            name,
+            files,
+            private: if private { Some(1) } else { None },
+        }
+    }
+
+    /* Hash of the re-serialized struct, only correct for info dictionaries we built ourselves */
+    #[allow(dead_code)]
+    pub fn hash(&self) -> Vec<u8> {
         let buffer: Vec<u8> = ser::to_bytes::<BencodeInfo>(self).unwrap();
-        let mut h = Sha1::new();
-        h.input(&buffer);
-        let result: Vec<u8> = hex::decode(h.result_str()).unwrap();
-        result
+        sha1_hash(&buffer)
     }
 
     /* Convert piece hashes from bytebuffer to Vec of Vec for ergonomics */
-    pub fn split_piece_hashes(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+    pub fn split_piece_hashes(&self) -> Result<Vec<Vec<u8>>, Error> {
         let hash_length = 20; //length of sha1 hash
         let buffer = self.pieces.to_owned();
         if buffer.len() % hash_length != 0 {
-            let err = Error::new(ErrorKind::Other, "oh no!");
+            let err = Error::other("oh no!");
             return Err(err);
         }
         let num_hashes = buffer.len() / hash_length;
@@ -101,20 +381,96 @@ impl BencodeInfo {
         }
         Ok(hashes)
     }
+
+    /* Validate the files list of a multi-file torrent, empty for single file torrents */
+    pub fn file_entries(&self) -> Result<Vec<FileEntry>, Error> {
+        let files = match &self.files {
+            Some(files) => files,
+            None => return Ok(vec![]),
+        };
+        check_path_component(&self.name)?;
+        let mut entries = Vec::with_capacity(files.len());
+        for f in files {
+            if f.path.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidData, "empty file path"));
+            }
+            for component in &f.path {
+                check_path_component(component)?;
+            }
+            entries.push(FileEntry {
+                Path: f.path.to_owned(),
+                Length: f.length,
+            });
+        }
+        Ok(entries)
+    }
 }
 impl TorrentFile {
+    /* Download the torrent to path, which is the output file for single file torrents
+    and the parent of the Name directory for multi-file torrents. */
+    pub fn download_to_file(
+        &mut self,
+        path: String,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let peerid = generate_peer_id();
+        self.download_from_peers(path, peerid, vec![], options)
+    }
 
-    /*  */
-    pub fn download_to_file(&mut self, path: String) -> Result<(), Error> {
-        let mut peerid: Vec<u8> = vec![0; 20];
-
-        for x in peerid.iter_mut() {
-            *x = rand::random()
+    /* Same as download_to_file but starting with peers that have already been discovered. The
+    trackers are kept informed through a TrackerSession for the duration of the download. */
+    pub(crate) fn download_from_peers(
+        &mut self,
+        path: String,
+        peerid: Vec<u8>,
+        mut peers: Vec<Peer>,
+        options: &DownloadOptions,
+    ) -> Result<(), Error> {
+        let storage = Arc::new(Storage::new(
+            Path::new(&path),
+            &self.Name,
+            &self.Files,
+            self.Length,
+        ));
+        let priorities = options.files.resolve(storage.files().len());
+        let selected: Vec<bool> = priorities.iter().map(|&p| p != Priority::Skip).collect();
+        storage.select(&selected);
+        let resume_path = Resume::path_for(Path::new(&path), self);
+        let mut resume = Resume::load_or_recheck(resume_path, self, &storage);
+        storage.allocate()?;
+        resume.save(&storage)?;
+        let mut left = self.Length;
+        for index in 0..self.PieceHashes.len() {
+            if resume.has_piece(index) {
+                let begin = index as u64 * self.PieceLength as u64;
+                left -= self.Length.min(begin + self.PieceLength as u64) - begin;
+            }
         }
-
-        let peers = match self.request_peers(peerid.to_vec(), DEFAULT_PORT) {
-            Ok(peers) => peers,
-            Err(err) => return Err(err),
+        let stats = Arc::new(TransferStats::new(left));
+        let (peer_tx, peer_updates) = unbounded();
+        let session = match TrackerSession::start(
+            self.clone(),
+            peerid.to_vec(),
+            options.port,
+            stats.clone(),
+            peer_tx,
+        ) {
+            Ok((session, found)) => {
+                for peer in found {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                Some(session)
+            }
+            Err(e) if peers.is_empty() => return Err(e),
+            Err(e) => {
+                info!(
+                    "Tracker announce failed, continuing with known peers: {}",
+                    e
+                );
+                None
+            }
         };
 
         let mut torrent = Torrent {
@@ -125,52 +481,130 @@ impl TorrentFile {
             piece_length: self.PieceLength,
             length: self.Length,
             name: self.Name.to_string(),
+            stats: stats.clone(),
+            peer_updates,
+            seeder: None,
+            max_peers: options.max_peers,
+            limits: TorrentLimits::new(&options.limits),
         };
 
-        let buf = match torrent.download() {
-            Ok(buf) => buf,
-            Err(err) => return Err(err),
-        };
-
-        let mut outfile = match File::create(path) {
-            Ok(outfile) => outfile,
-            Err(err) => return Err(err),
-        };
+        let choker = Choker::new(options.unchoke_slots, stats);
+        choker.start();
+        let mut seeder = Seeder::new(&torrent, storage.clone(), choker);
+        seeder.max_inbound = options.max_inbound;
+        torrent.seeder = Some(seeder.clone());
+        for index in 0..self.PieceHashes.len() {
+            if resume.has_piece(index) {
+                seeder.add_piece(index as u32);
+            }
+        }
+        let listening = seeder
+            .listen(&format!("[::]:{}", options.port))
+            .or_else(|_| seeder.listen(&format!("0.0.0.0:{}", options.port)));
+        if let Err(e) = &listening {
+            info!("Not accepting incoming peers: {}", e);
+        }
 
-        match outfile.write(&buf) {
-            Ok(_) => return Ok(()),
-            Err(err) => Err(err),
+        let picker = PiecePicker::new(self.PieceHashes.len(), |index| resume.has_piece(index));
+        picker.set_sequential(options.sequential);
+        let priorities = FilePriorities::new(
+            storage.clone(),
+            picker.clone(),
+            self.PieceLength,
+            priorities,
+        );
+        if options.commands {
+            control::read_commands(Controls {
+                picker: picker.clone(),
+                piece_length: self.PieceLength,
+                priorities,
+                limits: torrent.limits.clone(),
+            });
+        }
+        torrent.download(&storage, &mut resume, &picker)?;
+        /* Only when this run fetched the last piece, not for data that was complete already or
+        files left out of the selection */
+        if left > 0 && torrent.stats.left() == 0 {
+            if let Some(session) = &session {
+                session.completed();
+            }
         }
+        if options.seed && listening.is_ok() {
+            info!("Download complete, seeding {} until interrupted", self.Name);
+            runtime::block_on(signal::ctrl_c())?;
+            info!("Stopping");
+        }
+        /* Dropping the session sends stopped */
+        drop(session);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    // #[test]
-    // fn test_open() {
-    //     let torrent = open(String::from("C:/Users/Bernardo/git-repo/rust-torrent/src/testdata/archlinux-2019.12.01-x86_64.iso.torrent")).unwrap();
-    //     let mut file = File::open(String::from("C:/Users/Bernardo/git-repo/rust-torrent/src/testdata/archlinux-2019.12.01-x86_64.iso.torrent.golden.json")).unwrap();
-    //     let mut golden = String::new();
-    //     file.read_to_string(&mut golden).unwrap();
-    //     let expected: TorrentFile = serde_json::from_str(&golden).unwrap();
-    //     assert_eq!(format!("{:?}", expected), format!("{:?}", torrent));
-    // }
+    #[test]
+    fn test_open() {
+        let torrent = open(String::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/testdata/archlinux-2019.12.01-x86_64.iso.torrent"
+        )))
+        .unwrap();
+        let mut file = File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/testdata/archlinux-2019.12.01-x86_64.iso.torrent.golden.json"
+        ))
+        .unwrap();
+        let mut golden = String::new();
+        file.read_to_string(&mut golden).unwrap();
+        let expected: TorrentFile = serde_json::from_str(&golden).unwrap();
+        assert_eq!(format!("{:?}", expected), format!("{:?}", torrent));
+    }
+
+    #[test]
+    fn test_from_bytes_hashes_unmodeled_info_keys() {
+        let info: &[u8] = b"d6:lengthi1000e4:name8:file.bin12:piece lengthi16384e6:pieces20:1234567890abcdefghij7:privatei1e6:source8:internale";
+        let mut encoded = b"d8:announce30:http://tracker.example.org/ann4:info".to_vec();
+        encoded.extend_from_slice(info);
+        encoded.push(b'e');
+        let result = from_bytes(&encoded).unwrap();
+        assert_eq!(result.InfoHash, sha1_hash(info));
+
+        let reserialized = de::from_bytes::<BencodeTorrent>(&encoded)
+            .unwrap()
+            .info
+            .hash();
+        assert_ne!(result.InfoHash, reserialized);
+    }
+
+    #[test]
+    fn test_from_bytes_missing_info() {
+        assert!(from_bytes(b"d8:announce30:http://tracker.example.org/anne").is_err());
+        assert!(from_bytes(b"not bencode").is_err());
+    }
 
     #[test]
     fn test_to_torrent_file_correct_conversion() {
-        let mut input = BencodeTorrent {
+        let input = BencodeTorrent {
             announce: "http://bttracker.debian.org:6969/announce".to_string(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
             info: BencodeInfo {
                 pieces: ByteBuf::from("1234567890abcdefghijabcdefghij1234567890"),
                 piecelength: 262144,
                 length: 351272960,
                 name: "debian-10.2.0-amd64-netinst.iso".to_string(),
+                private: None,
+                files: None,
             },
         };
 
         let output = TorrentFile {
             Announce: "http://bttracker.debian.org:6969/announce".to_string(),
+            AnnounceList: vec![],
             InfoHash: vec![
                 216, 247, 57, 206, 195, 40, 149, 108, 204, 91, 191, 31, 134, 217, 253, 207, 219,
                 168, 206, 182,
@@ -188,26 +622,168 @@ mod tests {
             PieceLength: 262144,
             Length: 351272960,
             Name: "debian-10.2.0-amd64-netinst.iso".to_string(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
         };
 
-        let result = input.to_torrent_file().unwrap();
+        let result = input.to_torrent_file(input.info.hash()).unwrap();
         assert_eq!(format!("{:?}", result), format!("{:?}", output));
     }
 
     #[test]
     fn test_to_torrent_file_insufficient_bytes() {
-        let mut input = BencodeTorrent {
+        let input = BencodeTorrent {
             announce: "http://bttracker.debian.org:6969/announce".to_string(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
             info: BencodeInfo {
                 pieces: ByteBuf::from("1234567890abcdefghijabcdef"),
                 piecelength: 262144,
                 length: 351272960,
                 name: "debian-10.2.0-amd64-netinst.iso".to_string(),
+                private: None,
+                files: None,
             },
         };
-        match input.to_torrent_file() {
+        match input.to_torrent_file(input.info.hash()) {
             Ok(_) => assert_eq!(1, 0),
             Err(_) => assert_eq!(1, 1),
         }
     }
+
+    #[test]
+    fn test_to_torrent_file_multi_file() {
+        let input = BencodeTorrent {
+            announce: "http://tracker.example.org:6969/announce".to_string(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info: BencodeInfo {
+                pieces: ByteBuf::from("1234567890abcdefghij"),
+                piecelength: 262144,
+                length: 0,
+                name: "dataset".to_string(),
+                private: None,
+                files: Some(vec![
+                    BencodeFile {
+                        length: 1000,
+                        path: vec!["README".to_string()],
+                    },
+                    BencodeFile {
+                        length: 2000,
+                        path: vec!["data".to_string(), "part1.csv".to_string()],
+                    },
+                ]),
+            },
+        };
+        let result = input.to_torrent_file(input.info.hash()).unwrap();
+        assert_eq!(result.Length, 3000);
+        assert_eq!(
+            result.Files,
+            vec![
+                FileEntry {
+                    Path: vec!["README".to_string()],
+                    Length: 1000,
+                },
+                FileEntry {
+                    Path: vec!["data".to_string(), "part1.csv".to_string()],
+                    Length: 2000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_torrent_file_rejects_path_traversal() {
+        let input = BencodeTorrent {
+            announce: "http://tracker.example.org:6969/announce".to_string(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info: BencodeInfo {
+                pieces: ByteBuf::from("1234567890abcdefghij"),
+                piecelength: 262144,
+                length: 0,
+                name: "dataset".to_string(),
+                private: None,
+                files: Some(vec![BencodeFile {
+                    length: 1000,
+                    path: vec!["..".to_string(), "passwd".to_string()],
+                }]),
+            },
+        };
+        assert!(input.to_torrent_file(input.info.hash()).is_err());
+    }
+
+    #[test]
+    fn test_from_bytes_announce_list() {
+        let encoded = b"d8:announce23:http://primary/announce13:announce-listll23:http://primary/announceel23:http://backup1/announce22:udp://backup2:1337/annee4:infod6:lengthi1000e4:name8:file.bin12:piece lengthi16384e6:pieces20:1234567890abcdefghijee";
+        let result = from_bytes(encoded).unwrap();
+        assert_eq!(result.Announce, "http://primary/announce");
+        assert_eq!(result.AnnounceList.len(), 2);
+        assert_eq!(result.AnnounceList[0], vec!["http://primary/announce"]);
+        let mut backups = result.AnnounceList[1].to_vec();
+        backups.sort();
+        assert_eq!(
+            backups,
+            vec!["http://backup1/announce", "udp://backup2:1337/ann"]
+        );
+    }
+
+    #[test]
+    fn test_to_torrent_file_length_over_4gib() {
+        let encoded = b"d8:announce30:http://tracker.example.org/ann4:infod6:lengthi10737418240e4:name6:vm.img12:piece lengthi4194304e6:pieces20:1234567890abcdefghijee";
+        let result = from_bytes(encoded).unwrap();
+        assert_eq!(result.Length, 10737418240);
+    }
+
+    #[test]
+    fn test_to_torrent_file_multi_file_sum_over_4gib() {
+        let input = BencodeTorrent {
+            announce: "http://tracker.example.org:6969/announce".to_string(),
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info: BencodeInfo {
+                pieces: ByteBuf::from("1234567890abcdefghij"),
+                piecelength: 4194304,
+                length: 0,
+                name: "images".to_string(),
+                private: None,
+                files: Some(vec![
+                    BencodeFile {
+                        length: 3221225472,
+                        path: vec!["a.img".to_string()],
+                    },
+                    BencodeFile {
+                        length: 3221225472,
+                        path: vec!["b.img".to_string()],
+                    },
+                ]),
+            },
+        };
+        let result = input.to_torrent_file(input.info.hash()).unwrap();
+        assert_eq!(result.Length, 6442450944);
+    }
+
+    #[test]
+    fn test_from_info_bytes() {
+        let info: &[u8] = b"d6:lengthi1000e4:name8:file.bin12:piece lengthi16384e6:pieces20:1234567890abcdefghij7:privatei1ee";
+        let result = from_info_bytes("udp://tracker.example.org:1337".to_string(), info).unwrap();
+        assert_eq!(result.Announce, "udp://tracker.example.org:1337");
+        assert_eq!(result.InfoHash, sha1_hash(info));
+        assert_eq!(result.Name, "file.bin");
+        assert_eq!(result.Length, 1000);
+        assert_eq!(result.PieceHashes.len(), 1);
+    }
 }
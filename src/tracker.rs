@@ -2,35 +2,254 @@ extern crate hex;
 extern crate serde_bencode;
 extern crate serde_bytes;
 
+use crate::p2p::TransferStats;
 use crate::peers::*;
+use crate::runtime;
 use crate::torrentfile::TorrentFile;
+use crate::udp_tracker::{AnnounceRequest, UdpTracker, DEFAULT_MAX_RETRIES, QUICK_MAX_RETRIES};
+use crossbeam_channel::{unbounded, Sender};
 #[allow(unused_imports)]
 use serde_bencode::{de, ser};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::io::Error;
-#[allow(unused_imports)]
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::str;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
 use url::Url;
 
+/* Used when a tracker doesn't send an interval */
+static DEFAULT_INTERVAL: u32 = 1800;
+/* How long shutdown waits for the stopped announce */
+static STOP_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct BencodeTrackerResp {
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    failure_reason: Option<String>,
+    #[serde(
+        default,
+        rename = "warning message",
+        skip_serializing_if = "Option::is_none"
+    )]
+    warning_message: Option<String>,
     #[serde(default)]
     interval: u32,
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    min_interval: Option<u32>,
+    #[serde(
+        default,
+        rename = "tracker id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    tracker_id: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    complete: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incomplete: Option<u32>,
     #[serde(default)]
-    peers: ByteBuf,
+    peers: BencodePeers,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
 }
 
-impl TorrentFile {
+/* Trackers send either the compact 6 bytes per peer string or a list of peer dictionaries */
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum BencodePeers {
+    Compact(ByteBuf),
+    Dict(Vec<BencodePeer>),
+}
+
+impl Default for BencodePeers {
+    fn default() -> BencodePeers {
+        BencodePeers::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct BencodePeer {
+    ip: String,
+    port: u16,
+    #[serde(default, rename = "peer id", skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
+}
+
+impl BencodePeers {
+    fn to_peers(&self) -> Result<Vec<Peer>, Error> {
+        match self {
+            BencodePeers::Compact(peers) => unmarshal(peers.to_vec()),
+            /* Entries we can't resolve are skipped rather than failing the whole announce */
+            BencodePeers::Dict(peers) => Ok(peers
+                .iter()
+                .filter_map(|p| unmarshal_dict(&p.ip, p.port).ok())
+                .collect()),
+        }
+    }
+}
+
+/* Error reported by a tracker itself, as opposed to a network or parse failure */
+#[derive(Debug, PartialEq)]
+pub enum TrackerError {
+    Failure(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::Failure(reason) => write!(f, "tracker error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+/* Lifecycle event sent with an announce, None for regular re-announces */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackerEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl TrackerEvent {
+    /* Value of the event parameter for HTTP trackers */
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            TrackerEvent::None => None,
+            TrackerEvent::Started => Some("started"),
+            TrackerEvent::Completed => Some("completed"),
+            TrackerEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /* Event field of a BEP 15 announce */
+    fn udp_id(&self) -> u32 {
+        match self {
+            TrackerEvent::None => 0,
+            TrackerEvent::Completed => 1,
+            TrackerEvent::Started => 2,
+            TrackerEvent::Stopped => 3,
+        }
+    }
+}
+
+/* What we tell the tracker about ourselves in one announce */
+#[derive(Clone, Debug)]
+pub struct AnnounceParams {
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) port: u16,
+    pub(crate) event: TrackerEvent,
+    pub(crate) uploaded: u64,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    /* Our own addresses, sent so dual-stack trackers can hand out both (BEP 7) */
+    pub(crate) ipv4: Option<Ipv4Addr>,
+    pub(crate) ipv6: Option<Ipv6Addr>,
+    /* Retransmissions before a udp:// tracker counts as down */
+    pub(crate) udp_retries: u32,
+}
+
+impl AnnounceParams {
+    /* Snapshot the transfer counters for an announce */
+    pub fn new(
+        peer_id: &[u8],
+        port: u16,
+        event: TrackerEvent,
+        stats: &TransferStats,
+    ) -> AnnounceParams {
+        AnnounceParams {
+            peer_id: peer_id.to_vec(),
+            port,
+            event,
+            uploaded: stats.uploaded(),
+            downloaded: stats.downloaded(),
+            left: stats.left(),
+            ipv4: None,
+            ipv6: None,
+            udp_retries: QUICK_MAX_RETRIES,
+        }
+    }
+
+    /* Re-announces in the background hold nothing up, so they wait out the full BEP 15 schedule */
+    pub fn in_background(mut self) -> AnnounceParams {
+        self.udp_retries = DEFAULT_MAX_RETRIES;
+        self
+    }
+
+    /* Fill in the addresses this host would use to reach the internet over each family */
+    pub fn with_local_addresses(mut self) -> AnnounceParams {
+        if let Some(IpAddr::V4(ip)) = local_address("0.0.0.0:0", "198.51.100.1:6881") {
+            self.ipv4 = Some(ip);
+        }
+        if let Some(IpAddr::V6(ip)) = local_address("[::]:0", "[2001:db8::1]:6881") {
+            self.ipv6 = Some(ip);
+        }
+        self
+    }
+}
 
+/* Source address the routing table picks for probe. Connecting a UDP socket sends nothing. */
+fn local_address(bind: &str, probe: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(probe.parse::<SocketAddr>().ok()?).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    if ip.is_unspecified() || ip.is_loopback() {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+/* Merged result of announcing to all tiers, complete and incomplete count seeders and leechers */
+#[derive(Debug, PartialEq)]
+pub struct TrackerResponse {
+    pub(crate) interval: u32,
+    pub(crate) min_interval: u32,
+    pub(crate) complete: u32,
+    pub(crate) incomplete: u32,
+    pub(crate) peers: Vec<Peer>,
+}
+
+impl TrackerResponse {
+    /* Time until the next regular announce, never shorter than min interval */
+    fn next_announce(&self) -> Duration {
+        let interval = if self.interval == 0 {
+            DEFAULT_INTERVAL
+        } else {
+            self.interval
+        };
+        Duration::from_secs(interval.max(self.min_interval) as u64)
+    }
+}
+
+impl TorrentFile {
     /* Build url get request using url encoding library */
-    fn build_tracker_url(&mut self, peerid: Vec<u8>, port: u16) -> Result<String, Error> {
-        let mut base = Url::parse(&self.Announce).unwrap();
+    fn build_tracker_url(&self, announce: &str, params: &AnnounceParams) -> Result<String, Error> {
+        let mut base = match Url::parse(announce) {
+            Ok(base) => base,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e.to_string())),
+        };
         base.query_pairs_mut().append_pair("compact", "1");
-        base.query_pairs_mut().append_pair("downloaded", "0");
+        base.query_pairs_mut()
+            .append_pair("downloaded", &params.downloaded.to_string());
+        if let Some(event) = params.event.name() {
+            base.query_pairs_mut().append_pair("event", event);
+        }
         // Use encoding_override() to enforce binary percent encoding of info_hash
         base.query_pairs_mut()
             .encoding_override(Some(&|input| {
@@ -43,8 +262,14 @@ impl TorrentFile {
                 }
             }))
             .append_pair("info_hash", "!");
+        if let Some(ip) = params.ipv4 {
+            base.query_pairs_mut().append_pair("ipv4", &ip.to_string());
+        }
+        if let Some(ip) = params.ipv6 {
+            base.query_pairs_mut().append_pair("ipv6", &ip.to_string());
+        }
         base.query_pairs_mut()
-            .append_pair("left", &self.Length.to_string());
+            .append_pair("left", &params.left.to_string());
         base.query_pairs_mut()
             .encoding_override(Some(&|input| {
                 if input != "!" {
@@ -52,39 +277,280 @@ impl TorrentFile {
                     Cow::Borrowed(input.as_bytes())
                 } else {
                     // When "!" is seen, return the binary data instead
-                    Cow::Owned(peerid.clone())
+                    Cow::Owned(params.peer_id.clone())
                 }
             }))
             .append_pair("peer_id", "!");
         base.query_pairs_mut()
-            .append_pair("port", &port.to_string());
-        base.query_pairs_mut().append_pair("uploaded", "0");
+            .append_pair("port", &params.port.to_string());
+        if let Some(tracker_id) = self.TrackerIds.get(announce) {
+            base.query_pairs_mut()
+                .encoding_override(Some(&|input| {
+                    if input != "!" {
+                        Cow::Borrowed(input.as_bytes())
+                    } else {
+                        Cow::Owned(tracker_id.clone())
+                    }
+                }))
+                .append_pair("trackerid", "!");
+        }
+        base.query_pairs_mut()
+            .append_pair("uploaded", &params.uploaded.to_string());
 
         Ok(base.to_string())
     }
 
-    /* Request a list of peers from the tracker using a get request*/
+    /* Request a list of peers without reporting any transfer, as done before a download starts */
     pub fn request_peers(&mut self, peerid: Vec<u8>, port: u16) -> Result<Vec<Peer>, Error> {
-        let url = self.build_tracker_url(peerid, port).unwrap();
-        let client = reqwest::blocking::Client::builder()
+        let stats = TransferStats::new(self.Length);
+        let params =
+            AnnounceParams::new(&peerid, port, TrackerEvent::None, &stats).with_local_addresses();
+        Ok(runtime::block_on(self.announce(&params))?.peers)
+    }
+
+    /* Announce to every tier in order, stopping within a tier at the first tracker that responds.
+    The responding tracker moves to the front of its tier and peers from all tiers are merged. */
+    pub async fn announce(&mut self, params: &AnnounceParams) -> Result<TrackerResponse, Error> {
+        if self.AnnounceList.is_empty() && !self.Announce.is_empty() {
+            self.AnnounceList = vec![vec![self.Announce.to_owned()]];
+        }
+        let mut merged: Option<TrackerResponse> = None;
+        let mut last_err = Error::new(ErrorKind::NotFound, "torrent has no trackers");
+        for t in 0..self.AnnounceList.len() {
+            for i in 0..self.AnnounceList[t].len() {
+                let announce = self.AnnounceList[t][i].to_owned();
+                match self.announce_to(&announce, params).await {
+                    Ok(resp) => {
+                        merged = Some(match merged {
+                            None => resp,
+                            Some(mut merged) => {
+                                /* Re-announce often enough for the most impatient tracker */
+                                merged.interval = merged.interval.min(resp.interval);
+                                merged.min_interval = merged.min_interval.max(resp.min_interval);
+                                merged.complete = merged.complete.max(resp.complete);
+                                merged.incomplete = merged.incomplete.max(resp.incomplete);
+                                for peer in resp.peers {
+                                    if !merged.peers.contains(&peer) {
+                                        merged.peers.push(peer);
+                                    }
+                                }
+                                merged
+                            }
+                        });
+                        let tracker = self.AnnounceList[t].remove(i);
+                        self.AnnounceList[t].insert(0, tracker);
+                        break;
+                    }
+                    Err(e) => {
+                        debug!("Tracker {} failed: {}", announce, e);
+                        last_err = e;
+                    }
+                }
+            }
+        }
+        match merged {
+            Some(resp) => Ok(resp),
+            None => Err(last_err),
+        }
+    }
+
+    /* Announce to a single tracker, udp:// announce urls use BEP 15 */
+    async fn announce_to(
+        &mut self,
+        announce: &str,
+        params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        if announce.starts_with("udp://") {
+            return self.announce_udp(announce, params).await;
+        }
+        let url = self.build_tracker_url(announce, params)?;
+        let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(15))
             .build()
             .unwrap();
-        let resp = client.get(&url).send().unwrap().bytes().unwrap();
-        let tracker_resp: BencodeTrackerResp = de::from_bytes::<BencodeTrackerResp>(&resp).unwrap();
-        Ok(unmarshal(tracker_resp.peers.to_vec()).unwrap())
+        let resp = match client.get(&url).send().await {
+            Ok(resp) => resp.bytes().await,
+            Err(e) => Err(e),
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => return Err(Error::other(e)),
+        };
+        let tracker_resp: BencodeTrackerResp = match de::from_bytes::<BencodeTrackerResp>(&resp) {
+            Ok(tracker_resp) => tracker_resp,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+        };
+        self.parse_tracker_response(announce, tracker_resp)
+    }
+
+    /* Turn a decoded HTTP tracker response into a TrackerResponse, remembering its tracker id */
+    fn parse_tracker_response(
+        &mut self,
+        announce: &str,
+        tracker_resp: BencodeTrackerResp,
+    ) -> Result<TrackerResponse, Error> {
+        if let Some(reason) = tracker_resp.failure_reason {
+            return Err(Error::other(TrackerError::Failure(reason)));
+        }
+        if let Some(warning) = tracker_resp.warning_message {
+            info!("Tracker {} warning: {}", announce, warning);
+        }
+        let mut peers = tracker_resp.peers.to_peers()?;
+        if let Some(peers6) = tracker_resp.peers6 {
+            peers.append(&mut unmarshal6(peers6.to_vec())?);
+        }
+        if let Some(tracker_id) = tracker_resp.tracker_id {
+            self.TrackerIds
+                .insert(announce.to_string(), tracker_id.to_vec());
+        }
+        Ok(TrackerResponse {
+            interval: tracker_resp.interval,
+            min_interval: tracker_resp.min_interval.unwrap_or(0),
+            complete: tracker_resp.complete.unwrap_or(0),
+            incomplete: tracker_resp.incomplete.unwrap_or(0),
+            peers,
+        })
+    }
+
+    async fn announce_udp(
+        &self,
+        announce: &str,
+        params: &AnnounceParams,
+    ) -> Result<TrackerResponse, Error> {
+        let mut tracker = UdpTracker::new(announce).await?;
+        tracker.set_max_retries(params.udp_retries);
+        let resp = tracker
+            .announce(&AnnounceRequest {
+                info_hash: self.InfoHash.to_vec(),
+                peer_id: params.peer_id.to_vec(),
+                downloaded: params.downloaded,
+                left: params.left,
+                uploaded: params.uploaded,
+                event: params.event.udp_id(),
+                port: params.port,
+            })
+            .await?;
+        Ok(TrackerResponse {
+            interval: resp.interval,
+            min_interval: 0,
+            complete: resp.seeders,
+            incomplete: resp.leechers,
+            peers: resp.peers,
+        })
+    }
+}
+
+/* Keeps us registered with the trackers for the lifetime of a download. The started announce is
+made up front, after that a background task re-announces every interval, forwards new peers
+and sends completed when asked. Dropping the session sends stopped. */
+pub struct TrackerSession {
+    events: Option<mpsc::UnboundedSender<TrackerEvent>>,
+    done: crossbeam_channel::Receiver<()>,
+}
+
+impl TrackerSession {
+    pub fn start(
+        mut torrent_file: TorrentFile,
+        peer_id: Vec<u8>,
+        port: u16,
+        stats: Arc<TransferStats>,
+        peer_updates: Sender<Vec<Peer>>,
+    ) -> Result<(TrackerSession, Vec<Peer>), Error> {
+        let params = AnnounceParams::new(&peer_id, port, TrackerEvent::Started, &stats)
+            .with_local_addresses();
+        let resp = runtime::block_on(torrent_file.announce(&params))?;
+        let peers = resp.peers.to_vec();
+        let (events, mut event_rx) = mpsc::unbounded_channel::<TrackerEvent>();
+        let (done_tx, done) = unbounded::<()>();
+        runtime::spawn(async move {
+            let mut wait = resp.next_announce();
+            let mut last_announce = Instant::now();
+            loop {
+                let event = match timeout(
+                    wait.saturating_sub(last_announce.elapsed()),
+                    event_rx.recv(),
+                )
+                .await
+                {
+                    Ok(Some(event)) => event,
+                    Ok(None) => TrackerEvent::Stopped,
+                    Err(_) => TrackerEvent::None,
+                };
+                let mut params =
+                    AnnounceParams::new(&peer_id, port, event, &stats).with_local_addresses();
+                /* Stopped has to get out before the process exits */
+                if event != TrackerEvent::Stopped {
+                    params = params.in_background();
+                }
+                match torrent_file.announce(&params).await {
+                    Ok(resp) => {
+                        wait = resp.next_announce();
+                        let _ = peer_updates.send(resp.peers);
+                    }
+                    Err(e) => debug!("Announce failed: {}", e),
+                }
+                last_announce = Instant::now();
+                if event == TrackerEvent::Stopped {
+                    let _ = done_tx.send(());
+                    return;
+                }
+            }
+        });
+        Ok((
+            TrackerSession {
+                events: Some(events),
+                done,
+            },
+            peers,
+        ))
+    }
+
+    /* Tell the trackers the download finished, called once the last piece verifies */
+    pub fn completed(&self) {
+        if let Some(events) = &self.events {
+            let _ = events.send(TrackerEvent::Completed);
+        }
+    }
+}
+
+impl Drop for TrackerSession {
+    fn drop(&mut self) {
+        /* Closing the event channel makes the session send stopped */
+        self.events.take();
+        let _ = self.done.recv_timeout(STOP_TIMEOUT);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crossbeam_channel::Receiver;
     use mockito::{mock, Matcher};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    fn test_response() -> BencodeTrackerResp {
+        BencodeTrackerResp {
+            failure_reason: None,
+            warning_message: None,
+            interval: 900,
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            peers: BencodePeers::default(),
+            peers6: None,
+        }
+    }
 
     #[test]
     fn test_build_tracker_url() {
-        let mut to = TorrentFile {
+        let to = TorrentFile {
             Announce: "http://bttracker.debian.org:6969/announce".to_string(),
+            AnnounceList: vec![],
             InfoHash: vec![
                 216, 247, 57, 206, 195, 40, 149, 108, 204, 91, 191, 31, 134, 217, 253, 207, 219,
                 168, 206, 182,
@@ -102,13 +568,18 @@ mod tests {
             PieceLength: 262144,
             Length: 351272960,
             Name: "debian-10.2.0-amd64-netinst.iso".to_string(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
         };
 
         let peer_id: Vec<u8> = vec![
             1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
         ];
         let port = 6881;
-        let url = match to.build_tracker_url(peer_id, port) {
+        let stats = TransferStats::new(to.Length);
+        let params = AnnounceParams::new(&peer_id, port, TrackerEvent::None, &stats);
+        let url = match to.build_tracker_url(&to.Announce, &params) {
             Ok(url) => url,
             Err(_) => panic!(),
         };
@@ -116,11 +587,34 @@ mod tests {
         assert_eq!(url, expected)
     }
 
+    #[test]
+    fn test_build_tracker_url_left_over_4gib() {
+        let to = TorrentFile {
+            Announce: "http://tracker.example.org:6969/announce".to_string(),
+            AnnounceList: vec![],
+            InfoHash: vec![0; 20],
+            PieceHashes: vec![],
+            PieceLength: 4194304,
+            Length: 42949672960,
+            Name: "vm.img".to_string(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
+        };
+        let stats = TransferStats::new(to.Length);
+        let params = AnnounceParams::new(&[1; 20], 6881, TrackerEvent::None, &stats);
+        let url = to.build_tracker_url(&to.Announce, &params).unwrap();
+        assert!(url.contains("&left=42949672960&"));
+    }
+
     #[test]
     fn test_request_peer() {
         let response_struct = BencodeTrackerResp {
             interval: 900,
-            peers: ByteBuf::from(vec![192, 0, 2, 123, 0x1A, 0xE1, 127, 0, 0, 1, 0x1A, 0xE9]),
+            peers: BencodePeers::Compact(ByteBuf::from(vec![
+                192, 0, 2, 123, 0x1A, 0xE1, 127, 0, 0, 1, 0x1A, 0xE9,
+            ])),
+            ..test_response()
         };
         let response_bencode = ser::to_bytes::<BencodeTrackerResp>(&response_struct).unwrap();
         #[allow(unused_variables)]
@@ -130,6 +624,7 @@ mod tests {
 
         let mut to = TorrentFile {
             Announce: mockito::server_url(),
+            AnnounceList: vec![],
             InfoHash: vec![
                 216, 247, 57, 206, 195, 40, 149, 108, 204, 91, 191, 31, 134, 217, 253, 207, 219,
                 168, 206, 182,
@@ -147,6 +642,9 @@ mod tests {
             PieceLength: 262144,
             Length: 351272960,
             Name: "debian-10.2.0-amd64-netinst.iso".to_string(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
         };
 
         let resp = to
@@ -158,16 +656,290 @@ mod tests {
             )
             .unwrap();
         let expected = vec![
-            Peer {
-                ip: Ipv4Addr::new(192, 0, 2, 123),
-                port: 6881,
-            },
-            Peer {
-                ip: Ipv4Addr::new(127, 0, 0, 1),
-                port: 6889,
-            },
+            Peer::new(Ipv4Addr::new(192, 0, 2, 123), 6881),
+            Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6889),
         ];
         assert_eq!(format!("{:?}", resp), format!("{:?}", expected))
         // assert_eq!(1, 0)
     }
+
+    /* Minimal HTTP tracker answering one announce per response, request lines are sent back */
+    fn serve_http_tracker(responses: Vec<BencodeTrackerResp>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (requests, received) = unbounded();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut request = vec![0; 4096];
+                let n = conn.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let _ = requests.send(request.lines().next().unwrap().to_string());
+                let body = ser::to_bytes(&response).unwrap();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                conn.write_all(header.as_bytes()).unwrap();
+                conn.write_all(&body).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn mock_http_tracker(peers: Vec<u8>) -> String {
+        serve_http_tracker(vec![BencodeTrackerResp {
+            peers: BencodePeers::Compact(ByteBuf::from(peers)),
+            ..test_response()
+        }])
+        .0
+    }
+
+    /* Url of a port nothing is listening on */
+    fn dead_tracker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/announce", listener.local_addr().unwrap())
+    }
+
+    fn multitracker_torrent(announce_list: Vec<Vec<String>>) -> TorrentFile {
+        TorrentFile {
+            Announce: announce_list[0][0].to_owned(),
+            AnnounceList: announce_list,
+            InfoHash: vec![0; 20],
+            PieceHashes: vec![],
+            PieceLength: 262144,
+            Length: 1000,
+            Name: "file.bin".to_string(),
+            Files: vec![],
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
+        }
+    }
+
+    #[test]
+    fn test_request_peers_fails_over_within_tier() {
+        let dead = dead_tracker();
+        let backup = mock_http_tracker(vec![127, 0, 0, 1, 0x1A, 0xE1]);
+        let mut to = multitracker_torrent(vec![vec![dead.to_owned(), backup.to_owned()]]);
+        let peers = to.request_peers(vec![1; 20], 6881).unwrap();
+        assert_eq!(peers, vec![Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6881)]);
+        assert_eq!(to.AnnounceList, vec![vec![backup, dead]]);
+    }
+
+    #[test]
+    fn test_request_peers_merges_tiers() {
+        let primary = mock_http_tracker(vec![127, 0, 0, 1, 0x1A, 0xE1, 127, 0, 0, 2, 0x1A, 0xE1]);
+        let backup = mock_http_tracker(vec![127, 0, 0, 2, 0x1A, 0xE1, 127, 0, 0, 3, 0x1A, 0xE1]);
+        let mut to = multitracker_torrent(vec![vec![primary], vec![dead_tracker(), backup]]);
+        let peers = to.request_peers(vec![1; 20], 6881).unwrap();
+        let ips: Vec<IpAddr> = peers.iter().map(|p| p.addr.ip()).collect();
+        assert_eq!(
+            ips,
+            vec![
+                IpAddr::from([127, 0, 0, 1]),
+                IpAddr::from([127, 0, 0, 2]),
+                IpAddr::from([127, 0, 0, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_request_peers_all_trackers_down() {
+        let mut to = multitracker_torrent(vec![vec![dead_tracker()], vec![dead_tracker()]]);
+        assert!(to.request_peers(vec![1; 20], 6881).is_err());
+    }
+
+    #[test]
+    fn test_build_tracker_url_reports_event_and_counters() {
+        let to = multitracker_torrent(vec![
+            vec!["http://tracker.example.org/announce".to_string()],
+        ]);
+        let stats = TransferStats::new(0);
+        stats.uploaded.fetch_add(512, Ordering::Relaxed);
+        stats.downloaded.fetch_add(1000, Ordering::Relaxed);
+        let params = AnnounceParams::new(&[1; 20], 6881, TrackerEvent::Completed, &stats);
+        let url = to.build_tracker_url(&to.Announce, &params).unwrap();
+        assert!(url.contains("&downloaded=1000&event=completed&"));
+        assert!(url.contains("&left=0&"));
+        assert!(url.ends_with("&uploaded=512"));
+    }
+
+    #[test]
+    fn test_only_background_announces_wait_out_udp_backoff() {
+        let stats = TransferStats::new(100);
+        let params = AnnounceParams::new(&[1; 20], 6881, TrackerEvent::Started, &stats);
+        assert_eq!(params.udp_retries, QUICK_MAX_RETRIES);
+        assert_eq!(params.in_background().udp_retries, DEFAULT_MAX_RETRIES);
+    }
+
+    #[test]
+    fn test_next_announce_respects_min_interval() {
+        let resp = TrackerResponse {
+            interval: 60,
+            min_interval: 300,
+            complete: 0,
+            incomplete: 0,
+            peers: vec![],
+        };
+        assert_eq!(resp.next_announce(), Duration::from_secs(300));
+        let resp = TrackerResponse {
+            interval: 0,
+            min_interval: 0,
+            complete: 0,
+            incomplete: 0,
+            peers: vec![],
+        };
+        assert_eq!(resp.next_announce(), Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn test_tracker_session_lifecycle() {
+        let response = |interval: u32, peers: Vec<u8>| BencodeTrackerResp {
+            interval,
+            peers: BencodePeers::Compact(ByteBuf::from(peers)),
+            ..test_response()
+        };
+        let (url, requests) = serve_http_tracker(vec![
+            response(1, vec![127, 0, 0, 1, 0x1A, 0xE1]),
+            response(60, vec![127, 0, 0, 9, 0x1A, 0xE1]),
+            response(60, vec![]),
+            response(60, vec![]),
+        ]);
+        let stats = Arc::new(TransferStats::new(1000));
+        let (peer_tx, peer_rx) = unbounded();
+        let (session, peers) = TrackerSession::start(
+            multitracker_torrent(vec![vec![url]]),
+            vec![1; 20],
+            6881,
+            stats.clone(),
+            peer_tx,
+        )
+        .unwrap();
+        assert_eq!(peers[0], Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6881));
+        assert!(requests.recv().unwrap().contains("&event=started&"));
+
+        /* The tracker asked for a one second interval */
+        let reannounce = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!reannounce.contains("event="));
+        let found = peer_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(found[0], Peer::new(Ipv4Addr::new(127, 0, 0, 9), 6881));
+
+        stats.downloaded.fetch_add(1000, Ordering::Relaxed);
+        stats.left.fetch_sub(1000, Ordering::Relaxed);
+        session.completed();
+        let completed = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(completed.contains("&downloaded=1000&event=completed&"));
+        assert!(completed.contains("&left=0&"));
+
+        drop(session);
+        let stopped = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(stopped.contains("&event=stopped&"));
+    }
+
+    #[test]
+    fn test_parse_dict_peers() {
+        let resp = de::from_bytes::<BencodeTrackerResp>(
+            b"d8:completei5e10:incompletei3e8:intervali1800e5:peersld2:ip9:127.0.0.17:peer id20:-TR2940-abcdefghijkl4:porti6881eed2:ip9:not an ip4:porti1eed2:ip8:10.0.0.24:porti51413eeee",
+        )
+        .unwrap();
+        let mut to = multitracker_torrent(vec![vec![
+            "http://tracker.example.org/announce".to_string()
+        ]]);
+        let parsed = to
+            .parse_tracker_response("http://tracker.example.org/announce", resp)
+            .unwrap();
+        assert_eq!(parsed.complete, 5);
+        assert_eq!(parsed.incomplete, 3);
+        assert_eq!(
+            parsed.peers,
+            vec![
+                Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6881),
+                Peer::new(Ipv4Addr::new(10, 0, 0, 2), 51413),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failure_reason_is_a_tracker_error() {
+        let url = serve_http_tracker(vec![BencodeTrackerResp {
+            failure_reason: Some("torrent not registered".to_string()),
+            ..test_response()
+        }])
+        .0;
+        let mut to = multitracker_torrent(vec![vec![url]]);
+        let err = to.request_peers(vec![1; 20], 6881).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<TrackerError>(),
+            Some(&TrackerError::Failure("torrent not registered".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tracker_id_is_echoed() {
+        let (url, requests) = serve_http_tracker(vec![
+            BencodeTrackerResp {
+                warning_message: Some("slow down".to_string()),
+                tracker_id: Some(ByteBuf::from(b"abc 123".to_vec())),
+                ..test_response()
+            },
+            test_response(),
+        ]);
+        let mut to = multitracker_torrent(vec![vec![url]]);
+        to.request_peers(vec![1; 20], 6881).unwrap();
+        assert!(!requests.recv().unwrap().contains("trackerid"));
+        to.request_peers(vec![1; 20], 6881).unwrap();
+        assert!(requests.recv().unwrap().contains("&trackerid=abc+123&"));
+    }
+
+    #[test]
+    fn test_parse_peers6() {
+        let mut peers6 = vec![0; 15];
+        peers6.extend_from_slice(&[1, 0x1A, 0xE1]);
+        let resp = BencodeTrackerResp {
+            peers: BencodePeers::Compact(ByteBuf::from(vec![127, 0, 0, 1, 0x1A, 0xE1])),
+            peers6: Some(ByteBuf::from(peers6)),
+            ..test_response()
+        };
+        let mut to = multitracker_torrent(vec![vec![
+            "http://tracker.example.org/announce".to_string()
+        ]]);
+        let parsed = to
+            .parse_tracker_response("http://tracker.example.org/announce", resp)
+            .unwrap();
+        assert_eq!(
+            parsed.peers,
+            vec![
+                Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6881),
+                Peer::new(Ipv6Addr::LOCALHOST, 6881),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_dict_peers_ipv6() {
+        let resp = de::from_bytes::<BencodeTrackerResp>(
+            b"d8:intervali1800e5:peersld2:ip3:::14:porti6881eeee",
+        )
+        .unwrap();
+        let mut to = multitracker_torrent(vec![vec![
+            "http://tracker.example.org/announce".to_string()
+        ]]);
+        let parsed = to
+            .parse_tracker_response("http://tracker.example.org/announce", resp)
+            .unwrap();
+        assert_eq!(parsed.peers, vec![Peer::new(Ipv6Addr::LOCALHOST, 6881)]);
+    }
+
+    #[test]
+    fn test_build_tracker_url_dual_stack() {
+        let to = multitracker_torrent(vec![
+            vec!["http://tracker.example.org/announce".to_string()],
+        ]);
+        let stats = TransferStats::new(0);
+        let mut params = AnnounceParams::new(&[1; 20], 6881, TrackerEvent::None, &stats);
+        params.ipv4 = Some(Ipv4Addr::new(192, 0, 2, 7));
+        params.ipv6 = Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7));
+        let url = to.build_tracker_url(&to.Announce, &params).unwrap();
+        assert!(url.contains("&ipv4=192.0.2.7&ipv6=2001%3Adb8%3A%3A7&"));
+    }
 }
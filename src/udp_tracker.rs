@@ -0,0 +1,408 @@
+use crate::peers::*;
+use crate::tracker::TrackerError;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+use url::Url;
+
+/* Magic constant identifying the connect request (BEP 15) */
+static PROTOCOL_ID: u64 = 0x41727101980;
+static ACTION_CONNECT: u32 = 0;
+static ACTION_ANNOUNCE: u32 = 1;
+static ACTION_SCRAPE: u32 = 2;
+static ACTION_ERROR: u32 = 3;
+
+/* Connection ids may be reused for one minute after the tracker hands them out */
+static CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/* Requests are retransmitted after 15 * 2^n seconds for n up to 8 */
+static DEFAULT_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub(crate) static DEFAULT_MAX_RETRIES: u32 = 8;
+/* The full schedule takes over two hours, an announce a download waits on gives up after
+15 + 30 + 60 seconds so the next tracker gets a turn */
+pub(crate) static QUICK_MAX_RETRIES: u32 = 2;
+
+/* Connection ids by tracker address, shared so repeated announces skip the connect round trip */
+static CONNECTION_IDS: Mutex<BTreeMap<SocketAddr, (u64, Instant)>> = Mutex::new(BTreeMap::new());
+
+pub struct AnnounceRequest {
+    pub(crate) info_hash: Vec<u8>,
+    pub(crate) peer_id: Vec<u8>,
+    pub(crate) downloaded: u64,
+    pub(crate) left: u64,
+    pub(crate) uploaded: u64,
+    pub(crate) event: u32,
+    pub(crate) port: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AnnounceResponse {
+    pub(crate) interval: u32,
+    pub(crate) leechers: u32,
+    pub(crate) seeders: u32,
+    pub(crate) peers: Vec<Peer>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScrapeResponse {
+    pub(crate) seeders: u32,
+    pub(crate) completed: u32,
+    pub(crate) leechers: u32,
+}
+
+pub struct UdpTracker {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /* Resolve a udp://host:port announce url and bind a socket of the matching family */
+    pub async fn new(announce: &str) -> Result<UdpTracker> {
+        let url = match Url::parse(announce) {
+            Ok(url) => url,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e.to_string())),
+        };
+        let (host, port) = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) if url.scheme() == "udp" => (host.to_string(), port),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "expected udp://host:port tracker url",
+                ))
+            }
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr = match lookup_host((host, port)).await?.next() {
+            Some(addr) => addr,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    "tracker host did not resolve",
+                ))
+            }
+        };
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        Ok(UdpTracker {
+            addr,
+            socket,
+            base_timeout: DEFAULT_BASE_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+        })
+    }
+
+    /* Retransmit at most max_retries times before giving up on the tracker */
+    pub(crate) fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /* Send a request and wait for the matching response, retransmitting with exponential backoff.
+    head is the connection id (or protocol id for connect) and body follows the transaction id */
+    async fn transact(&mut self, head: u64, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        let transaction_id: u32 = rand::random();
+        let mut request: Vec<u8> = vec![];
+        request.write_u64::<BigEndian>(head).unwrap();
+        request.write_u32::<BigEndian>(action).unwrap();
+        request.write_u32::<BigEndian>(transaction_id).unwrap();
+        request.extend_from_slice(body);
+
+        let mut buf = vec![0; 65536];
+        for n in 0..=self.max_retries {
+            self.socket.send_to(&request, self.addr).await?;
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let (size, from) =
+                    match timeout(deadline - now, self.socket.recv_from(&mut buf)).await {
+                        Ok(received) => received?,
+                        Err(_) => break,
+                    };
+                if from != self.addr || size < 8 {
+                    continue;
+                }
+                let mut header = Cursor::new(&buf[0..8]);
+                let resp_action = header.read_u32::<BigEndian>().unwrap();
+                let resp_transaction_id = header.read_u32::<BigEndian>().unwrap();
+                if resp_transaction_id != transaction_id {
+                    continue;
+                }
+                if resp_action == ACTION_ERROR {
+                    return Err(Error::other(TrackerError::Failure(
+                        String::from_utf8_lossy(&buf[8..size]).to_string(),
+                    )));
+                }
+                if resp_action != action {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "unexpected tracker action",
+                    ));
+                }
+                return Ok(buf[8..size].to_vec());
+            }
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "udp tracker did not respond",
+        ))
+    }
+
+    /* Reuse a cached connection id or obtain a fresh one from the tracker */
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some(&(id, obtained)) = CONNECTION_IDS.lock().unwrap().get(&self.addr) {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+        let resp = self.transact(PROTOCOL_ID, ACTION_CONNECT, &[]).await?;
+        if resp.len() < 8 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "connect response too short",
+            ));
+        }
+        let id = Cursor::new(&resp).read_u64::<BigEndian>().unwrap();
+        CONNECTION_IDS
+            .lock()
+            .unwrap()
+            .insert(self.addr, (id, Instant::now()));
+        Ok(id)
+    }
+
+    pub async fn announce(&mut self, req: &AnnounceRequest) -> Result<AnnounceResponse> {
+        let mut body: Vec<u8> = vec![];
+        body.extend_from_slice(&req.info_hash);
+        body.extend_from_slice(&req.peer_id);
+        body.write_u64::<BigEndian>(req.downloaded).unwrap();
+        body.write_u64::<BigEndian>(req.left).unwrap();
+        body.write_u64::<BigEndian>(req.uploaded).unwrap();
+        body.write_u32::<BigEndian>(req.event).unwrap();
+        body.write_u32::<BigEndian>(0).unwrap(); // ip, 0 means the sender's address
+        body.write_u32::<BigEndian>(rand::random()).unwrap(); // key
+        body.write_i32::<BigEndian>(-1).unwrap(); // num_want, -1 leaves it to the tracker
+        body.write_u16::<BigEndian>(req.port).unwrap();
+
+        let connection_id = self.connection_id().await?;
+        let resp = self.transact(connection_id, ACTION_ANNOUNCE, &body).await?;
+        if resp.len() < 12 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "announce response too short",
+            ));
+        }
+        let mut reader = Cursor::new(&resp[0..12]);
+        Ok(AnnounceResponse {
+            interval: reader.read_u32::<BigEndian>().unwrap(),
+            leechers: reader.read_u32::<BigEndian>().unwrap(),
+            seeders: reader.read_u32::<BigEndian>().unwrap(),
+            /* Trackers reached over IPv6 answer with 18 byte peer entries */
+            peers: if self.addr.is_ipv4() {
+                unmarshal(resp[12..].to_vec())?
+            } else {
+                unmarshal6(resp[12..].to_vec())?
+            },
+        })
+    }
+
+    #[allow(dead_code)]
+    pub async fn scrape(&mut self, info_hashes: &[Vec<u8>]) -> Result<Vec<ScrapeResponse>> {
+        let body: Vec<u8> = info_hashes.concat();
+        let connection_id = self.connection_id().await?;
+        let resp = self.transact(connection_id, ACTION_SCRAPE, &body).await?;
+        if resp.len() != 12 * info_hashes.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "scrape response has the wrong length",
+            ));
+        }
+        let mut reader = Cursor::new(resp);
+        let mut stats = vec![];
+        for _ in info_hashes {
+            stats.push(ScrapeResponse {
+                seeders: reader.read_u32::<BigEndian>().unwrap(),
+                completed: reader.read_u32::<BigEndian>().unwrap(),
+                leechers: reader.read_u32::<BigEndian>().unwrap(),
+            });
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::block_on;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::thread;
+
+    type Responder = fn(u32, &[u8]) -> (u32, Vec<u8>);
+
+    /* Stand-in tracker answering one request per entry in script, None drops the request */
+    fn mock_udp_tracker(script: Vec<Option<Responder>>) -> (String, thread::JoinHandle<Vec<u32>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut actions = vec![];
+            let mut buf = vec![0; 2048];
+            for step in script {
+                let (size, from) = socket.recv_from(&mut buf).unwrap();
+                let mut reader = Cursor::new(&buf[0..16]);
+                let _head = reader.read_u64::<BigEndian>().unwrap();
+                let action = reader.read_u32::<BigEndian>().unwrap();
+                let transaction_id = reader.read_u32::<BigEndian>().unwrap();
+                actions.push(action);
+                if let Some(respond) = step {
+                    let (resp_action, body) = respond(action, &buf[16..size]);
+                    let mut resp: Vec<u8> = vec![];
+                    resp.write_u32::<BigEndian>(resp_action).unwrap();
+                    resp.write_u32::<BigEndian>(transaction_id).unwrap();
+                    resp.extend_from_slice(&body);
+                    socket.send_to(&resp, from).unwrap();
+                }
+            }
+            actions
+        });
+        (url, handle)
+    }
+
+    fn connect_response(action: u32, _body: &[u8]) -> (u32, Vec<u8>) {
+        (action, vec![0, 0, 0, 0, 0, 0, 0x12, 0x34])
+    }
+
+    fn announce_response(action: u32, body: &[u8]) -> (u32, Vec<u8>) {
+        assert_eq!(body.len(), 82);
+        let mut resp = vec![0, 0, 0x07, 0x08, 0, 0, 0, 1, 0, 0, 0, 2];
+        resp.extend_from_slice(&[192, 0, 2, 123, 0x1A, 0xE1, 127, 0, 0, 1, 0x1A, 0xE9]);
+        (action, resp)
+    }
+
+    fn scrape_response(action: u32, body: &[u8]) -> (u32, Vec<u8>) {
+        assert_eq!(body.len(), 20);
+        (action, vec![0, 0, 0, 5, 0, 0, 0, 10, 0, 0, 0, 3])
+    }
+
+    fn error_response(_action: u32, _body: &[u8]) -> (u32, Vec<u8>) {
+        (ACTION_ERROR, b"unregistered torrent".to_vec())
+    }
+
+    fn test_request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: vec![1; 20],
+            peer_id: vec![2; 20],
+            downloaded: 0,
+            left: 5368709120,
+            uploaded: 0,
+            event: 0,
+            port: 6881,
+        }
+    }
+
+    fn fast_tracker(url: &str) -> UdpTracker {
+        let mut tracker = block_on(UdpTracker::new(url)).unwrap();
+        tracker.base_timeout = Duration::from_millis(100);
+        tracker.max_retries = 2;
+        tracker
+    }
+
+    #[test]
+    fn test_announce() {
+        let (url, handle) = mock_udp_tracker(vec![Some(connect_response), Some(announce_response)]);
+        let mut tracker = fast_tracker(&url);
+        let resp = block_on(tracker.announce(&test_request())).unwrap();
+        assert_eq!(
+            resp,
+            AnnounceResponse {
+                interval: 1800,
+                leechers: 1,
+                seeders: 2,
+                peers: vec![
+                    Peer::new(Ipv4Addr::new(192, 0, 2, 123), 6881),
+                    Peer::new(Ipv4Addr::new(127, 0, 0, 1), 6889),
+                ],
+            }
+        );
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE]
+        );
+    }
+
+    #[test]
+    fn test_connection_id_is_cached() {
+        let (url, handle) = mock_udp_tracker(vec![
+            Some(connect_response),
+            Some(announce_response),
+            Some(scrape_response),
+        ]);
+        let mut tracker = fast_tracker(&url);
+        block_on(tracker.announce(&test_request())).unwrap();
+        let stats = block_on(tracker.scrape(&[vec![1; 20]])).unwrap();
+        assert_eq!(
+            stats,
+            vec![ScrapeResponse {
+                seeders: 5,
+                completed: 10,
+                leechers: 3,
+            }]
+        );
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_SCRAPE]
+        );
+    }
+
+    #[test]
+    fn test_retransmits_dropped_request() {
+        let (url, handle) = mock_udp_tracker(vec![
+            None,
+            Some(connect_response),
+            None,
+            Some(announce_response),
+        ]);
+        let mut tracker = fast_tracker(&url);
+        let resp = block_on(tracker.announce(&test_request())).unwrap();
+        assert_eq!(resp.peers.len(), 2);
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                ACTION_CONNECT,
+                ACTION_CONNECT,
+                ACTION_ANNOUNCE,
+                ACTION_ANNOUNCE
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_action() {
+        let (url, _handle) = mock_udp_tracker(vec![Some(connect_response), Some(error_response)]);
+        let mut tracker = fast_tracker(&url);
+        let err = block_on(tracker.announce(&test_request())).unwrap_err();
+        assert_eq!(err.to_string(), "tracker error: unregistered torrent");
+    }
+
+    #[test]
+    fn test_gives_up_after_retries() {
+        let (url, _handle) = mock_udp_tracker(vec![None, None, None]);
+        let mut tracker = fast_tracker(&url);
+        let err = block_on(tracker.announce(&test_request())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_new_rejects_other_schemes() {
+        assert!(block_on(UdpTracker::new("http://tracker.example.org:6969/announce")).is_err());
+        assert!(block_on(UdpTracker::new("udp://tracker.example.org/announce")).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::p2p::{check_integrity, file_spans, PieceWork};
+use crate::storage::Storage;
+use crate::torrentfile::TorrentFile;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PieceStatus {
+    Intact,
+    /* The data could not be read, usually because a file is absent or too short */
+    Missing,
+    Corrupt,
+}
+
+/* How much of one file is covered by intact pieces */
+#[derive(Debug, PartialEq)]
+pub struct FileCompletion {
+    pub(crate) path: String,
+    pub(crate) length: u64,
+    pub(crate) verified: u64,
+    /* Size on disk, None when the file doesn't exist */
+    pub(crate) size: Option<u64>,
+}
+
+impl FileCompletion {
+    pub fn percent(&self) -> f64 {
+        if self.length == 0 {
+            100.0
+        } else {
+            self.verified as f64 / self.length as f64 * 100.0
+        }
+    }
+}
+
+pub struct VerifyReport {
+    pub(crate) pieces: Vec<PieceStatus>,
+    pub(crate) files: Vec<FileCompletion>,
+}
+
+impl VerifyReport {
+    /* Every piece is intact and no file has trailing data */
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|&status| status == PieceStatus::Intact)
+            && self.files.iter().all(|f| f.size == Some(f.length))
+    }
+
+    pub fn pieces_with(&self, status: PieceStatus) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|&index| self.pieces[index] == status)
+            .collect()
+    }
+
+    pub fn print(&self) {
+        for f in &self.files {
+            match f.size {
+                Some(size) if size != f.length => println!(
+                    "{:>7.2}%  {} (size {}, expected {})",
+                    f.percent(),
+                    f.path,
+                    size,
+                    f.length
+                ),
+                None => println!("{:>7.2}%  {} (missing)", f.percent(), f.path),
+                _ => println!("{:>7.2}%  {}", f.percent(), f.path),
+            }
+        }
+        let missing = self.pieces_with(PieceStatus::Missing);
+        let corrupt = self.pieces_with(PieceStatus::Corrupt);
+        let intact = self.pieces.len() - missing.len() - corrupt.len();
+        println!("{} of {} pieces intact", intact, self.pieces.len());
+        if !missing.is_empty() {
+            println!("Missing pieces: {}", format_ranges(&missing));
+        }
+        if !corrupt.is_empty() {
+            println!("Corrupt pieces: {}", format_ranges(&corrupt));
+        }
+    }
+}
+
+/* Hash-check the data of torrent_file at path, laid out the same way a download would write it */
+pub fn verify(torrent_file: &TorrentFile, path: &Path) -> VerifyReport {
+    let storage = Storage::new(
+        path,
+        &torrent_file.Name,
+        &torrent_file.Files,
+        torrent_file.Length,
+    );
+    let pieces = check_pieces(torrent_file, &storage);
+    let mut files: Vec<FileCompletion> = storage
+        .files()
+        .iter()
+        .zip(storage.paths())
+        .map(|(f, path)| FileCompletion {
+            path: f.Path.join("/"),
+            length: f.Length,
+            verified: 0,
+            size: fs::metadata(path).ok().map(|m| m.len()),
+        })
+        .collect();
+    for (index, &status) in pieces.iter().enumerate() {
+        if status != PieceStatus::Intact {
+            continue;
+        }
+        let (begin, end) = piece_bounds(torrent_file, index);
+        for span in file_spans(storage.files(), begin, end) {
+            files[span.file_index].verified += span.length;
+        }
+    }
+    VerifyReport { pieces, files }
+}
+
+/* Read and hash every piece from storage */
+fn check_pieces(torrent_file: &TorrentFile, storage: &Storage) -> Vec<PieceStatus> {
+    map_pieces(torrent_file.PieceHashes.len(), |index| {
+        check_piece(torrent_file, storage, index)
+    })
+}
+
+/* Run f for every piece index, spread over one thread per core, and collect the results in
+index order */
+pub(crate) fn map_pieces<T, F>(num_pieces: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<T>> = (0..num_pieces).map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(num_pieces))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= num_pieces {
+                            return done;
+                        }
+                        done.push((index, f(index)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (index, result) in worker.join().unwrap() {
+                results[index] = Some(result);
+            }
+        }
+    });
+    results.into_iter().map(|result| result.unwrap()).collect()
+}
+
+pub(crate) fn check_piece(
+    torrent_file: &TorrentFile,
+    storage: &Storage,
+    index: usize,
+) -> PieceStatus {
+    let (begin, end) = piece_bounds(torrent_file, index);
+    let pw = PieceWork {
+        index: index as u32,
+        hash: torrent_file.PieceHashes[index].to_vec(),
+        length: (end - begin) as u32,
+    };
+    match storage.read(begin, pw.length as usize) {
+        Ok(buf) => match check_integrity(&pw, &buf) {
+            Ok(_) => PieceStatus::Intact,
+            Err(_) => PieceStatus::Corrupt,
+        },
+        Err(_) => PieceStatus::Missing,
+    }
+}
+
+fn piece_bounds(torrent_file: &TorrentFile, index: usize) -> (u64, u64) {
+    let begin = index as u64 * torrent_file.PieceLength as u64;
+    (
+        begin,
+        torrent_file
+            .Length
+            .min(begin + torrent_file.PieceLength as u64),
+    )
+}
+
+/* Print sorted piece indices compactly, e.g. "0-3, 7, 9-10" */
+fn format_ranges(indices: &[usize]) -> String {
+    let mut ranges: Vec<String> = vec![];
+    let mut i = 0;
+    while i < indices.len() {
+        let start = indices[i];
+        while i + 1 < indices.len() && indices[i + 1] == indices[i] + 1 {
+            i += 1;
+        }
+        if indices[i] == start {
+            ranges.push(start.to_string());
+        } else {
+            ranges.push(format!("{}-{}", start, indices[i]));
+        }
+        i += 1;
+    }
+    ranges.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::{sha1_hash, FileEntry};
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn test_torrent(data: &[u8], files: Vec<FileEntry>) -> TorrentFile {
+        TorrentFile {
+            Announce: String::new(),
+            AnnounceList: vec![],
+            InfoHash: vec![5; 20],
+            PieceHashes: data.chunks(4).map(sha1_hash).collect(),
+            PieceLength: 4,
+            Length: data.len() as u64,
+            Name: "multi".to_string(),
+            Files: files,
+            TrackerIds: HashMap::new(),
+            ..TorrentFile::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_multi_file() {
+        let root = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let files = vec![
+            FileEntry {
+                Path: vec!["a.txt".to_string()],
+                Length: 5,
+            },
+            FileEntry {
+                Path: vec!["sub".to_string(), "b.txt".to_string()],
+                Length: 7,
+            },
+            FileEntry {
+                Path: vec!["c.txt".to_string()],
+                Length: 6,
+            },
+        ];
+        let torrent_file = test_torrent(b"hello world!abcdef", files);
+        fs::create_dir_all(root.join("multi").join("sub")).unwrap();
+        fs::write(root.join("multi").join("a.txt"), b"hello").unwrap();
+        fs::write(root.join("multi").join("sub").join("b.txt"), b" wXrld!").unwrap();
+
+        let report = verify(&torrent_file, &root);
+        assert!(!report.is_complete());
+        assert_eq!(
+            report.pieces,
+            vec![
+                PieceStatus::Intact,
+                PieceStatus::Corrupt,
+                PieceStatus::Intact,
+                PieceStatus::Missing,
+                PieceStatus::Missing,
+            ]
+        );
+        assert_eq!(report.pieces_with(PieceStatus::Missing), vec![3, 4]);
+        let verified: Vec<u64> = report.files.iter().map(|f| f.verified).collect();
+        assert_eq!(verified, vec![4, 4, 0]);
+        assert_eq!(report.files[1].path, "sub/b.txt");
+
+        fs::write(root.join("multi").join("sub").join("b.txt"), b" world!").unwrap();
+        fs::write(root.join("multi").join("c.txt"), b"abcdef").unwrap();
+        let report = verify(&torrent_file, &root);
+        assert!(report.is_complete());
+        assert!(report.files.iter().all(|f| f.percent() == 100.0));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_single_file() {
+        let path = std::env::temp_dir().join(format!("rust-torrent-{}", rand::random::<u32>()));
+        let torrent_file = test_torrent(b"0123456789", vec![]);
+        fs::write(&path, b"0123456789").unwrap();
+        let report = verify(&torrent_file, &path);
+        assert!(report.is_complete());
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].verified, 10);
+
+        fs::write(&path, b"01234567").unwrap();
+        let report = verify(&torrent_file, &path);
+        assert_eq!(report.pieces_with(PieceStatus::Missing), vec![2]);
+
+        /* Trailing data leaves every piece intact but is still a mismatch */
+        fs::write(&path, b"0123456789!").unwrap();
+        let report = verify(&torrent_file, &path);
+        assert!(report.pieces_with(PieceStatus::Intact).len() == 3);
+        assert!(!report.is_complete());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_ranges() {
+        assert_eq!(format_ranges(&[0, 1, 2, 3, 7, 9, 10]), "0-3, 7, 9-10");
+        assert_eq!(format_ranges(&[5]), "5");
+        assert_eq!(format_ranges(&[]), "");
+    }
+}